@@ -0,0 +1,139 @@
+// Loopback test for the UDP gateway. Start NOS3 and nos3_io, run `udp 0` and `udp 1` in nos3_io,
+// then `cargo run --example udp_loopback`. Uses the `[udp.0]` settings from src/config.toml for
+// message mode and `[udp.1]` for request mode, and exits with 1 if any check fails.
+use nosengine_rust::client::{Bus, DataNode};
+use std::net::UdpSocket;
+use std::process;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const NOS_CONNECTION: &str = "tcp://localhost:12000";
+const TEST_NODE: &str = "udp_loopback";
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A bridge's NOS bus, node and addresses, as in src/config.toml.
+struct Gateway {
+    nos_bus: &'static str,
+    node: &'static str,
+    listen: &'static str,
+    send_to: &'static str,
+}
+
+const MESSAGE_GATEWAY: Gateway = Gateway {
+    nos_bus: "udp_0",
+    node: "nos3_io_udp_0",
+    listen: "127.0.0.1:5010",
+    send_to: "127.0.0.1:5011",
+};
+
+const REQUEST_GATEWAY: Gateway = Gateway {
+    nos_bus: "udp_1",
+    node: "nos3_io_udp_1",
+    listen: "127.0.0.1:5012",
+    send_to: "127.0.0.1:5013",
+};
+
+fn main() {
+    let results = [message_mode(), request_mode()];
+    let failures = results
+        .concat()
+        .into_iter()
+        .filter(|passed| !passed)
+        .count();
+    if failures > 0 {
+        println!("{} check(s) failed", failures);
+        process::exit(1);
+    }
+}
+
+/// Print whether a check passed, and return it.
+fn check(name: &str, result: Result<(), String>) -> bool {
+    match result {
+        Ok(()) => println!("[ PASS ] {}", name),
+        Err(ref err) => println!("[ FAIL ] {}: {}", name, err),
+    }
+    result.is_ok()
+}
+
+fn message_mode() -> Vec<bool> {
+    let bus = Bus::new(MESSAGE_GATEWAY.nos_bus, NOS_CONNECTION).unwrap();
+    let node = DataNode::new(&bus, TEST_NODE).unwrap();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    node.set_message_handler(move |data: &[u8]| {
+        tx.lock().unwrap().send(data.to_vec()).unwrap();
+        None
+    });
+
+    let socket = UdpSocket::bind(MESSAGE_GATEWAY.send_to).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+    // datagram => gateway => message
+    socket.send_to(&[1, 2, 3], MESSAGE_GATEWAY.listen).unwrap();
+    let to_nos = match rx.recv_timeout(TIMEOUT) {
+        Ok(ref data) if data[..] == [1, 2, 3] => Ok(()),
+        other => Err(format!("{:?}", other)),
+    };
+
+    // message => gateway => datagram
+    node.send_message(MESSAGE_GATEWAY.node, &[4, 5, 6]).unwrap();
+    let mut buf = [0u8; 64];
+    let to_udp = match socket.recv(&mut buf) {
+        Ok(n) if buf[..n] == [4, 5, 6] => Ok(()),
+        Ok(n) => Err(format!("{:?}", &buf[..n])),
+        Err(err) => Err(err.to_string()),
+    };
+
+    vec![check("UDP to NOS", to_nos), check("NOS to UDP", to_udp)]
+}
+
+fn request_mode() -> Vec<bool> {
+    let bus = Bus::new(REQUEST_GATEWAY.nos_bus, NOS_CONNECTION).unwrap();
+    let node = DataNode::new(&bus, TEST_NODE).unwrap();
+    // Each request is answered with its bytes reversed
+    node.set_message_handler(|data: &[u8]| Some(data.iter().rev().cloned().collect()));
+
+    // datagram => gateway => request, and the reply back to the datagram's sender
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client.send_to(&[7, 8, 9], REQUEST_GATEWAY.listen).unwrap();
+    let mut buf = [0u8; 64];
+    let to_nos = match client.recv(&mut buf) {
+        Ok(n) if buf[..n] == [9, 8, 7] => Ok(()),
+        Ok(n) => Err(format!("{:?}", &buf[..n])),
+        Err(err) => Err(err.to_string()),
+    };
+
+    // request => gateway => datagram, and the datagram answering it back as the reply
+    let server = UdpSocket::bind(REQUEST_GATEWAY.send_to).unwrap();
+    server.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let answer = thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        let (n, source) = server.recv_from(&mut buf)?;
+        buf[..n].reverse();
+        server.send_to(&buf[..n], source).map(|_| ())
+    });
+    let reply = node.send_request_message(REQUEST_GATEWAY.node, &[4, 5, 6]);
+    let to_udp = match (answer.join().unwrap(), reply) {
+        (Err(err), _) => Err(format!("no datagram: {}", err)),
+        (_, Err(err)) => Err(err.to_string()),
+        (_, Ok(ref reply)) if reply.get_contents() == [6, 5, 4] => Ok(()),
+        (_, Ok(reply)) => Err(format!("{:?}", reply.get_contents())),
+    };
+
+    // request => gateway => nothing in answer, so the reply is an error
+    let reply = node.send_request_message(REQUEST_GATEWAY.node, &[1]);
+    let unanswered = match reply {
+        Ok(ref reply) if reply.get_contents().starts_with(b"nos3_io error: ") => Ok(()),
+        Ok(reply) => Err(format!("{:?}", reply.get_contents())),
+        Err(err) => Err(err.to_string()),
+    };
+
+    vec![
+        check("UDP request to NOS", to_nos),
+        check("NOS request to UDP", to_udp),
+        check("Unanswered NOS request to UDP", unanswered),
+    ]
+}
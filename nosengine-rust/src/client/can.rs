@@ -0,0 +1,397 @@
+//! Provides functionality for communicating using CAN on NOSEngine.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate nosengine_rust;
+//! # use nosengine_rust::client::can::*;
+//! let can1 = CAN::new("can10", "tcp://localhost:12001", "can2").unwrap();
+//! let mut can2 = CAN::new("can11", "tcp://localhost:12001", "can2").unwrap();
+//!
+//! // Only accept extended frames with identifiers 0x18FF_0000 through 0x18FF_00FF
+//! can2.set_filters(vec![CANFilter::new(CANId::Extended(0x18FF_0000), 0x1FFF_FF00)]);
+//!
+//! can1.write(&CANFrame::new(CANId::Standard(0x123), &[1, 2]).unwrap()).unwrap();
+//! can1.write(&CANFrame::new(CANId::Extended(0x18FF_0042), &[3, 4]).unwrap()).unwrap();
+//!
+//! let frame = can2.read().unwrap();
+//! assert_eq!(frame.id, CANId::Extended(0x18FF_0042));
+//! assert_eq!(frame.data, vec![3u8, 4]);
+//! assert_eq!(can2.read(), None);
+//! ```
+
+use super::ffi::can;
+use libc;
+use std::error::Error;
+use std::ffi;
+use std::ffi::CString;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// Largest identifier of a standard (11-bit) frame
+pub const CAN_SFF_MASK: u32 = 0x0000_07FF;
+/// Largest identifier of an extended (29-bit) frame
+pub const CAN_EFF_MASK: u32 = 0x1FFF_FFFF;
+/// Largest payload of a CAN frame
+pub const CAN_MAX_DLEN: usize = 8;
+
+/// This enum represents any type of error that can occur when interacting with CAN.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CANError {
+    /// An error occurred when converting a Rust string to a C string.
+    /// Specifically, the Rust string contained a null character, which cannot be represented
+    /// in C strings.
+    StringError {
+        /// Description from the underlying std::ffi::NulError
+        description: String,
+        /// Index in the original string of the problematic null character
+        position: usize,
+    },
+    /// There was an error when creating the CAN node.
+    CANCreationError,
+    /// The identifier does not fit in 11 bits (standard) or 29 bits (extended).
+    InvalidId {
+        /// The identifier which was attempted
+        id: CANId,
+    },
+    /// The payload is longer than 8 bytes.
+    InvalidLength {
+        /// The length which was attempted
+        len: usize,
+    },
+    /// NOSEngine failed to send the frame.
+    WriteError,
+}
+
+impl From<ffi::NulError> for CANError {
+    fn from(err: ffi::NulError) -> Self {
+        CANError::StringError {
+            description: String::from(err.description()),
+            position: err.nul_position(),
+        }
+    }
+}
+
+impl fmt::Display for CANError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CANError::StringError {
+                description,
+                position,
+            } => write!(f, "Null character at index {}: {}", position, description),
+            CANError::CANCreationError => write!(f, "Error while creating CAN node"),
+            CANError::InvalidId { id } => write!(f, "Invalid CAN identifier {}", id),
+            CANError::InvalidLength { len } => write!(
+                f,
+                "Invalid payload length {}: Must be at most {} bytes.",
+                len, CAN_MAX_DLEN
+            ),
+            CANError::WriteError => write!(f, "Error while writing CAN frame"),
+        }
+    }
+}
+
+/// The identifier of a CAN frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CANId {
+    /// An 11-bit identifier
+    Standard(u16),
+    /// A 29-bit identifier
+    Extended(u32),
+}
+
+impl CANId {
+    /// Returns the identifier as a raw number, without any flag for the frame format.
+    pub fn raw(&self) -> u32 {
+        match *self {
+            CANId::Standard(id) => u32::from(id),
+            CANId::Extended(id) => id,
+        }
+    }
+
+    /// Returns `true` if this is a 29-bit identifier.
+    pub fn is_extended(&self) -> bool {
+        match *self {
+            CANId::Standard(_) => false,
+            CANId::Extended(_) => true,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match *self {
+            CANId::Standard(id) => u32::from(id) <= CAN_SFF_MASK,
+            CANId::Extended(id) => id <= CAN_EFF_MASK,
+        }
+    }
+}
+
+impl fmt::Display for CANId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CANId::Standard(id) => write!(f, "{:03X}", id),
+            CANId::Extended(id) => write!(f, "{:08X}", id),
+        }
+    }
+}
+
+/// One CAN frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CANFrame {
+    /// Identifier of the frame
+    pub id: CANId,
+    /// `true` if this is a remote transmission request. Remote frames carry no data, but
+    /// `data.len()` is still sent as the requested length.
+    pub rtr: bool,
+    /// Payload of the frame, at most 8 bytes
+    pub data: Vec<u8>,
+}
+
+impl CANFrame {
+    /// Creates a data frame, checking that the identifier and payload length are valid.
+    pub fn new(id: CANId, data: &[u8]) -> Result<CANFrame, CANError> {
+        let frame = CANFrame {
+            id,
+            rtr: false,
+            data: data.to_vec(),
+        };
+        frame.validate()?;
+        Ok(frame)
+    }
+
+    /// Creates a remote transmission request for `len` bytes.
+    pub fn remote(id: CANId, len: usize) -> Result<CANFrame, CANError> {
+        let frame = CANFrame {
+            id,
+            rtr: true,
+            data: vec![0u8; len],
+        };
+        frame.validate()?;
+        Ok(frame)
+    }
+
+    fn validate(&self) -> Result<(), CANError> {
+        if !self.id.is_valid() {
+            return Err(CANError::InvalidId { id: self.id });
+        }
+        if self.data.len() > CAN_MAX_DLEN {
+            return Err(CANError::InvalidLength {
+                len: self.data.len(),
+            });
+        }
+        Ok(())
+    }
+
+    fn to_raw(&self) -> can::CANFrame {
+        let mut raw = can::CANFrame {
+            id: self.id.raw(),
+            extended: self.id.is_extended() as u8,
+            rtr: self.rtr as u8,
+            dlc: self.data.len() as u8,
+            data: [0u8; 8],
+        };
+        if !self.rtr {
+            raw.data[..self.data.len()].copy_from_slice(&self.data);
+        }
+        raw
+    }
+
+    fn from_raw(raw: &can::CANFrame) -> CANFrame {
+        let id = if raw.extended != 0 {
+            CANId::Extended(raw.id & CAN_EFF_MASK)
+        } else {
+            CANId::Standard((raw.id & CAN_SFF_MASK) as u16)
+        };
+        let len = (raw.dlc as usize).min(CAN_MAX_DLEN);
+        CANFrame {
+            id,
+            rtr: raw.rtr != 0,
+            data: raw.data[..len].to_vec(),
+        }
+    }
+}
+
+/// Accepts frames whose identifier matches `id` in every bit set in `mask`. A filter only
+/// matches frames of the same format (standard or extended) as `id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CANFilter {
+    /// Identifier to compare against
+    pub id: CANId,
+    /// Bits of the identifier which must match
+    pub mask: u32,
+}
+
+impl CANFilter {
+    /// Creates a new filter.
+    pub fn new(id: CANId, mask: u32) -> CANFilter {
+        CANFilter { id, mask }
+    }
+
+    /// Returns `true` if this filter accepts `frame`.
+    pub fn matches(&self, frame: &CANFrame) -> bool {
+        self.id.is_extended() == frame.id.is_extended()
+            && (self.id.raw() & self.mask) == (frame.id.raw() & self.mask)
+    }
+}
+
+/// Returns `true` if any of `filters` accepts the frame, or if there are no filters.
+fn accepted(filters: &RwLock<Vec<CANFilter>>, frame: &CANFrame) -> bool {
+    let filters = filters.read().unwrap();
+    filters.is_empty() || filters.iter().any(|filter| filter.matches(frame))
+}
+
+/// This struct represents one node on a CAN bus.
+pub struct CAN {
+    can_ptr: *mut can::CANHandle,
+    filters: Arc<RwLock<Vec<CANFilter>>>,
+    /// The name of this CAN node. Must be unique on a bus
+    pub name: String,
+    /// The connection string to the server
+    pub connection: String,
+    /// The name of the CAN bus
+    pub bus: String,
+}
+
+unsafe impl Sync for CAN {}
+
+impl CAN {
+    /// Create a new CAN node.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: Name of this CAN node. Must be unique on a bus.
+    /// * `connection`: Connection string to server. Usually of the form `tcp://<domain>:<port>`,
+    ///     but can take other forms. See the connection string section of the NOSEngine
+    ///     user manual for more.
+    /// * `bus`: Name of the bus to which this node should connect. If the bus does not already
+    ///     exist, it will be created automatically.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::can::*;
+    /// let can = CAN::new("can12", "tcp://localhost:12001", "can2");
+    /// assert!(can.is_ok());
+    /// ```
+    pub fn new(name: &str, connection: &str, bus: &str) -> Result<CAN, CANError> {
+        let c_name = CString::new(name)?;
+        let c_connection = CString::new(connection)?;
+        let c_bus = CString::new(bus)?;
+
+        let can_ptr = can::can_open(c_name.as_ptr(), c_connection.as_ptr(), c_bus.as_ptr());
+
+        if can_ptr.is_null() {
+            Err(CANError::CANCreationError)
+        } else {
+            Ok(CAN {
+                can_ptr,
+                filters: Arc::new(RwLock::new(Vec::new())),
+                name: String::from(name),
+                connection: String::from(connection),
+                bus: String::from(bus),
+            })
+        }
+    }
+
+    /// Send a frame to every other node on the bus.
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::can`](../can/index.html#examples)
+    pub fn write(&self, frame: &CANFrame) -> Result<(), CANError> {
+        frame.validate()?;
+        let raw = frame.to_raw();
+        match can::can_write(self.can_ptr, &raw as *const can::CANFrame) {
+            can::CANStatus::Success => Ok(()),
+            can::CANStatus::Failure => Err(CANError::WriteError),
+        }
+    }
+
+    /// Take the oldest received frame which passes the filters. Frames which do not pass are
+    /// discarded. If no frames are available, `None` is returned.
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::can`](../can/index.html#examples)
+    pub fn read(&self) -> Option<CANFrame> {
+        let mut raw = can::CANFrame::default();
+        while let can::CANStatus::Success = can::can_read(self.can_ptr, &mut raw) {
+            let frame = CANFrame::from_raw(&raw);
+            if accepted(&self.filters, &frame) {
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    /// Return the number of frames waiting to be read, including frames which will be
+    /// discarded by the filters.
+    pub fn available(&self) -> usize {
+        can::can_available(self.can_ptr)
+    }
+
+    /// Replace the receive filters. A frame is received if any filter accepts it. With no
+    /// filters, every frame is received.
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::can`](../can/index.html#examples)
+    pub fn set_filters(&mut self, filters: Vec<CANFilter>) {
+        *self.filters.write().unwrap() = filters;
+    }
+
+    /// Set a callback which will run whenever this node receives a frame which passes the
+    /// filters. Frames delivered to the callback are not returned by `CAN::read`.
+    ///
+    /// # Arguments
+    ///
+    /// * `func`: A callback with the following parameters:
+    ///     * `&CANFrame`: The frame that was just received
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::can::*;
+    /// let can1 = CAN::new("can13", "tcp://localhost:12001", "can2").unwrap();
+    /// let mut can2 = CAN::new("can14", "tcp://localhost:12001", "can2").unwrap();
+    ///
+    /// can2.set_callback(move |frame: &CANFrame| {
+    ///     assert_eq!(frame.data, vec![1u8, 2, 3, 4]);
+    /// });
+    ///
+    /// can1.write(&CANFrame::new(CANId::Standard(0x7FF), &[1, 2, 3, 4]).unwrap()).unwrap();
+    /// ```
+    pub fn set_callback<F>(&mut self, func: F)
+    where
+        F: FnMut(&CANFrame) -> (),
+        F: 'static,
+    {
+        extern "C" fn c_callback(frame: *const can::CANFrame, user: *mut libc::c_void) {
+            unsafe {
+                let func = user as *mut Box<FnMut(&can::CANFrame)>;
+                (*func)(&*frame);
+            }
+        }
+
+        let filters = self.filters.clone();
+        let mut func = func;
+        let func = Box::new(move |raw: &can::CANFrame| {
+            let frame = CANFrame::from_raw(raw);
+            if accepted(&filters, &frame) {
+                func(&frame);
+            }
+        }) as Box<FnMut(&can::CANFrame)>;
+        let func = Box::into_raw(Box::new(func));
+
+        can::can_set_read_callback(self.can_ptr, c_callback, func as *mut libc::c_void);
+    }
+}
+
+impl Drop for CAN {
+    fn drop(&mut self) {
+        can::can_close(&mut self.can_ptr as *mut *mut can::CANHandle);
+    }
+}
+
+unsafe impl Send for CAN {}
@@ -37,6 +37,108 @@ use std::ffi;
 use std::ffi::CString;
 use std::fmt;
 
+/// The most `I2CSlave`s created with `I2CSlave::with_handler` which can exist at once.
+pub const MAX_HANDLER_SLAVES: usize = 16;
+
+slave_handlers!(
+    i2c::I2CDirection,
+    MAX_HANDLER_SLAVES,
+    [
+        0 => slave_callback_0,
+        1 => slave_callback_1,
+        2 => slave_callback_2,
+        3 => slave_callback_3,
+        4 => slave_callback_4,
+        5 => slave_callback_5,
+        6 => slave_callback_6,
+        7 => slave_callback_7,
+        8 => slave_callback_8,
+        9 => slave_callback_9,
+        10 => slave_callback_10,
+        11 => slave_callback_11,
+        12 => slave_callback_12,
+        13 => slave_callback_13,
+        14 => slave_callback_14,
+        15 => slave_callback_15
+    ]
+);
+
+/// An address on an I2C bus, in either 7-bit or 10-bit addressing mode.
+///
+/// Plain `u16`s convert to 7-bit addresses, so `8u16` can be passed anywhere an `I2CAddress` is
+/// expected. Addresses are checked when they are used, against the reserved ranges of their mode.
+/// On a NOS bus, both modes share one set of numbers, so a 10-bit device should not use the same
+/// number as a 7-bit one.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate nosengine_rust;
+/// # use nosengine_rust::client::i2c::*;
+/// let master = I2CMaster::new(9u16, "tcp://localhost:12001", "i2c22").unwrap();
+/// let address = I2CAddress::TenBit(0x250);
+/// let slave = I2CSlave::with_handler(address, "tcp://localhost:12001", "i2c22", |_, data| {
+///     data.len()
+/// }).unwrap();
+///
+/// assert_eq!(slave.address, address);
+/// assert_eq!(master.write(address, &[1u8]), Ok(()));
+/// assert!(master.write(I2CAddress::TenBit(0x400), &[1u8]).is_err());
+/// assert!(master.write(0x78u16, &[1u8]).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum I2CAddress {
+    /// A 7-bit address. 0x00-0x07 and 0x78-0x7F are reserved, leaving 0x08-0x77.
+    SevenBit(u16),
+    /// A 10-bit address, from 0x000 to 0x3FF.
+    TenBit(u16),
+}
+
+impl I2CAddress {
+    /// The address as a plain number, without its mode.
+    pub fn raw(&self) -> u16 {
+        match *self {
+            I2CAddress::SevenBit(address) | I2CAddress::TenBit(address) => address,
+        }
+    }
+
+    /// Whether this is a 10-bit address.
+    pub fn is_ten_bit(&self) -> bool {
+        match *self {
+            I2CAddress::SevenBit(_) => false,
+            I2CAddress::TenBit(_) => true,
+        }
+    }
+
+    /// Returns this address, or `I2CError::InvalidAddress` if it is out of range or reserved.
+    pub fn validate(self) -> Result<I2CAddress, I2CError> {
+        let valid = match self {
+            I2CAddress::SevenBit(address) => address >= 0x08 && address <= 0x77,
+            I2CAddress::TenBit(address) => address <= 0x3FF,
+        };
+        if valid {
+            Ok(self)
+        } else {
+            Err(I2CError::InvalidAddress { address: self })
+        }
+    }
+}
+
+impl From<u16> for I2CAddress {
+    fn from(address: u16) -> Self {
+        I2CAddress::SevenBit(address)
+    }
+}
+
+impl fmt::Display for I2CAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            I2CAddress::SevenBit(address) => write!(f, "{:#04x}", address),
+            I2CAddress::TenBit(address) => write!(f, "{:#05x} (10-bit)", address),
+        }
+    }
+}
+
 /// This enum represents any type of error that can occur when interacting with I2C
 #[derive(Debug, Clone, PartialEq)]
 pub enum I2CError {
@@ -54,12 +156,26 @@ pub enum I2CError {
     /// This error is raised when an I2C device is created with an invalid address.
     InvalidAddress {
         /// The address which was attempted
-        address: u16,
+        address: I2CAddress,
     },
     /// Attempted to read or write to an address that doesn't exist.
     UnknownAddress {
         /// The address which was not found
-        address: u16,
+        address: I2CAddress,
+    },
+    /// Every slot for closure handlers is already in use. See `MAX_HANDLER_SLAVES`.
+    TooManyHandlers,
+    /// The Packet Error Code of an SMBus transaction was wrong.
+    PECMismatch {
+        /// PEC calculated from the transaction
+        expected: u8,
+        /// PEC which was received
+        received: u8,
+    },
+    /// An SMBus block was longer than `SMBUS_BLOCK_MAX` bytes.
+    InvalidBlockLength {
+        /// Length of the block
+        length: usize,
     },
 }
 
@@ -86,14 +202,37 @@ impl fmt::Display for I2CError {
                 position,
             } => write!(f, "Null character at index {}: {}", position, description),
             I2CError::I2CCreationError => write!(f, "Error while creating I2C node"),
-            I2CError::InvalidAddress { address } => write!(
-                f,
-                "Invalid address {}: Must be between 8 and 127, inclusive.",
-                address
-            ),
+            I2CError::InvalidAddress { address } => match address {
+                I2CAddress::SevenBit(_) => write!(
+                    f,
+                    "Invalid address {}: Must be between 0x08 and 0x77, inclusive.",
+                    address
+                ),
+                I2CAddress::TenBit(_) => write!(
+                    f,
+                    "Invalid address {}: Must be between 0x000 and 0x3FF, inclusive.",
+                    address
+                ),
+            },
             I2CError::UnknownAddress { address } => {
                 write!(f, "Address {} not found on this bus.", address)
             }
+            I2CError::TooManyHandlers => write!(
+                f,
+                "No more than {} I2C slaves can have closure handlers",
+                MAX_HANDLER_SLAVES
+            ),
+            I2CError::PECMismatch { expected, received } => write!(
+                f,
+                "PEC mismatch: expected {:#04x}, received {:#04x}",
+                expected, received
+            ),
+            I2CError::InvalidBlockLength { length } => write!(
+                f,
+                "Block of {} bytes is longer than the SMBus maximum of {}",
+                length,
+                super::smbus::SMBUS_BLOCK_MAX
+            ),
         }
     }
 }
@@ -106,7 +245,9 @@ pub struct I2CMaster<'a> {
     /// Name of this bus to which this master is connected
     pub bus: &'a str,
     /// Address of this master
-    pub address: u16,
+    pub address: I2CAddress,
+    /// Whether the SMBus transactions in `client::smbus` use Packet Error Checking
+    pub pec: bool,
 }
 
 impl<'a> I2CMaster<'a> {
@@ -133,14 +274,16 @@ impl<'a> I2CMaster<'a> {
     /// let master = I2CMaster::new(10u16, "tcp://localhost:12001", "i2c20");
     /// assert!(master.is_err());
     /// ```
-    pub fn new(address: u16, connection: &'a str, bus: &'a str) -> Result<I2CMaster<'a>, I2CError> {
-        if address < 8 || address > 127 {
-            return Err(I2CError::InvalidAddress { address });
-        }
+    pub fn new<A: Into<I2CAddress>>(
+        address: A,
+        connection: &'a str,
+        bus: &'a str,
+    ) -> Result<I2CMaster<'a>, I2CError> {
+        let address = address.into().validate()?;
         let c_connection = CString::new(connection)?;
         let c_bus = CString::new(bus)?;
 
-        let i2c_ptr = i2c::i2c_init_master(address, c_connection.as_ptr(), c_bus.as_ptr());
+        let i2c_ptr = i2c::i2c_init_master(address.raw(), c_connection.as_ptr(), c_bus.as_ptr());
 
         if i2c_ptr.is_null() {
             Err(I2CError::I2CCreationError)
@@ -150,6 +293,7 @@ impl<'a> I2CMaster<'a> {
                 connection,
                 bus,
                 address,
+                pec: false,
             })
         }
     }
@@ -163,13 +307,15 @@ impl<'a> I2CMaster<'a> {
     /// # Examples
     ///
     /// See [`nosengine-rust::client::i2c`](../i2c/index.html#examples)
-    pub fn read(&self, address: u16, num_bytes: usize) -> Result<Vec<u8>, I2CError> {
-        if address < 8 || address > 127 {
-            return Err(I2CError::InvalidAddress { address });
-        }
+    pub fn read<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        num_bytes: usize,
+    ) -> Result<Vec<u8>, I2CError> {
+        let address = address.into().validate()?;
         let mut rbuf: Vec<u8> = Vec::with_capacity(num_bytes);
         rbuf.resize(num_bytes, 0u8);
-        match i2c::i2c_read(self.i2c_ptr, address, rbuf.as_mut_ptr(), num_bytes) {
+        match i2c::i2c_read(self.i2c_ptr, address.raw(), rbuf.as_mut_ptr(), num_bytes) {
             i2c::I2CStatus::Success => Ok(rbuf),
             i2c::I2CStatus::Failure => Err(I2CError::UnknownAddress { address }),
         }
@@ -184,11 +330,9 @@ impl<'a> I2CMaster<'a> {
     /// # Examples
     ///
     /// See [`nosengine-rust::client::i2c`](../i2c/index.html#examples)
-    pub fn write(&self, address: u16, data: &[u8]) -> Result<(), I2CError> {
-        if address < 8 || address > 127 {
-            return Err(I2CError::InvalidAddress { address });
-        }
-        match i2c::i2c_write(self.i2c_ptr, address, data.as_ptr(), data.len()) {
+    pub fn write<A: Into<I2CAddress>>(&self, address: A, data: &[u8]) -> Result<(), I2CError> {
+        let address = address.into().validate()?;
+        match i2c::i2c_write(self.i2c_ptr, address.raw(), data.as_ptr(), data.len()) {
             i2c::I2CStatus::Success => {
                 println!("i2c.write: {:?}", data);
                 Ok(())
@@ -202,17 +346,18 @@ impl<'a> I2CMaster<'a> {
     ///
     /// * `tx_data`: Bytes to write to the device
     /// * `rx_len`: Number of bytes expected to be read
-    pub fn transaction(
+    pub fn transaction<A: Into<I2CAddress>>(
         &self,
-        address: u16,
+        address: A,
         tx_data: &[u8],
         rx_len: usize,
     ) -> Result<Vec<u8>, I2CError> {
+        let address = address.into().validate()?;
         let mut rbuf: Vec<u8> = Vec::with_capacity(rx_len);
         rbuf.resize(rx_len, 0u8);
         match i2c::i2c_transaction(
             self.i2c_ptr,
-            address,
+            address.raw(),
             tx_data.as_ptr(),
             tx_data.len(),
             rbuf.as_mut_ptr(),
@@ -238,7 +383,8 @@ pub struct I2CSlave<'a> {
     /// Name of the bus to which this slave is connected
     pub bus: &'a str,
     /// Address of this slave
-    pub address: u16,
+    pub address: I2CAddress,
+    handler_slot: Option<usize>,
 }
 
 impl<'a> I2CSlave<'a> {
@@ -260,18 +406,109 @@ impl<'a> I2CSlave<'a> {
     ///         to which this device should write data. It is guaranteed to have enough bytes of
     ///         valid memory based on the length argument
     ///     * `usize`: The number of bytes being read or written
-    pub fn new(
-        address: u16,
+    pub fn new<A: Into<I2CAddress>>(
+        address: A,
         connection: &'a str,
         bus: &'a str,
         callback: extern "C" fn(i2c::I2CDirection, *mut u8, usize) -> usize,
     ) -> Result<I2CSlave<'a>, I2CError> {
+        let address = address.into().validate()?;
+        let c_connection = CString::new(connection)?;
+        let c_bus = CString::new(bus)?;
+
+        let i2c_ptr = i2c::i2c_init_slave(
+            address.raw(),
+            c_connection.as_ptr(),
+            c_bus.as_ptr(),
+            callback,
+        );
+
+        if i2c_ptr.is_null() {
+            Err(I2CError::I2CCreationError)
+        } else {
+            Ok(I2CSlave {
+                i2c_ptr,
+                connection,
+                bus,
+                address,
+                handler_slot: None,
+            })
+        }
+    }
+
+    /// Constructs a new I2C slave which handles the master's reads and writes with a closure.
+    /// Unlike `I2CSlave::new`, the closure can capture its environment. At most
+    /// `MAX_HANDLER_SLAVES` slaves created this way can exist at once.
+    ///
+    /// A master's `transaction` reaches the slave as a write followed by a read.
+    ///
+    /// # Arguments
+    ///
+    /// * `address`: Address for this slave. Must be unique on a bus
+    /// * `connection`: NOSEngine connection string
+    /// * `bus`: Name of the bus to connect to
+    /// * `handler`: Closure that runs every time the master reads from or writes to this device.
+    ///     It receives the direction and the buffer, which holds the data being written, or
+    ///     should be filled with the data being read. It returns the number of bytes read or
+    ///     written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::i2c::*;
+    /// # use nosengine_rust::ffi::i2c::I2CDirection;
+    /// # use std::sync::{Arc, Mutex};
+    /// let master = I2CMaster::new(9u16, "tcp://localhost:12001", "i2c21").unwrap();
+    ///
+    /// let registers = Arc::new(Mutex::new([0u8; 4]));
+    /// let slave_registers = registers.clone();
+    /// let mut pointer = 0usize;
+    /// let slave = I2CSlave::with_handler(8u16, "tcp://localhost:12001", "i2c21", move |dir, data| {
+    ///     let mut registers = slave_registers.lock().unwrap();
+    ///     match dir {
+    ///         I2CDirection::Write => {
+    ///             pointer = data[0] as usize;
+    ///             for (i, byte) in data[1..].iter().enumerate() {
+    ///                 registers[pointer + i] = *byte;
+    ///             }
+    ///         }
+    ///         I2CDirection::Read => {
+    ///             data.copy_from_slice(&registers[pointer..pointer + data.len()]);
+    ///         }
+    ///     }
+    ///     data.len()
+    /// }).unwrap();
+    ///
+    /// master.write(8u16, &[1u8, 0x12, 0x34]).unwrap();
+    /// assert_eq!(master.transaction(8u16, &[1u8], 2), Ok(vec![0x12u8, 0x34]));
+    /// assert_eq!(*registers.lock().unwrap(), [0u8, 0x12, 0x34, 0]);
+    /// ```
+    pub fn with_handler<A, F>(
+        address: A,
+        connection: &'a str,
+        bus: &'a str,
+        handler: F,
+    ) -> Result<I2CSlave<'a>, I2CError>
+    where
+        A: Into<I2CAddress>,
+        F: FnMut(i2c::I2CDirection, &mut [u8]) -> usize,
+        F: Send + 'static,
+    {
+        let address = address.into().validate()?;
         let c_connection = CString::new(connection)?;
         let c_bus = CString::new(bus)?;
 
-        let i2c_ptr = i2c::i2c_init_slave(address, c_connection.as_ptr(), c_bus.as_ptr(), callback);
+        let slot = claim_handler_slot(Box::new(handler)).ok_or(I2CError::TooManyHandlers)?;
+        let i2c_ptr = i2c::i2c_init_slave(
+            address.raw(),
+            c_connection.as_ptr(),
+            c_bus.as_ptr(),
+            SLAVE_CALLBACKS[slot],
+        );
 
         if i2c_ptr.is_null() {
+            release_handler_slot(slot);
             Err(I2CError::I2CCreationError)
         } else {
             Ok(I2CSlave {
@@ -279,6 +516,7 @@ impl<'a> I2CSlave<'a> {
                 connection,
                 bus,
                 address,
+                handler_slot: Some(slot),
             })
         }
     }
@@ -287,5 +525,10 @@ impl<'a> I2CSlave<'a> {
 impl<'a> Drop for I2CSlave<'a> {
     fn drop(&mut self) {
         i2c::i2c_close(&mut self.i2c_ptr as *mut *mut i2c::I2CHandle);
+        if let Some(slot) = self.handler_slot.take() {
+            release_handler_slot(slot);
+        }
     }
 }
+
+unsafe impl<'a> Send for I2CSlave<'a> {}
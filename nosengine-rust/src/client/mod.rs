@@ -64,15 +64,106 @@
 //! assert_eq!(response.get_contents(), &[5u8, 6, 7, 8]);
 //! ```
 
+/// The NOSEngine C API does not pass any user data to I2C and SPI slave callbacks, so there is no
+/// way for one `extern "C"` function to tell slaves apart. This macro generates a fixed table of
+/// callbacks, each of which forwards to the closure stored in its own slot. Each slave with a
+/// closure handler claims one slot for as long as it exists.
+macro_rules! slave_handlers {
+    ($dir:ty, $max:expr, [$($slot:expr => $name:ident),*]) => {
+        /// A closure which handles reads and writes from the master.
+        type SlaveHandler = Box<FnMut($dir, &mut [u8]) -> usize + Send>;
+
+        lazy_static! {
+            static ref SLAVE_HANDLERS: ::std::sync::Mutex<
+                Vec<Option<::std::sync::Arc<::std::sync::Mutex<SlaveHandler>>>>,
+            > = ::std::sync::Mutex::new((0..$max).map(|_| None).collect());
+        }
+
+        fn dispatch(slot: usize, dir: $dir, buffer: *mut u8, len: usize) -> usize {
+            // Clone the handler out of the table so that the table is not locked while it runs
+            let handler = SLAVE_HANDLERS.lock().unwrap()[slot].clone();
+            match handler {
+                Some(handler) => {
+                    let data = unsafe { ::std::slice::from_raw_parts_mut(buffer, len) };
+                    let mut func = handler.lock().unwrap();
+                    (&mut *func)(dir, data)
+                }
+                None => 0,
+            }
+        }
+
+        $(
+            extern "C" fn $name(dir: $dir, buffer: *mut u8, len: usize) -> usize {
+                dispatch($slot, dir, buffer, len)
+            }
+        )*
+
+        const SLAVE_CALLBACKS: [extern "C" fn($dir, *mut u8, usize) -> usize; $max] =
+            [$($name),*];
+
+        /// Stores `handler` in a free slot, returning the slot, or `None` if every slot is taken.
+        fn claim_handler_slot(handler: SlaveHandler) -> Option<usize> {
+            let mut handlers = SLAVE_HANDLERS.lock().unwrap();
+            let slot = handlers.iter().position(|handler| handler.is_none())?;
+            handlers[slot] = Some(::std::sync::Arc::new(::std::sync::Mutex::new(handler)));
+            Some(slot)
+        }
+
+        fn release_handler_slot(slot: usize) {
+            SLAVE_HANDLERS.lock().unwrap()[slot] = None;
+        }
+    };
+}
+
+pub mod can;
 pub mod i2c;
+pub mod rpc;
+pub mod smbus;
 pub mod spi;
+pub mod time;
+pub mod typed;
 pub mod uart;
 
 use super::ffi;
 use std;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// A closure set with `DataNode::set_message_handler`. It receives the contents of each message,
+/// and if it returns `Some`, those bytes are sent back as the reply.
+type MessageHandler = Box<FnMut(&[u8]) -> Option<Vec<u8>> + Send>;
+
+lazy_static! {
+    /// The NOSEngine C API does not pass any user data to message callbacks, so closures are
+    /// looked up by the address of the data node that received the message.
+    static ref MESSAGE_HANDLERS: Mutex<HashMap<usize, Arc<Mutex<MessageHandler>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// This is the callback handed to NOSEngine for every node that has a message handler.
+extern "C" fn message_handler_callback(
+    node_ptr: *mut ffi::DataNodeHandle,
+    msg_ptr: *mut ffi::MessageHandle,
+) {
+    // Clone the handler out of the map so that the global lock is not held while it runs.
+    // Otherwise a handler which sends a message to another node in this process would deadlock.
+    let handler = match MESSAGE_HANDLERS.lock() {
+        Ok(handlers) => handlers.get(&(node_ptr as usize)).cloned(),
+        Err(_) => None,
+    };
+    if let Some(handler) = handler {
+        let data = unsafe { Message::get_contents_from_ptr(msg_ptr) };
+        let reply = match handler.lock() {
+            Ok(mut func) => (&mut *func)(data),
+            Err(_) => None,
+        };
+        if let Some(reply) = reply {
+            DataNode::send_reply_message_ptr(node_ptr, msg_ptr, &reply);
+        }
+    }
+}
 
 /// This function returns the most recent NOSEngine error in the current thread.
 fn get_nos_error() -> NosError {
@@ -437,10 +528,50 @@ impl DataNode {
     ) {
         ffi::data_node_set_message_received_callback(self.node_ptr, func);
     }
+
+    /// Sets a closure which will be called each time this node receives a message. Unlike
+    /// `DataNode::set_message_callback`, the closure can capture its environment, and it is given
+    /// the contents of the message directly. If the closure returns `Some`, the returned bytes are
+    /// sent as the reply to the message, so this can be used to answer
+    /// `DataNode::send_request_message`.
+    ///
+    /// Setting a new handler replaces the previous one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::*;
+    /// let bus = Bus::new("testbus2", "tcp://localhost:12001").unwrap();
+    /// let node1 = DataNode::new(&bus, "node7").unwrap();
+    /// let node2 = DataNode::new(&bus, "node8").unwrap();
+    ///
+    /// let offset = 4u8;
+    /// node2.set_message_handler(move |data: &[u8]| {
+    ///     Some(data.iter().map(|b| b + offset).collect())
+    /// });
+    /// let response = node1.send_request_message("node8", &[1u8, 2, 3, 4]).unwrap();
+    /// assert_eq!(response.get_contents(), &[5u8, 6, 7, 8]);
+    /// ```
+    pub fn set_message_handler<F>(&self, func: F)
+    where
+        F: FnMut(&[u8]) -> Option<Vec<u8>>,
+        F: Send + 'static,
+    {
+        let func = Box::new(func) as MessageHandler;
+        MESSAGE_HANDLERS
+            .lock()
+            .unwrap()
+            .insert(self.node_ptr as usize, Arc::new(Mutex::new(func)));
+        ffi::data_node_set_message_received_callback(self.node_ptr, message_handler_callback);
+    }
 }
 
 impl Drop for DataNode {
     fn drop(&mut self) {
+        if let Ok(mut handlers) = MESSAGE_HANDLERS.lock() {
+            handlers.remove(&(self.node_ptr as usize));
+        }
         if !self.node_ptr.is_null() {
             ffi::destroy_data_node(
                 self.bus_ptr.bus_ptr,
@@ -0,0 +1,641 @@
+//! A small remote procedure call framework on top of `DataNode`. An `RpcServer` owns a data node
+//! and dispatches each incoming call to the handler registered for its method. Handlers run on a
+//! pool of worker threads, and each result is sent back to the caller as a message of its own as
+//! soon as the handler returns. An `RpcClient` sends each call as a message and waits, with a
+//! timeout, for the reply carrying the same call ID.
+//!
+//! Calls and replies are plain messages rather than NOSEngine requests, because a request can only
+//! be answered from within the callback which received it, and the sender of a request waits for
+//! the answer without a timeout. A server therefore runs calls in parallel, up to the number of
+//! workers, and a handler which panics is answered with an error rather than left unanswered.
+//!
+//! Methods can be identified either by a numeric ID or by name. Both are carried in a small
+//! envelope in front of the parameters, so any component that can send a `DataNode` message can
+//! take part. The envelope is laid out as follows (multi-byte fields are big-endian):
+//!
+//! * Call: `0x00`, call ID (`u32`), caller's node name length (`u8`), caller's node name, method,
+//!     parameters
+//! * Method: `0x00` followed by the ID (`u16`), or `0x01`, the name length (`u8`) and the name
+//! * Reply: `0x01`, call ID (`u32`), status (`u8`), then the result, or a UTF-8 error message
+//!     if the status is not `0x00`
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate nosengine_rust;
+//! # use nosengine_rust::client::*;
+//! # use nosengine_rust::client::rpc::*;
+//! # use std::time::Duration;
+//! let bus = Bus::new("rpcbus", "tcp://localhost:12001").unwrap();
+//!
+//! let mut server = RpcServer::new(&bus, "power").unwrap();
+//! server.register(1u16, |params: &[u8]| Ok(params.iter().rev().cloned().collect()));
+//! server.register("set_fault", |params: &[u8]| match params {
+//!     [0] | [1] => Ok(vec![]),
+//!     _ => Err(String::from("fault must be 0 or 1")),
+//! });
+//! server.start(2);
+//!
+//! let client = RpcClient::new(&bus, "power_client").unwrap();
+//! assert_eq!(client.call("power", 1u16, &[1u8, 2, 3]), Ok(vec![3u8, 2, 1]));
+//! assert_eq!(client.call("power", "set_fault", &[1u8]), Ok(vec![]));
+//! assert_eq!(
+//!     client.call("power", "set_fault", &[7u8]),
+//!     Err(RpcError::HandlerError {
+//!         description: String::from("fault must be 0 or 1")
+//!     })
+//! );
+//! assert_eq!(
+//!     client.call("power", "query_state", &[]),
+//!     Err(RpcError::UnknownMethod {
+//!         method: Method::from("query_state")
+//!     })
+//! );
+//! assert_eq!(
+//!     client.call_timeout("nobody", 1u16, &[], Duration::from_millis(100)),
+//!     Err(RpcError::Timeout)
+//! );
+//! // A call which timed out does not hold up the next one
+//! assert_eq!(client.call("power", 1u16, &[4u8, 5]), Ok(vec![5u8, 4]));
+//! ```
+
+use super::typed::{Codec, TypedError};
+use super::{Bus, DataNode, NosError};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+const KIND_CALL: u8 = 0x00;
+const KIND_REPLY: u8 = 0x01;
+
+const METHOD_ID: u8 = 0x00;
+const METHOD_NAME: u8 = 0x01;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_UNKNOWN_METHOD: u8 = 0x01;
+const STATUS_HANDLER_ERROR: u8 = 0x02;
+const STATUS_MALFORMED: u8 = 0x03;
+
+/// How long `RpcClient::call` waits for a reply before giving up.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Identifies a method on an `RpcServer`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// A method identified by a number
+    Id(u16),
+    /// A method identified by name. Names longer than 255 bytes cannot be encoded.
+    Name(String),
+}
+
+impl From<u16> for Method {
+    fn from(id: u16) -> Self {
+        Method::Id(id)
+    }
+}
+
+impl<'a> From<&'a str> for Method {
+    fn from(name: &'a str) -> Self {
+        Method::Name(String::from(name))
+    }
+}
+
+impl From<String> for Method {
+    fn from(name: String) -> Self {
+        Method::Name(name)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Method::Id(id) => write!(f, "#{}", id),
+            Method::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+/// This enum represents any type of error that can occur when making a remote procedure call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// An error occurred in the underlying `DataNode`.
+    NosEngineError(NosError),
+    /// No reply was received before the timeout expired.
+    Timeout,
+    /// The server has no handler registered for the method.
+    UnknownMethod {
+        /// The method which was called
+        method: Method,
+    },
+    /// The handler on the server returned an error.
+    HandlerError {
+        /// The error message returned by the handler
+        description: String,
+    },
+    /// A call or reply envelope could not be parsed, or a method name was too long to encode.
+    MalformedMessage,
+    /// Typed parameters or results could not be encoded or decoded.
+    CodecError(TypedError),
+}
+
+impl From<NosError> for RpcError {
+    fn from(err: NosError) -> Self {
+        RpcError::NosEngineError(err)
+    }
+}
+
+impl From<TypedError> for RpcError {
+    fn from(err: TypedError) -> Self {
+        match err {
+            TypedError::NosEngineError(err) => RpcError::NosEngineError(err),
+            err => RpcError::CodecError(err),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::NosEngineError(err) => write!(f, "{}", err),
+            RpcError::Timeout => write!(f, "Timed out waiting for reply"),
+            RpcError::UnknownMethod { method } => write!(f, "Unknown method {}", method),
+            RpcError::HandlerError { description } => {
+                write!(f, "Error in method handler: {}", description)
+            }
+            RpcError::MalformedMessage => write!(f, "Malformed RPC message"),
+            RpcError::CodecError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for RpcError {
+    fn description(&self) -> &str {
+        match self {
+            RpcError::NosEngineError(err) => err.description(),
+            RpcError::Timeout => "Timed out waiting for reply",
+            RpcError::UnknownMethod { method: _ } => "Unknown method",
+            RpcError::HandlerError { description } => &description,
+            RpcError::MalformedMessage => "Malformed RPC message",
+            RpcError::CodecError(err) => err.description(),
+        }
+    }
+}
+
+/// A method handler. It receives the call parameters and returns either the result, or an error
+/// message which is passed back to the caller.
+type Handler = Arc<Fn(&[u8]) -> Result<Vec<u8>, String> + Send + Sync>;
+
+/// One decoded call, waiting for a worker.
+struct Call {
+    id: u32,
+    /// Name of the node to which the reply is sent
+    caller: String,
+    /// The method and its parameters, or `None` if they could not be decoded
+    request: Option<(Method, Vec<u8>)>,
+}
+
+fn encode_method(method: &Method, buf: &mut Vec<u8>) -> Result<(), RpcError> {
+    match method {
+        Method::Id(id) => {
+            buf.push(METHOD_ID);
+            buf.push((id >> 8) as u8);
+            buf.push(*id as u8);
+        }
+        Method::Name(name) => {
+            if name.len() > 255 {
+                return Err(RpcError::MalformedMessage);
+            }
+            buf.push(METHOD_NAME);
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(name.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+fn encode_call(
+    id: u32,
+    reply_to: &str,
+    method: &Method,
+    params: &[u8],
+) -> Result<Vec<u8>, RpcError> {
+    if reply_to.len() > 255 {
+        return Err(RpcError::MalformedMessage);
+    }
+    let mut buf = Vec::with_capacity(params.len() + reply_to.len() + 16);
+    buf.push(KIND_CALL);
+    buf.extend_from_slice(&be_u32(id));
+    buf.push(reply_to.len() as u8);
+    buf.extend_from_slice(reply_to.as_bytes());
+    encode_method(method, &mut buf)?;
+    buf.extend_from_slice(params);
+    Ok(buf)
+}
+
+fn encode_reply(id: u32, status: u8, result: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(result.len() + 6);
+    buf.push(KIND_REPLY);
+    buf.extend_from_slice(&be_u32(id));
+    buf.push(status);
+    buf.extend_from_slice(result);
+    buf
+}
+
+fn be_u32(value: u32) -> [u8; 4] {
+    [
+        (value >> 24) as u8,
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ]
+}
+
+/// Reads a length-prefixed string from the front of `data`, returning it and the rest of `data`.
+fn take_string(data: &[u8]) -> Option<(String, &[u8])> {
+    let len = *data.get(0)? as usize;
+    if data.len() < len + 1 {
+        return None;
+    }
+    let string = String::from_utf8(data[1..len + 1].to_vec()).ok()?;
+    Some((string, &data[len + 1..]))
+}
+
+/// Splits the kind and call ID off the front of an envelope.
+fn decode_header(data: &[u8]) -> Option<(u8, u32, &[u8])> {
+    if data.len() < 5 {
+        return None;
+    }
+    let id = (u32::from(data[1]) << 24)
+        | (u32::from(data[2]) << 16)
+        | (u32::from(data[3]) << 8)
+        | u32::from(data[4]);
+    Some((data[0], id, &data[5..]))
+}
+
+fn decode_call(id: u32, data: &[u8]) -> Option<Call> {
+    // Without the caller's name there is nowhere to send a reply, not even an error
+    let (caller, data) = take_string(data)?;
+    Some(Call {
+        id,
+        caller,
+        request: decode_method(data),
+    })
+}
+
+fn decode_method(data: &[u8]) -> Option<(Method, Vec<u8>)> {
+    let (method, params) = match *data.get(0)? {
+        METHOD_ID if data.len() >= 3 => (
+            Method::Id((u16::from(data[1]) << 8) | u16::from(data[2])),
+            &data[3..],
+        ),
+        METHOD_NAME => {
+            let (name, params) = take_string(&data[1..])?;
+            (Method::Name(name), params)
+        }
+        _ => return None,
+    };
+    Some((method, params.to_vec()))
+}
+
+/// Runs one call and encodes its reply. A handler which panics is answered with an error.
+fn run_call(handlers: &RwLock<HashMap<Method, Handler>>, call: &Call) -> Vec<u8> {
+    let (method, params) = match &call.request {
+        Some(request) => request,
+        None => return encode_reply(call.id, STATUS_MALFORMED, &[]),
+    };
+    let handler = handlers.read().unwrap().get(method).cloned();
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return encode_reply(call.id, STATUS_UNKNOWN_METHOD, &[]),
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(params))) {
+        Ok(Ok(result)) => encode_reply(call.id, STATUS_OK, &result),
+        Ok(Err(err)) => encode_reply(call.id, STATUS_HANDLER_ERROR, err.as_bytes()),
+        Err(payload) => {
+            let err = format!(
+                "handler for {} panicked: {}",
+                method,
+                panic_message(&*payload)
+            );
+            encode_reply(call.id, STATUS_HANDLER_ERROR, err.as_bytes())
+        }
+    }
+}
+
+fn panic_message(payload: &(Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Serves remote procedure calls on a data node.
+pub struct RpcServer {
+    /// Shared with the workers, which send the replies
+    node: Arc<Mutex<DataNode>>,
+    handlers: Arc<RwLock<HashMap<Method, Handler>>>,
+}
+
+impl RpcServer {
+    /// Creates a server with a data node of the given name. The name is what clients pass as the
+    /// destination of their calls. No calls are served until `RpcServer::start` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus`: Bus on which to create the node
+    /// * `name`: Name of the server's data node. Must be unique on a bus.
+    pub fn new(bus: &Arc<Bus>, name: &str) -> Result<RpcServer, NosError> {
+        Ok(RpcServer {
+            node: Arc::new(Mutex::new(DataNode::new(bus, name)?)),
+            handlers: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    /// Registers a handler for a method, replacing any previous handler for that method. Handlers
+    /// can be registered before or after the server is started.
+    ///
+    /// # Arguments
+    ///
+    /// * `method`: A method ID (`u16`) or name (`&str`)
+    /// * `handler`: Closure which receives the parameters of each call, and returns the result
+    ///     to be sent back, or an error message
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::rpc`](../rpc/index.html#examples)
+    pub fn register<M, F>(&mut self, method: M, handler: F)
+    where
+        M: Into<Method>,
+        F: Fn(&[u8]) -> Result<Vec<u8>, String>,
+        F: Send + Sync + 'static,
+    {
+        self.handlers
+            .write()
+            .unwrap()
+            .insert(method.into(), Arc::new(handler));
+    }
+
+    /// Registers a handler whose parameters and result are encoded with the codec `C`. Calls
+    /// whose parameters cannot be decoded are answered with an error without running the handler.
+    pub fn register_typed<C, M, P, R, F>(&mut self, method: M, handler: F)
+    where
+        C: Codec,
+        M: Into<Method>,
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(P) -> Result<R, String>,
+        F: Send + Sync + 'static,
+    {
+        self.register(method, move |params: &[u8]| {
+            let params = C::decode(params).map_err(|err| err.to_string())?;
+            let result = handler(params)?;
+            C::encode(&result).map_err(|err| err.to_string())
+        });
+    }
+
+    /// Starts serving calls. Each call is decoded as it arrives, run on one of `workers` threads,
+    /// and its result sent to the caller as soon as the handler returns.
+    ///
+    /// # Arguments
+    ///
+    /// * `workers`: Number of worker threads. At least one is always started.
+    pub fn start(&mut self, workers: usize) {
+        let (sender, receiver): (Sender<Call>, Receiver<Call>) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let handlers = self.handlers.clone();
+            let node = self.node.clone();
+            thread::spawn(move || loop {
+                let call = match receiver.lock().unwrap().recv() {
+                    Ok(call) => call,
+                    // The server was dropped
+                    Err(_) => break,
+                };
+                let reply = run_call(&handlers, &call);
+                // A caller which has gone away is not the server's problem
+                let _ = node.lock().unwrap().send_message(&call.caller, &reply);
+            });
+        }
+
+        let sender = Mutex::new(sender);
+        self.node
+            .lock()
+            .unwrap()
+            .set_message_handler(move |data: &[u8]| {
+                if let Some((KIND_CALL, id, body)) = decode_header(data) {
+                    if let Some(call) = decode_call(id, body) {
+                        let _ = sender.lock().unwrap().send(call);
+                    }
+                }
+                None
+            });
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        // Releases the call sender, which stops the workers once they finish their calls
+        self.node
+            .lock()
+            .unwrap()
+            .set_message_handler(|_: &[u8]| None);
+    }
+}
+
+/// Makes remote procedure calls to `RpcServer`s.
+pub struct RpcClient {
+    /// Taken for each send, as a data node cannot be used from several threads at once
+    node: Mutex<DataNode>,
+    name: String,
+    next_id: AtomicUsize,
+    /// Calls waiting for their reply, by call ID
+    pending: Arc<Mutex<HashMap<u32, Sender<Vec<u8>>>>>,
+    /// Timeout used by `RpcClient::call`. Defaults to `DEFAULT_TIMEOUT`.
+    pub timeout: Duration,
+}
+
+impl RpcClient {
+    /// Creates a client with a data node of the given name, from which calls are sent and to which
+    /// replies are sent back.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus`: Bus on which to create the node
+    /// * `name`: Name of the client's data node. Must be unique on a bus.
+    pub fn new(bus: &Arc<Bus>, name: &str) -> Result<RpcClient, NosError> {
+        let node = DataNode::new(bus, name)?;
+        let pending: Arc<Mutex<HashMap<u32, Sender<Vec<u8>>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let replies = pending.clone();
+        node.set_message_handler(move |data: &[u8]| {
+            // Replies to calls which have timed out are no longer pending, and are dropped
+            if let Some((KIND_REPLY, id, _)) = decode_header(data) {
+                if let Some(waiting) = replies.lock().unwrap().remove(&id) {
+                    let _ = waiting.send(data.to_vec());
+                }
+            }
+            None
+        });
+        Ok(RpcClient {
+            node: Mutex::new(node),
+            name: String::from(name),
+            next_id: AtomicUsize::new(0),
+            pending,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Calls a method on a server, and waits up to `RpcClient::timeout` for the result.
+    ///
+    /// # Arguments
+    ///
+    /// * `server`: Name of the server's data node
+    /// * `method`: A method ID (`u16`) or name (`&str`)
+    /// * `params`: Parameters passed to the handler
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::rpc`](../rpc/index.html#examples)
+    pub fn call<M: Into<Method>>(
+        &self,
+        server: &str,
+        method: M,
+        params: &[u8],
+    ) -> Result<Vec<u8>, RpcError> {
+        self.call_timeout(server, method, params, self.timeout)
+    }
+
+    /// Calls a method on a server, and waits up to `timeout` for the result. Calls can be made
+    /// from several threads at once, each waiting only for its own reply.
+    pub fn call_timeout<M: Into<Method>>(
+        &self,
+        server: &str,
+        method: M,
+        params: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RpcError> {
+        let method = method.into();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u32;
+        let call = encode_call(id, &self.name, &method, params)?;
+
+        let (sender, receiver) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        let sent = self.node.lock().unwrap().send_message(server, &call);
+        let reply = match sent {
+            Ok(()) => receiver
+                .recv_timeout(timeout)
+                .map_err(|_| RpcError::Timeout),
+            Err(err) => Err(RpcError::from(err)),
+        };
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(err) => {
+                self.pending.lock().unwrap().remove(&id);
+                return Err(err);
+            }
+        };
+
+        let (status, result) = match decode_header(&reply) {
+            Some((KIND_REPLY, reply_id, body)) if reply_id == id && !body.is_empty() => {
+                (body[0], &body[1..])
+            }
+            _ => return Err(RpcError::MalformedMessage),
+        };
+        match status {
+            STATUS_OK => Ok(result.to_vec()),
+            STATUS_UNKNOWN_METHOD => Err(RpcError::UnknownMethod { method }),
+            STATUS_HANDLER_ERROR => Err(RpcError::HandlerError {
+                description: String::from_utf8_lossy(result).into_owned(),
+            }),
+            _ => Err(RpcError::MalformedMessage),
+        }
+    }
+
+    /// Calls a method whose parameters and result are encoded with the codec `C`, and waits up to
+    /// `RpcClient::timeout` for the result.
+    pub fn call_typed<C, M, P, R>(&self, server: &str, method: M, params: &P) -> Result<R, RpcError>
+    where
+        C: Codec,
+        M: Into<Method>,
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let result = self.call(server, method, &C::encode(params)?)?;
+        Ok(C::decode(&result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handlers() -> RwLock<HashMap<Method, Handler>> {
+        let mut handlers: HashMap<Method, Handler> = HashMap::new();
+        handlers.insert(Method::Id(1), Arc::new(|params: &[u8]| Ok(params.to_vec())));
+        handlers.insert(Method::from("explode"), Arc::new(|_: &[u8]| panic!("boom")));
+        RwLock::new(handlers)
+    }
+
+    fn call(method: Method, params: &[u8]) -> Call {
+        let data = encode_call(7, "client", &method, params).unwrap();
+        match decode_header(&data) {
+            Some((KIND_CALL, id, body)) => decode_call(id, body).unwrap(),
+            _ => panic!("not a call"),
+        }
+    }
+
+    #[test]
+    fn calls_decode_to_their_caller_method_and_parameters() {
+        let call = call(Method::from("set_fault"), &[1, 2]);
+        assert_eq!(call.id, 7);
+        assert_eq!(call.caller, "client");
+        assert_eq!(
+            call.request,
+            Some((Method::from("set_fault"), vec![1u8, 2]))
+        );
+    }
+
+    #[test]
+    fn every_call_gets_a_reply() {
+        let handlers = handlers();
+        assert_eq!(
+            run_call(&handlers, &call(Method::Id(1), &[3])),
+            encode_reply(7, STATUS_OK, &[3])
+        );
+        assert_eq!(
+            run_call(&handlers, &call(Method::Id(2), &[])),
+            encode_reply(7, STATUS_UNKNOWN_METHOD, &[])
+        );
+        assert_eq!(
+            run_call(&handlers, &call(Method::from("explode"), &[])),
+            encode_reply(
+                7,
+                STATUS_HANDLER_ERROR,
+                b"handler for 'explode' panicked: boom"
+            )
+        );
+        let malformed = Call {
+            id: 7,
+            caller: String::from("client"),
+            request: None,
+        };
+        assert_eq!(
+            run_call(&handlers, &malformed),
+            encode_reply(7, STATUS_MALFORMED, &[])
+        );
+    }
+}
@@ -0,0 +1,701 @@
+//! SMBus protocols on top of NOSEngine I2C.
+//!
+//! `I2CMaster` gains the SMBus transactions (`smbus_write_quick` through `smbus_process_call`),
+//! and `I2CSlave::smbus` decodes them again on the device side, so that a simulated SMBus device
+//! only has to implement the parts of `SMBusDevice` it supports.
+//!
+//! When Packet Error Checking is on (`I2CMaster::pec` for the master, and the `pec` argument to
+//! `I2CSlave::smbus` for the device), each transaction carries a CRC-8 of all its bytes, including
+//! the address bytes. Both sides have to agree on it, just as on a real bus. SMBus only defines
+//! 7-bit addressing, so PEC uses the low 7 bits of the address.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate nosengine_rust;
+//! # use nosengine_rust::client::i2c::*;
+//! # use nosengine_rust::client::smbus::*;
+//! struct Thermometer {
+//!     limit: u16,
+//! }
+//!
+//! impl SMBusDevice for Thermometer {
+//!     fn read_word(&mut self, command: u8) -> u16 {
+//!         match command {
+//!             0x00 => 0x1234, // temperature
+//!             0x01 => self.limit,
+//!             _ => 0xFFFF,
+//!         }
+//!     }
+//!
+//!     fn write_word(&mut self, command: u8, value: u16) {
+//!         if command == 0x01 {
+//!             self.limit = value;
+//!         }
+//!     }
+//! }
+//!
+//! let mut master = I2CMaster::new(9u16, "tcp://localhost:12001", "i2c23").unwrap();
+//! master.pec = true;
+//! let slave = I2CSlave::smbus(8u16, "tcp://localhost:12001", "i2c23", true, Thermometer {
+//!     limit: 0,
+//! }).unwrap();
+//!
+//! assert_eq!(master.smbus_read_word_data(8u16, 0x00), Ok(0x1234));
+//! master.smbus_write_word_data(8u16, 0x01, 0x0500).unwrap();
+//! assert_eq!(master.smbus_read_word_data(8u16, 0x01), Ok(0x0500));
+//! ```
+
+use super::ffi::i2c::I2CDirection;
+use super::i2c::{I2CAddress, I2CError, I2CMaster, I2CSlave};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The most data bytes in an SMBus block transfer.
+pub const SMBUS_BLOCK_MAX: usize = 32;
+
+/// How long an `I2CSlave::smbus` with PEC waits for a read to follow a write which could be the
+/// start of a process call, before taking it as a write byte.
+pub const SMBUS_SETTLE_TIME: Duration = Duration::from_millis(10);
+
+/// Computes the SMBus Packet Error Code of `data`, which is a CRC-8 with the polynomial
+/// x^8 + x^2 + x + 1.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate nosengine_rust;
+/// # use nosengine_rust::client::smbus::*;
+/// assert_eq!(crc8(b"123456789"), 0xF4);
+/// ```
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// The address byte which starts a write to `address`.
+fn write_address(address: I2CAddress) -> u8 {
+    (address.raw() << 1) as u8
+}
+
+/// The address byte which starts a read from `address`.
+fn read_address(address: I2CAddress) -> u8 {
+    write_address(address) | 1
+}
+
+impl<'a> I2CMaster<'a> {
+    /// Performs an SMBus quick command, which sends no data. `read` is the value of the
+    /// read/write bit.
+    pub fn smbus_write_quick<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        read: bool,
+    ) -> Result<(), I2CError> {
+        if read {
+            self.read(address, 0).map(|_| ())
+        } else {
+            self.write(address, &[])
+        }
+    }
+
+    /// Performs an SMBus receive byte, which reads one byte without a command.
+    pub fn smbus_read_byte<A: Into<I2CAddress>>(&self, address: A) -> Result<u8, I2CError> {
+        let address = address.into();
+        let data = self.read(address, 1 + self.pec as usize)?;
+        self.check_pec(&[read_address(address)], &data)?;
+        Ok(data[0])
+    }
+
+    /// Performs an SMBus send byte, which writes one byte without a command.
+    pub fn smbus_write_byte<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        value: u8,
+    ) -> Result<(), I2CError> {
+        self.smbus_write(address.into(), &[value])
+    }
+
+    /// Performs an SMBus read byte, which reads one byte from the given command.
+    pub fn smbus_read_byte_data<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        command: u8,
+    ) -> Result<u8, I2CError> {
+        Ok(self.smbus_read(address.into(), command, 1)?[0])
+    }
+
+    /// Performs an SMBus write byte, which writes one byte to the given command.
+    pub fn smbus_write_byte_data<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        command: u8,
+        value: u8,
+    ) -> Result<(), I2CError> {
+        self.smbus_write(address.into(), &[command, value])
+    }
+
+    /// Performs an SMBus read word, which reads a little-endian word from the given command.
+    pub fn smbus_read_word_data<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        command: u8,
+    ) -> Result<u16, I2CError> {
+        let data = self.smbus_read(address.into(), command, 2)?;
+        Ok(u16::from(data[0]) | u16::from(data[1]) << 8)
+    }
+
+    /// Performs an SMBus write word, which writes a little-endian word to the given command.
+    pub fn smbus_write_word_data<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        command: u8,
+        value: u16,
+    ) -> Result<(), I2CError> {
+        self.smbus_write(address.into(), &[command, value as u8, (value >> 8) as u8])
+    }
+
+    /// Performs an SMBus block read, which reads a count byte, then that many bytes, from the
+    /// given command.
+    pub fn smbus_read_block_data<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        command: u8,
+    ) -> Result<Vec<u8>, I2CError> {
+        let address = address.into();
+        // The length isn't known until the count byte arrives, so read as much as there could be
+        let mut data =
+            self.transaction(address, &[command], 1 + SMBUS_BLOCK_MAX + self.pec as usize)?;
+        let length = 1 + data[0] as usize;
+        if length > 1 + SMBUS_BLOCK_MAX {
+            return Err(I2CError::InvalidBlockLength { length: length - 1 });
+        }
+        data.truncate(length + self.pec as usize);
+        self.check_pec(
+            &[write_address(address), command, read_address(address)],
+            &data,
+        )?;
+        data.truncate(length);
+        data.remove(0);
+        Ok(data)
+    }
+
+    /// Performs an SMBus block write, which writes a count byte, then `data`, to the given
+    /// command. At most `SMBUS_BLOCK_MAX` bytes can be written.
+    pub fn smbus_write_block_data<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        command: u8,
+        data: &[u8],
+    ) -> Result<(), I2CError> {
+        if data.len() > SMBUS_BLOCK_MAX {
+            return Err(I2CError::InvalidBlockLength { length: data.len() });
+        }
+        let mut message = vec![command, data.len() as u8];
+        message.extend_from_slice(data);
+        self.smbus_write(address.into(), &message)
+    }
+
+    /// Performs an SMBus process call, which writes a word to the given command, then reads a
+    /// word back in the same transaction.
+    ///
+    /// On a NOS bus this reaches an `SMBusDevice` as `write_word` followed by `read_word`.
+    pub fn smbus_process_call<A: Into<I2CAddress>>(
+        &self,
+        address: A,
+        command: u8,
+        value: u16,
+    ) -> Result<u16, I2CError> {
+        let address = address.into();
+        let tx = [command, value as u8, (value >> 8) as u8];
+        let data = self.transaction(address, &tx, 2 + self.pec as usize)?;
+        let mut covered = vec![write_address(address)];
+        covered.extend_from_slice(&tx);
+        covered.push(read_address(address));
+        self.check_pec(&covered, &data)?;
+        Ok(u16::from(data[0]) | u16::from(data[1]) << 8)
+    }
+
+    /// Writes `data`, followed by its PEC if PEC is on.
+    fn smbus_write(&self, address: I2CAddress, data: &[u8]) -> Result<(), I2CError> {
+        let mut message = data.to_vec();
+        if self.pec {
+            let mut covered = vec![write_address(address)];
+            covered.extend_from_slice(data);
+            message.push(crc8(&covered));
+        }
+        self.write(address, &message)
+    }
+
+    /// Writes `command`, then reads `len` bytes and checks their PEC if PEC is on.
+    fn smbus_read(
+        &self,
+        address: I2CAddress,
+        command: u8,
+        len: usize,
+    ) -> Result<Vec<u8>, I2CError> {
+        let mut data = self.transaction(address, &[command], len + self.pec as usize)?;
+        self.check_pec(
+            &[write_address(address), command, read_address(address)],
+            &data,
+        )?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// If PEC is on, checks that the last byte of `data` is the PEC of `covered` then `data`.
+    fn check_pec(&self, covered: &[u8], data: &[u8]) -> Result<(), I2CError> {
+        if !self.pec {
+            return Ok(());
+        }
+        let (received, data) = data.split_last().unwrap();
+        let mut covered = covered.to_vec();
+        covered.extend_from_slice(data);
+        let expected = crc8(&covered);
+        if expected == *received {
+            Ok(())
+        } else {
+            Err(I2CError::PECMismatch {
+                expected,
+                received: *received,
+            })
+        }
+    }
+}
+
+/// A simulated SMBus device. Each method handles one SMBus protocol, and the defaults ignore
+/// writes and answer reads with all ones, as a bus with nothing driving it would.
+///
+/// On a NOS bus, a read which follows a write can't be told apart from a read in the same
+/// transaction. So after any write, the next read is decoded as a read byte, read word or block
+/// read, using the first byte written as the command, and only reads with no write before them
+/// are a receive byte. Which commands are blocks has to come from the device, through
+/// `is_block`.
+pub trait SMBusDevice: Send {
+    /// Quick command, where `read` is the value of the read/write bit.
+    fn quick(&mut self, _read: bool) {}
+
+    /// Receive byte: a one-byte read without a command.
+    fn receive_byte(&mut self) -> u8 {
+        0xFF
+    }
+
+    /// Send byte: a one-byte write without a command.
+    fn send_byte(&mut self, _value: u8) {}
+
+    /// Read byte from a command.
+    fn read_byte(&mut self, _command: u8) -> u8 {
+        0xFF
+    }
+
+    /// Write byte to a command.
+    fn write_byte(&mut self, _command: u8, _value: u8) {}
+
+    /// Read word from a command.
+    fn read_word(&mut self, _command: u8) -> u16 {
+        0xFFFF
+    }
+
+    /// Write word to a command.
+    fn write_word(&mut self, _command: u8, _value: u16) {}
+
+    /// Whether a command is read and written as a block.
+    fn is_block(&mut self, _command: u8) -> bool {
+        false
+    }
+
+    /// Block read from a command. Only the first `SMBUS_BLOCK_MAX` bytes are sent.
+    fn read_block(&mut self, _command: u8) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Block write to a command.
+    fn write_block(&mut self, _command: u8, _data: &[u8]) {}
+}
+
+impl<'a> I2CSlave<'a> {
+    /// Constructs a new I2C slave which decodes the master's reads and writes as SMBus
+    /// transactions, and passes them to `device`. This uses one of the `MAX_HANDLER_SLAVES`
+    /// slots, like `I2CSlave::with_handler`.
+    ///
+    /// If `pec` is set, every read ends with a PEC, and a write with a wrong PEC never reaches
+    /// the device, and is not acknowledged. A write of a command alone is the start of a read.
+    /// Three bytes are either a write byte and its PEC, or the start of a process call, which
+    /// carries its PEC at the end of the read, so they are held until what follows settles which:
+    /// a read makes it a process call, and anything else, or `SMBUS_SETTLE_TIME` with nothing,
+    /// makes it a write byte. A write byte settled late cannot be refused to the master, so if
+    /// its PEC is wrong it is only dropped.
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::smbus`](../smbus/index.html#examples)
+    pub fn smbus<A, D>(
+        address: A,
+        connection: &'a str,
+        bus: &'a str,
+        pec: bool,
+        device: D,
+    ) -> Result<I2CSlave<'a>, I2CError>
+    where
+        A: Into<I2CAddress>,
+        D: SMBusDevice + 'static,
+    {
+        let address = address.into();
+        let decoder = Arc::new(Mutex::new(Decoder::new(address, pec, device)));
+
+        // Settles a held write once nothing has followed it for long enough. It stops when the
+        // slave's handler, and so `held`, is dropped.
+        let (held, holds) = mpsc::channel::<u64>();
+        let settler = decoder.clone();
+        thread::spawn(move || {
+            let mut waiting = None;
+            loop {
+                let next = match waiting {
+                    Some(_) => holds.recv_timeout(SMBUS_SETTLE_TIME),
+                    None => holds.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match next {
+                    Ok(hold) => waiting = Some(hold),
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Some(hold) = waiting.take() {
+                            settler.lock().unwrap().settle(hold);
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+
+        let held = Mutex::new(held);
+        I2CSlave::with_handler(address, connection, bus, move |dir, data| {
+            let mut decoder = decoder.lock().unwrap();
+            let handled = match dir {
+                I2CDirection::Write => decoder.write(data),
+                I2CDirection::Read => decoder.read(data),
+            };
+            if let Some(hold) = decoder.holding() {
+                let _ = held.lock().unwrap().send(hold);
+            }
+            handled
+        })
+    }
+}
+
+/// Decodes the reads and writes reaching an `I2CSlave::smbus` into calls on its device.
+struct Decoder<D> {
+    address: I2CAddress,
+    pec: bool,
+    device: D,
+    /// Bytes of the last write, which a following read uses as its command
+    last_write: Vec<u8>,
+    /// Numbers the writes held until it is known whether a read follows, while one is held
+    held: Option<u64>,
+    holds: u64,
+}
+
+impl<D: SMBusDevice> Decoder<D> {
+    fn new(address: I2CAddress, pec: bool, device: D) -> Decoder<D> {
+        Decoder {
+            address,
+            pec,
+            device,
+            last_write: Vec::new(),
+            held: None,
+            holds: 0,
+        }
+    }
+
+    /// The number of the write being held, if one is.
+    fn holding(&self) -> Option<u64> {
+        self.held
+    }
+
+    /// Whether `message` ends with the PEC of the write it ends.
+    fn pec_matches(&self, message: &[u8]) -> bool {
+        match message.split_last() {
+            Some((received, contents)) => {
+                let mut covered = vec![write_address(self.address)];
+                covered.extend_from_slice(contents);
+                crc8(&covered) == *received
+            }
+            None => false,
+        }
+    }
+
+    /// Nothing followed the held write numbered `hold`, if it is still held, so it was a write
+    /// byte.
+    fn settle(&mut self, hold: u64) {
+        if self.held != Some(hold) {
+            return;
+        }
+        self.held = None;
+        if self.pec_matches(&self.last_write) {
+            let (command, value) = (self.last_write[0], self.last_write[1]);
+            self.device.write_byte(command, value);
+            self.last_write.truncate(2);
+        } else {
+            self.last_write.clear();
+        }
+    }
+
+    /// The master wrote `data`. Returns how many bytes were acknowledged.
+    fn write(&mut self, data: &[u8]) -> usize {
+        if let Some(hold) = self.held {
+            self.settle(hold);
+        }
+        self.last_write.clear();
+        let mut message = data.to_vec();
+        if self.pec {
+            match message.len() {
+                // Quick commands never carry a PEC
+                0 => {}
+                // A command alone, for a read
+                1 => {
+                    self.last_write = message;
+                    return data.len();
+                }
+                3 if !self.device.is_block(message[0]) => {
+                    self.holds += 1;
+                    self.held = Some(self.holds);
+                    self.last_write = message;
+                    return data.len();
+                }
+                _ if self.pec_matches(&message) => {
+                    message.pop();
+                }
+                _ => return 0,
+            }
+        }
+        match message.len() {
+            0 => {
+                self.device.quick(false);
+                return 0;
+            }
+            1 => self.device.send_byte(message[0]),
+            _ if self.device.is_block(message[0]) => {
+                let length = (message[1] as usize).min(message.len() - 2);
+                self.device.write_block(message[0], &message[2..2 + length]);
+            }
+            2 => self.device.write_byte(message[0], message[1]),
+            3 => self.device.write_word(
+                message[0],
+                u16::from(message[1]) | u16::from(message[2]) << 8,
+            ),
+            _ => return 0,
+        }
+        self.last_write = message;
+        data.len()
+    }
+
+    /// The master reads into `data`. Returns how many bytes were read.
+    fn read(&mut self, data: &mut [u8]) -> usize {
+        if data.is_empty() {
+            if let Some(hold) = self.held {
+                self.settle(hold);
+            }
+            self.last_write.clear();
+            self.device.quick(true);
+            return 0;
+        }
+        // A read after a held write makes it a process call, which writes the word
+        if self.held.take().is_some() {
+            let message = &self.last_write;
+            self.device.write_word(
+                message[0],
+                u16::from(message[1]) | u16::from(message[2]) << 8,
+            );
+        }
+
+        let len = data.len() - self.pec as usize;
+        let mut covered = Vec::new();
+        let response = match self.last_write.first().cloned() {
+            Some(command) => {
+                covered.push(write_address(self.address));
+                covered.extend_from_slice(&self.last_write);
+                if self.device.is_block(command) {
+                    let mut block = self.device.read_block(command);
+                    block.truncate(SMBUS_BLOCK_MAX);
+                    block.insert(0, block.len() as u8);
+                    block
+                } else if len == 1 {
+                    vec![self.device.read_byte(command)]
+                } else {
+                    let word = self.device.read_word(command);
+                    vec![word as u8, (word >> 8) as u8]
+                }
+            }
+            None => vec![self.device.receive_byte()],
+        };
+        self.last_write.clear();
+        covered.push(read_address(self.address));
+        covered.extend_from_slice(&response);
+
+        let length = response.len().min(len);
+        data[..length].copy_from_slice(&response[..length]);
+        if self.pec {
+            data[length] = crc8(&covered);
+        }
+        data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: I2CAddress = I2CAddress::SevenBit(0x20);
+
+    /// Records every call, and answers reads with fixed values. Command 0x40 is a block.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+    }
+
+    impl SMBusDevice for Recorder {
+        fn send_byte(&mut self, value: u8) {
+            self.calls.push(format!("send_byte {:#x}", value));
+        }
+
+        fn read_byte(&mut self, command: u8) -> u8 {
+            self.calls.push(format!("read_byte {:#x}", command));
+            0x5A
+        }
+
+        fn write_byte(&mut self, command: u8, value: u8) {
+            self.calls
+                .push(format!("write_byte {:#x} {:#x}", command, value));
+        }
+
+        fn read_word(&mut self, command: u8) -> u16 {
+            self.calls.push(format!("read_word {:#x}", command));
+            0xBEEF
+        }
+
+        fn write_word(&mut self, command: u8, value: u16) {
+            self.calls
+                .push(format!("write_word {:#x} {:#x}", command, value));
+        }
+
+        fn is_block(&mut self, command: u8) -> bool {
+            command == 0x40
+        }
+
+        fn write_block(&mut self, command: u8, data: &[u8]) {
+            self.calls
+                .push(format!("write_block {:#x} {:?}", command, data));
+        }
+    }
+
+    fn decoder(pec: bool) -> Decoder<Recorder> {
+        Decoder::new(ADDRESS, pec, Recorder::default())
+    }
+
+    /// `message` with its PEC on the end, as the master writes it.
+    fn with_pec(message: &[u8]) -> Vec<u8> {
+        let mut covered = vec![write_address(ADDRESS)];
+        covered.extend_from_slice(message);
+        let mut message = message.to_vec();
+        message.push(crc8(&covered));
+        message
+    }
+
+    #[test]
+    fn write_byte_with_pec_settles_on_the_next_write() {
+        let mut decoder = decoder(true);
+        assert_eq!(decoder.write(&with_pec(&[0x01, 0x22])), 3);
+        assert!(decoder.device.calls.is_empty());
+        assert_eq!(decoder.write(&with_pec(&[0x07])), 2);
+        assert_eq!(
+            decoder.device.calls,
+            ["write_byte 0x1 0x22", "send_byte 0x7"]
+        );
+    }
+
+    #[test]
+    fn write_byte_with_pec_settles_after_a_while() {
+        let mut decoder = decoder(true);
+        decoder.write(&with_pec(&[0x01, 0x22]));
+        let hold = decoder.holding().unwrap();
+        decoder.settle(hold);
+        assert_eq!(decoder.device.calls, ["write_byte 0x1 0x22"]);
+        assert_eq!(decoder.holding(), None);
+    }
+
+    #[test]
+    fn bad_pec_is_never_applied() {
+        let mut decoder = decoder(true);
+        // A corrupt write byte is held, then dropped
+        let mut message = with_pec(&[0x01, 0x22]);
+        message[2] ^= 0xFF;
+        decoder.write(&message);
+        let hold = decoder.holding().unwrap();
+        decoder.settle(hold);
+        // A corrupt write word or send byte is refused outright
+        let mut message = with_pec(&[0x02, 0x34, 0x12]);
+        message[3] ^= 0xFF;
+        assert_eq!(decoder.write(&message), 0);
+        let mut message = with_pec(&[0x07]);
+        message[1] ^= 0xFF;
+        assert_eq!(decoder.write(&message), 0);
+        assert!(decoder.device.calls.is_empty());
+    }
+
+    #[test]
+    fn process_call_whose_last_byte_looks_like_a_pec() {
+        let mut decoder = decoder(true);
+        // The high byte of the word happens to be the PEC of a write byte
+        let mut covered = vec![write_address(ADDRESS), 0x03, 0x34];
+        let high = crc8(&covered);
+        decoder.write(&[0x03, 0x34, high]);
+        let mut rx = [0u8; 3];
+        assert_eq!(decoder.read(&mut rx), 3);
+        assert_eq!(
+            decoder.device.calls,
+            [
+                format!("write_word 0x3 {:#x}", u16::from(high) << 8 | 0x34),
+                String::from("read_word 0x3")
+            ]
+        );
+        assert_eq!(&rx[..2], &[0xEF, 0xBE]);
+        covered.push(high);
+        covered.push(read_address(ADDRESS));
+        covered.extend_from_slice(&rx[..2]);
+        assert_eq!(rx[2], crc8(&covered));
+        assert_eq!(decoder.holding(), None);
+    }
+
+    #[test]
+    fn block_write_with_and_without_pec() {
+        let mut decoder = decoder(true);
+        assert_eq!(decoder.write(&with_pec(&[0x40, 2, 0xAA, 0xBB])), 5);
+        let mut message = with_pec(&[0x40, 1, 0xCC]);
+        message[3] ^= 1;
+        assert_eq!(decoder.write(&message), 0);
+        let mut plain = self::decoder(false);
+        assert_eq!(plain.write(&[0x40, 1, 0xCC]), 3);
+        assert_eq!(decoder.device.calls, ["write_block 0x40 [170, 187]"]);
+        assert_eq!(plain.device.calls, ["write_block 0x40 [204]"]);
+    }
+
+    #[test]
+    fn read_byte_uses_the_command_written_before_it() {
+        let mut decoder = decoder(true);
+        decoder.write(&[0x05]);
+        let mut rx = [0u8; 2];
+        decoder.read(&mut rx);
+        assert_eq!(decoder.device.calls, ["read_byte 0x5"]);
+        assert_eq!(rx[0], 0x5A);
+        assert_eq!(
+            rx[1],
+            crc8(&[write_address(ADDRESS), 0x05, read_address(ADDRESS), 0x5A])
+        );
+    }
+}
@@ -39,6 +39,32 @@ use std::ffi;
 use std::ffi::CString;
 use std::fmt;
 
+/// The most `SPISlave`s created with `SPISlave::with_handler` which can exist at once.
+pub const MAX_HANDLER_SLAVES: usize = 16;
+
+slave_handlers!(
+    spi::SPIDirection,
+    MAX_HANDLER_SLAVES,
+    [
+        0 => slave_callback_0,
+        1 => slave_callback_1,
+        2 => slave_callback_2,
+        3 => slave_callback_3,
+        4 => slave_callback_4,
+        5 => slave_callback_5,
+        6 => slave_callback_6,
+        7 => slave_callback_7,
+        8 => slave_callback_8,
+        9 => slave_callback_9,
+        10 => slave_callback_10,
+        11 => slave_callback_11,
+        12 => slave_callback_12,
+        13 => slave_callback_13,
+        14 => slave_callback_14,
+        15 => slave_callback_15
+    ]
+);
+
 /// This enum represents any type of error that can occur when interacting with SPI.
 #[derive(Debug, Clone, PartialEq)]
 pub enum SPIError {
@@ -56,6 +82,8 @@ pub enum SPIError {
     /// This error is raised when a read or write is attempted when either no chip is selected,
     /// or the selected chip is not found on this bus
     ChipSelectionError,
+    /// Every slot for closure handlers is already in use. See `MAX_HANDLER_SLAVES`.
+    TooManyHandlers,
 }
 
 impl From<ffi::NulError> for SPIError {
@@ -76,6 +104,11 @@ impl fmt::Display for SPIError {
             } => write!(f, "Null character at index {}: {}", position, description),
             SPIError::SPICreationError => write!(f, "Error while creating SPI node"),
             SPIError::ChipSelectionError => write!(f, "No SPI chip selected"),
+            SPIError::TooManyHandlers => write!(
+                f,
+                "No more than {} SPI slaves can have closure handlers",
+                MAX_HANDLER_SLAVES
+            ),
         }
     }
 }
@@ -189,6 +222,7 @@ pub struct SPISlave<'a> {
     pub bus: &'a str,
     /// Chip select number of this slave
     pub cs: u8,
+    handler_slot: Option<usize>,
 }
 
 impl<'a> SPISlave<'a> {
@@ -229,6 +263,83 @@ impl<'a> SPISlave<'a> {
                 connection,
                 bus,
                 cs,
+                handler_slot: None,
+            })
+        }
+    }
+
+    /// Construct a new SPI slave which handles the master's reads and writes with a closure.
+    /// Unlike `SPISlave::new`, the closure can capture its environment. At most
+    /// `MAX_HANDLER_SLAVES` slaves created this way can exist at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `cs`: Chip select number for this slave. Must be unique on a bus
+    /// * `connection`: NOSEngine connection string
+    /// * `bus`: Name of the bus to connect to
+    /// * `handler`: Closure that runs every time the master reads from or writes to this device.
+    ///     It receives the direction and the buffer, which holds the data being written, or
+    ///     should be filled with the data being read. It returns the number of bytes read or
+    ///     written.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::spi::*;
+    /// # use nosengine_rust::ffi::spi::SPIDirection;
+    /// # use std::sync::{Arc, Mutex};
+    /// let master = SPIMaster::new("tcp://localhost:12001", "spi21").unwrap();
+    ///
+    /// let register = Arc::new(Mutex::new(0u8));
+    /// let slave_register = register.clone();
+    /// let slave = SPISlave::with_handler(2, "tcp://localhost:12001", "spi21", move |dir, data| {
+    ///     let mut register = slave_register.lock().unwrap();
+    ///     match dir {
+    ///         SPIDirection::Write => *register = data[data.len() - 1],
+    ///         SPIDirection::Read => for byte in data.iter_mut() {
+    ///             *byte = *register;
+    ///         },
+    ///     }
+    ///     data.len()
+    /// }).unwrap();
+    ///
+    /// master.chip_select(2);
+    /// master.write(&[0x42u8]).unwrap();
+    /// assert_eq!(master.read(2), Ok(vec![0x42u8, 0x42]));
+    /// assert_eq!(*register.lock().unwrap(), 0x42);
+    /// ```
+    pub fn with_handler<F>(
+        cs: u8,
+        connection: &'a str,
+        bus: &'a str,
+        handler: F,
+    ) -> Result<SPISlave<'a>, SPIError>
+    where
+        F: FnMut(spi::SPIDirection, &mut [u8]) -> usize,
+        F: Send + 'static,
+    {
+        let c_connection = CString::new(connection)?;
+        let c_bus = CString::new(bus)?;
+
+        let slot = claim_handler_slot(Box::new(handler)).ok_or(SPIError::TooManyHandlers)?;
+        let spi_ptr = spi::spi_init_slave(
+            cs,
+            c_connection.as_ptr(),
+            c_bus.as_ptr(),
+            SLAVE_CALLBACKS[slot],
+        );
+
+        if spi_ptr.is_null() {
+            release_handler_slot(slot);
+            Err(SPIError::SPICreationError)
+        } else {
+            Ok(SPISlave {
+                spi_ptr,
+                connection,
+                bus,
+                cs,
+                handler_slot: Some(slot),
             })
         }
     }
@@ -237,5 +348,10 @@ impl<'a> SPISlave<'a> {
 impl<'a> Drop for SPISlave<'a> {
     fn drop(&mut self) {
         spi::spi_close(&mut self.spi_ptr as *mut *mut spi::SPIHandle);
+        if let Some(slot) = self.handler_slot.take() {
+            release_handler_slot(slot);
+        }
     }
 }
+
+unsafe impl<'a> Send for SPISlave<'a> {}
@@ -0,0 +1,296 @@
+//! Provides access to the NOSEngine simulation clock.
+//!
+//! Every component of a NOS3 simulation follows a shared clock, which is published on a time bus
+//! by one `TimeSender`. A `TimeClient` reads the current simulation time and can run a callback
+//! on every tick. In tests, a `TimeSender` can be used to drive the clock directly.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate nosengine_rust;
+//! # use nosengine_rust::client::time::*;
+//! # use std::sync::mpsc;
+//! # use std::time::Duration;
+//! let sender = TimeSender::new("time_source", "tcp://localhost:12001", "time19").unwrap();
+//! let mut client = TimeClient::new("time_client", "tcp://localhost:12001", "time19").unwrap();
+//!
+//! let (tx, rx) = mpsc::channel();
+//! client.set_tick_callback(move |time: SimTime| {
+//!     tx.send(time).unwrap();
+//! });
+//!
+//! sender.send(100).unwrap();
+//! assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(100));
+//! assert_eq!(client.time(), 100);
+//! ```
+
+use super::ffi::time;
+use libc;
+use std::error::Error;
+use std::ffi;
+use std::ffi::CString;
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// A simulation time, in ticks.
+pub type SimTime = i64;
+
+/// This enum represents any type of error that can occur when interacting with simulation time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeError {
+    /// An error occurred when converting a Rust string to a C string.
+    /// Specifically, the Rust string contained a null character, which cannot be represented
+    /// in C strings.
+    StringError {
+        /// Description from the underlying std::ffi::NulError
+        description: String,
+        /// Index in the original string of the problematic null character
+        position: usize,
+    },
+    /// There was an error when creating the time sender or client.
+    TimeCreationError,
+    /// The time could not be sent.
+    SendError,
+}
+
+impl From<ffi::NulError> for TimeError {
+    fn from(err: ffi::NulError) -> Self {
+        TimeError::StringError {
+            description: String::from(err.description()),
+            position: err.nul_position(),
+        }
+    }
+}
+
+impl fmt::Display for TimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeError::StringError {
+                description,
+                position,
+            } => write!(f, "Null character at index {}: {}", position, description),
+            TimeError::TimeCreationError => write!(f, "Error while creating time node"),
+            TimeError::SendError => write!(f, "Error while sending time"),
+        }
+    }
+}
+
+/// This struct publishes the simulation time on a time bus.
+pub struct TimeSender {
+    sender_ptr: *mut time::TimeSenderHandle,
+    /// Name of this time sender. Must be unique on a bus
+    pub name: String,
+    /// The connection string to the server
+    pub connection: String,
+    /// Name of the time bus
+    pub bus: String,
+}
+
+impl TimeSender {
+    /// Creates a new time sender. There can be only one per bus: If there is already a time sender
+    /// on the given bus, this function returns `Err`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: Name of this time sender. Must be unique on a bus.
+    /// * `connection`: Connection string to server. Usually of the form `tcp://<domain>:<port>`.
+    /// * `bus`: Name of the time bus
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::time::*;
+    /// let sender = TimeSender::new("sender1", "tcp://localhost:12001", "time20");
+    /// assert!(sender.is_ok());
+    /// let sender2 = TimeSender::new("sender2", "tcp://localhost:12001", "time20");
+    /// assert!(sender2.is_err());
+    /// ```
+    pub fn new(name: &str, connection: &str, bus: &str) -> Result<TimeSender, TimeError> {
+        let c_name = CString::new(name)?;
+        let c_connection = CString::new(connection)?;
+        let c_bus = CString::new(bus)?;
+
+        let sender_ptr =
+            time::time_sender_init(c_name.as_ptr(), c_connection.as_ptr(), c_bus.as_ptr());
+
+        if sender_ptr.is_null() {
+            Err(TimeError::TimeCreationError)
+        } else {
+            Ok(TimeSender {
+                sender_ptr,
+                name: String::from(name),
+                connection: String::from(connection),
+                bus: String::from(bus),
+            })
+        }
+    }
+
+    /// Publishes a new simulation time to every time client on the bus.
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::time`](../time/index.html#examples)
+    pub fn send(&self, time: SimTime) -> Result<(), TimeError> {
+        match time::time_sender_send(self.sender_ptr, time) {
+            time::TimeStatus::Success => Ok(()),
+            time::TimeStatus::Failure => Err(TimeError::SendError),
+        }
+    }
+}
+
+impl Drop for TimeSender {
+    fn drop(&mut self) {
+        time::time_sender_close(&mut self.sender_ptr as *mut *mut time::TimeSenderHandle);
+    }
+}
+
+unsafe impl Send for TimeSender {}
+
+unsafe impl Sync for TimeSender {}
+
+/// A closure set with `TimeClient::set_tick_callback`.
+type TickCallback = Box<FnMut(SimTime) + Send>;
+
+/// This struct follows the simulation time published on a time bus.
+pub struct TimeClient {
+    client_ptr: *mut time::TimeClientHandle,
+    /// NOSEngine is handed this slot rather than the closure in it, and the slot is locked while
+    /// the closure runs, so a new closure can replace the old one without freeing it mid-call.
+    callback: Box<Mutex<Option<TickCallback>>>,
+    /// Name of this time client. Must be unique on a bus
+    pub name: String,
+    /// The connection string to the server
+    pub connection: String,
+    /// Name of the time bus
+    pub bus: String,
+}
+
+impl TimeClient {
+    /// Creates a new time client.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: Name of this time client. Must be unique on a bus.
+    /// * `connection`: Connection string to server. Usually of the form `tcp://<domain>:<port>`.
+    /// * `bus`: Name of the time bus
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::time::*;
+    /// let client = TimeClient::new("client1", "tcp://localhost:12001", "time21");
+    /// assert!(client.is_ok());
+    /// ```
+    pub fn new(name: &str, connection: &str, bus: &str) -> Result<TimeClient, TimeError> {
+        let c_name = CString::new(name)?;
+        let c_connection = CString::new(connection)?;
+        let c_bus = CString::new(bus)?;
+
+        let client_ptr =
+            time::time_client_init(c_name.as_ptr(), c_connection.as_ptr(), c_bus.as_ptr());
+
+        if client_ptr.is_null() {
+            Err(TimeError::TimeCreationError)
+        } else {
+            Ok(TimeClient {
+                client_ptr,
+                callback: Box::new(Mutex::new(None)),
+                name: String::from(name),
+                connection: String::from(connection),
+                bus: String::from(bus),
+            })
+        }
+    }
+
+    /// Returns the most recent simulation time received by this client.
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::time`](../time/index.html#examples)
+    pub fn time(&self) -> SimTime {
+        time::time_client_get_time(self.client_ptr)
+    }
+
+    /// Blocks until the simulation time reaches `time`, checking every `poll` of wall time.
+    /// Returns the simulation time at which it woke up, which may be later than `time`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::time::*;
+    /// # use std::thread;
+    /// # use std::time::Duration;
+    /// let client = TimeClient::new("client2", "tcp://localhost:12001", "time22").unwrap();
+    /// let handle = thread::spawn(|| {
+    ///     let sender = TimeSender::new("sender3", "tcp://localhost:12001", "time22").unwrap();
+    ///     for tick in 1..=10 {
+    ///         sender.send(tick).unwrap();
+    ///         thread::sleep(Duration::from_millis(10));
+    ///     }
+    /// });
+    /// assert!(client.wait_until(5, Duration::from_millis(1)) >= 5);
+    /// handle.join().unwrap();
+    /// ```
+    pub fn wait_until(&self, time: SimTime, poll: Duration) -> SimTime {
+        loop {
+            let now = self.time();
+            if now >= time {
+                return now;
+            }
+            thread::sleep(poll);
+        }
+    }
+
+    /// Set a callback which will run every time this client receives a new simulation time. It
+    /// runs on NOSEngine's callback thread. Setting a new callback replaces the previous one,
+    /// waiting for it to finish if it is running.
+    ///
+    /// # Arguments
+    ///
+    /// * `func`: A callback with the following parameters:
+    ///     * `SimTime`: The new simulation time
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::time`](../time/index.html#examples)
+    pub fn set_tick_callback<F>(&mut self, func: F)
+    where
+        F: FnMut(SimTime) -> (),
+        F: Send + 'static,
+    {
+        extern "C" fn c_callback(time: i64, user: *mut libc::c_void) {
+            let slot = unsafe { &*(user as *const Mutex<Option<TickCallback>>) };
+            if let Ok(mut func) = slot.lock() {
+                if let Some(ref mut func) = *func {
+                    func(time);
+                }
+            }
+        }
+
+        // Taking the lock waits out a running callback, and the old one is dropped after the lock
+        // is released, when NOSEngine can no longer reach it
+        let old = self.callback.lock().unwrap().replace(Box::new(func));
+        if old.is_none() {
+            let slot = &*self.callback as *const Mutex<Option<TickCallback>>;
+            time::time_client_set_tick_callback(
+                self.client_ptr,
+                c_callback,
+                slot as *mut libc::c_void,
+            );
+        }
+    }
+}
+
+impl Drop for TimeClient {
+    fn drop(&mut self) {
+        // Closed before the callback slot is freed, so NOSEngine stops calling into it first
+        time::time_client_close(&mut self.client_ptr as *mut *mut time::TimeClientHandle);
+    }
+}
+
+unsafe impl Send for TimeClient {}
@@ -0,0 +1,303 @@
+//! Typed messages on top of `DataNode`. A `TypedNode` serializes values with a `Codec` before
+//! sending them, and deserializes the values it receives, so that simulator messages can be
+//! passed around as Rust types instead of byte slices.
+//!
+//! The following codecs are available:
+//!
+//! * `Bincode`: A compact binary encoding. This is the default.
+//! * `Cbor`: Concise Binary Object Representation (RFC 7049)
+//! * `Json`: JSON text, which is convenient for debugging
+//! * `BigEndian`: Fixed-width, big-endian fields in declaration order with no padding. This
+//!     matches a packed C struct in network byte order, so it can be used to talk to existing
+//!     C simulators.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate nosengine_rust;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use nosengine_rust::client::*;
+//! # use nosengine_rust::client::typed::*;
+//! #[derive(Serialize, Deserialize, Debug, PartialEq)]
+//! struct Telemetry {
+//!     voltage: f32,
+//!     current: f32,
+//! }
+//!
+//! # fn main() {
+//! let bus = Bus::new("typedbus", "tcp://localhost:12001").unwrap();
+//! let node1: TypedNode<Telemetry> = TypedNode::new(&bus, "typed1").unwrap();
+//! let node2: TypedNode<Telemetry> = TypedNode::new(&bus, "typed2").unwrap();
+//!
+//! node2.on_message(|msg| {
+//!     let request = msg.unwrap();
+//!     Some(Telemetry {
+//!         voltage: request.voltage * 2.0,
+//!         current: request.current * 2.0,
+//!     })
+//! });
+//!
+//! let response = node1
+//!     .request("typed2", &Telemetry { voltage: 1.5, current: 0.25 })
+//!     .unwrap();
+//! assert_eq!(response, Telemetry { voltage: 3.0, current: 0.5 });
+//! # }
+//! ```
+
+use super::{Bus, DataNode, NosError};
+use bincode;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor;
+use serde_json;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// This enum represents any type of error that can occur when sending or receiving typed messages.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedError {
+    /// An error occurred in the underlying `DataNode`.
+    NosEngineError(NosError),
+    /// A value could not be serialized by the codec.
+    EncodeError {
+        /// Description of the error, from the codec
+        description: String,
+    },
+    /// A received message could not be deserialized into the expected type.
+    DecodeError {
+        /// Description of the error, from the codec
+        description: String,
+    },
+}
+
+impl From<NosError> for TypedError {
+    fn from(err: NosError) -> Self {
+        TypedError::NosEngineError(err)
+    }
+}
+
+impl fmt::Display for TypedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypedError::NosEngineError(err) => write!(f, "{}", err),
+            TypedError::EncodeError { description } => {
+                write!(f, "Error while encoding message: {}", description)
+            }
+            TypedError::DecodeError { description } => {
+                write!(f, "Error while decoding message: {}", description)
+            }
+        }
+    }
+}
+
+impl Error for TypedError {
+    fn description(&self) -> &str {
+        match self {
+            TypedError::NosEngineError(err) => err.description(),
+            TypedError::EncodeError { description } => &description,
+            TypedError::DecodeError { description } => &description,
+        }
+    }
+}
+
+/// A `Codec` converts values to and from the bytes carried by a `Message`.
+pub trait Codec {
+    /// Serialize `value` into bytes.
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, TypedError>;
+
+    /// Deserialize a value from `data`.
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, TypedError>;
+}
+
+/// Compact binary codec. Integers are variable-length encoded, so messages stay small.
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, TypedError> {
+        bincode::DefaultOptions::new()
+            .serialize(value)
+            .map_err(|err| TypedError::EncodeError {
+                description: err.to_string(),
+            })
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, TypedError> {
+        bincode::DefaultOptions::new()
+            .deserialize(data)
+            .map_err(|err| TypedError::DecodeError {
+                description: err.to_string(),
+            })
+    }
+}
+
+/// Fixed-width, big-endian codec. Each field is written in declaration order with no padding or
+/// tags, the way a packed C struct would be sent in network byte order. Fixed-size arrays are
+/// written without a length, but `Vec`s and `String`s are prefixed by a `u64` length, so C-compatible
+/// types should only use arrays.
+pub struct BigEndian;
+
+impl Codec for BigEndian {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, TypedError> {
+        bincode::DefaultOptions::new()
+            .with_big_endian()
+            .with_fixint_encoding()
+            .serialize(value)
+            .map_err(|err| TypedError::EncodeError {
+                description: err.to_string(),
+            })
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, TypedError> {
+        bincode::DefaultOptions::new()
+            .with_big_endian()
+            .with_fixint_encoding()
+            .deserialize(data)
+            .map_err(|err| TypedError::DecodeError {
+                description: err.to_string(),
+            })
+    }
+}
+
+/// CBOR codec. Messages are self-describing, so fields can be added without breaking older
+/// receivers.
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, TypedError> {
+        serde_cbor::to_vec(value).map_err(|err| TypedError::EncodeError {
+            description: err.to_string(),
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, TypedError> {
+        serde_cbor::from_slice(data).map_err(|err| TypedError::DecodeError {
+            description: err.to_string(),
+        })
+    }
+}
+
+/// JSON codec. Larger and slower than the binary codecs, but messages can be read by a human.
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, TypedError> {
+        serde_json::to_vec(value).map_err(|err| TypedError::EncodeError {
+            description: err.to_string(),
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, TypedError> {
+        serde_json::from_slice(data).map_err(|err| TypedError::DecodeError {
+            description: err.to_string(),
+        })
+    }
+}
+
+/// A `DataNode` which sends and receives values of type `T`, encoded with the codec `C`.
+pub struct TypedNode<T, C = Bincode> {
+    node: DataNode,
+    phantom: PhantomData<(fn(T) -> T, C)>,
+}
+
+impl<T, C> TypedNode<T, C>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Creates a typed data node on the supplied bus. The same naming rules as `DataNode::new`
+    /// apply.
+    ///
+    /// # Arguments
+    /// * `bus`: Bus on which to create the node
+    /// * `name`: Name of the node to be created. Must be unique on a bus.
+    ///
+    /// # Examples
+    /// ```
+    /// # extern crate nosengine_rust;
+    /// # use nosengine_rust::client::*;
+    /// # use nosengine_rust::client::typed::*;
+    /// let bus = Bus::new("typedbus", "tcp://localhost:12001").unwrap();
+    /// let node: TypedNode<(u16, u32), BigEndian> = TypedNode::new(&bus, "typed3").unwrap();
+    /// ```
+    pub fn new(bus: &Arc<Bus>, name: &str) -> Result<TypedNode<T, C>, NosError> {
+        Ok(TypedNode::from_node(DataNode::new(bus, name)?))
+    }
+
+    /// Wraps an existing data node.
+    pub fn from_node(node: DataNode) -> TypedNode<T, C> {
+        TypedNode {
+            node,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the underlying data node, which can still be used to send raw bytes.
+    pub fn node(&self) -> &DataNode {
+        &self.node
+    }
+
+    /// Encodes `value` and sends it to the specified node.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination`: The (case-sensitive) name of the recipient node
+    /// * `value`: The value to be sent to the recipient
+    pub fn send(&self, destination: &str, value: &T) -> Result<(), TypedError> {
+        let data = C::encode(value)?;
+        Ok(self.node.send_message(destination, &data)?)
+    }
+
+    /// Encodes `value`, sends it to the specified node, and blocks until a reply is received.
+    /// The reply is decoded as a `T`. The recipient must reply, or else this function will block
+    /// indefinitely.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination`: The (case-sensitive) name of the recipient node
+    /// * `value`: The value to be sent to the recipient
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::typed`](../typed/index.html#examples)
+    pub fn request(&self, destination: &str, value: &T) -> Result<T, TypedError> {
+        let data = C::encode(value)?;
+        let response = self.node.send_request_message(destination, &data)?;
+        C::decode(response.get_contents())
+    }
+
+    /// Blocks until a message is received, then decodes it.
+    pub fn receive(&self) -> Result<T, TypedError> {
+        let message = self.node.receive_message()?;
+        C::decode(message.get_contents())
+    }
+
+    /// Sets a closure which will be called with each decoded message this node receives. Messages
+    /// which cannot be decoded are passed to the closure as `TypedError::DecodeError`. If the
+    /// closure returns `Some`, the value is encoded and sent as the reply to the message. If the
+    /// reply cannot be encoded, the error is printed to stderr and an empty reply is sent instead,
+    /// so that a requester gets a `TypedError::DecodeError` rather than waiting forever.
+    ///
+    /// # Examples
+    ///
+    /// See [`nosengine-rust::client::typed`](../typed/index.html#examples)
+    pub fn on_message<F>(&self, mut func: F)
+    where
+        F: FnMut(Result<T, TypedError>) -> Option<T>,
+        F: Send + 'static,
+        T: 'static,
+        C: 'static,
+    {
+        self.node.set_message_handler(move |data: &[u8]| {
+            func(C::decode(data)).map(|reply| {
+                C::encode(&reply).unwrap_or_else(|err| {
+                    eprintln!("TypedNode: could not encode reply: {}", err);
+                    Vec::new()
+                })
+            })
+        });
+    }
+}
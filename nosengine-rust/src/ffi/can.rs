@@ -0,0 +1,189 @@
+//! This module contains the wrappers around the C functions associated with CAN.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate nosengine_rust;
+//! # use nosengine_rust::ffi::can::*;
+//! # use std::ffi::CString;
+//! let conn = CString::new("tcp://localhost:12001").unwrap();
+//! let bus = CString::new("can").unwrap();
+//! let name1 = CString::new("can1").unwrap();
+//! let name2 = CString::new("can2").unwrap();
+//!
+//! let mut can1 = can_open(name1.as_ptr(), conn.as_ptr(), bus.as_ptr());
+//! let mut can2 = can_open(name2.as_ptr(), conn.as_ptr(), bus.as_ptr());
+//!
+//! let frame = CANFrame {
+//!     id: 0x123,
+//!     extended: 0,
+//!     rtr: 0,
+//!     dlc: 2,
+//!     data: [1, 2, 0, 0, 0, 0, 0, 0],
+//! };
+//! assert_eq!(can_write(can1, &frame as *const CANFrame), CANStatus::Success);
+//!
+//! let mut received = CANFrame::default();
+//! assert_eq!(can_read(can2, &mut received as *mut CANFrame), CANStatus::Success);
+//! assert_eq!(received, frame);
+//!
+//! can_close(&mut can1 as *mut *mut CANHandle);
+//! can_close(&mut can2 as *mut *mut CANHandle);
+//! ```
+
+use libc::{c_char, c_void};
+
+/// This enum represents a pointer to an opaque C struct.
+pub enum CANHandle {}
+
+/// One CAN frame, laid out as NOSEngine expects it.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[repr(C)]
+pub struct CANFrame {
+    /// Identifier of the frame. 11 bits for standard frames, or 29 bits for extended frames.
+    pub id: u32,
+    /// Nonzero if `id` is a 29-bit extended identifier
+    pub extended: u8,
+    /// Nonzero if this is a remote transmission request
+    pub rtr: u8,
+    /// Number of valid bytes in `data`, from 0 to 8
+    pub dlc: u8,
+    /// Payload of the frame. Only the first `dlc` bytes are meaningful.
+    pub data: [u8; 8],
+}
+
+/// This function creates a new CAN node on the given bus. Every frame written by a node is
+/// received by all of the other nodes on the bus.
+///
+/// # Arguments
+///
+/// * `name`: Name of this CAN node. Must be unique on a bus
+/// * `connection`: Connection string to the NOSEngine server
+/// * `bus`: Name of the CAN bus
+///
+/// # Safety
+///
+/// After a CAN node is created, it must be cleaned up using `can_close`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate nosengine_rust;
+/// # use nosengine_rust::ffi::can::*;
+/// # use std::ffi::CString;
+/// let conn = CString::new("tcp://localhost:12001").unwrap();
+/// let bus = CString::new("can").unwrap();
+/// let name = CString::new("can3").unwrap();
+///
+/// let mut can = can_open(name.as_ptr(), conn.as_ptr(), bus.as_ptr());
+/// assert!(!can.is_null());
+/// # can_close(&mut can as *mut *mut CANHandle);
+/// ```
+pub fn can_open(
+    name: *const c_char,
+    connection: *const c_char,
+    bus: *const c_char,
+) -> *mut CANHandle {
+    unsafe { NE_can_open(name, connection, bus) }
+}
+
+/// This function closes a CAN node and cleans up all associated memory.
+///
+/// # Arguments
+///
+/// * `can`: Pointer to a CAN handle, which is made null by this function
+///
+/// # Examples
+///
+/// ```
+/// # extern crate nosengine_rust;
+/// # use nosengine_rust::ffi::can::*;
+/// # use std::ffi::CString;
+/// let conn = CString::new("tcp://localhost:12001").unwrap();
+/// let bus = CString::new("can").unwrap();
+/// let name = CString::new("can4").unwrap();
+///
+/// let mut can = can_open(name.as_ptr(), conn.as_ptr(), bus.as_ptr());
+/// can_close(&mut can as *mut *mut CANHandle);
+/// assert!(can.is_null());
+/// ```
+pub fn can_close(can: *mut *mut CANHandle) {
+    unsafe { NE_can_close(can) }
+}
+
+/// This function sends one frame to every other node on the bus.
+///
+/// # Arguments
+///
+/// * `can`: Handle to the CAN node
+/// * `frame`: The frame to send
+///
+/// # Examples
+///
+/// See [`nosengine-rust::ffi::can`](../can/index.html#examples)
+pub fn can_write(can: *mut CANHandle, frame: *const CANFrame) -> CANStatus {
+    unsafe { NE_can_write(can, frame) }
+}
+
+/// This function takes the oldest frame received by this node. If no frames are waiting,
+/// `CANStatus::Failure` is returned and `frame` is left untouched.
+///
+/// # Arguments
+///
+/// * `can`: Handle to the CAN node
+/// * `frame`: Pointer to a frame, where the result, if any, will be stored
+///
+/// # Examples
+///
+/// See [`nosengine-rust::ffi::can`](../can/index.html#examples)
+pub fn can_read(can: *mut CANHandle, frame: *mut CANFrame) -> CANStatus {
+    unsafe { NE_can_read(can, frame) }
+}
+
+/// This function returns the number of frames waiting to be read.
+pub fn can_available(can: *mut CANHandle) -> usize {
+    unsafe { NE_can_available(can) }
+}
+
+/// This function sets a callback which will execute whenever this node receives a frame.
+/// Frames which are delivered to the callback are not queued for `can_read`.
+///
+/// # Arguments
+///
+/// * `can`: Handle to the CAN node
+/// * `callback`: Callback which has the following parameters:
+///     * `frame`: The frame that was received. Only valid for the duration of the callback.
+///     * `user`: User-specified data that is passed every time this callback runs
+/// * `user`: User-specified data that will be passed to the callback every time it runs
+pub fn can_set_read_callback(
+    can: *mut CANHandle,
+    callback: extern "C" fn(frame: *const CANFrame, user: *mut c_void),
+    user: *mut c_void,
+) {
+    unsafe { NE_can_set_read_callback(can, callback, user) }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+#[repr(C)]
+#[allow(missing_docs)]
+pub enum CANStatus {
+    Success,
+    Failure,
+}
+
+extern "C" {
+    fn NE_can_open(
+        name: *const c_char,
+        connection: *const c_char,
+        bus: *const c_char,
+    ) -> *mut CANHandle;
+    fn NE_can_close(can: *mut *mut CANHandle);
+    fn NE_can_write(can: *mut CANHandle, frame: *const CANFrame) -> CANStatus;
+    fn NE_can_read(can: *mut CANHandle, frame: *mut CANFrame) -> CANStatus;
+    fn NE_can_available(can: *mut CANHandle) -> usize;
+    fn NE_can_set_read_callback(
+        can: *mut CANHandle,
+        callback: extern "C" fn(*const CANFrame, *mut c_void),
+        user: *mut c_void,
+    );
+}
@@ -109,8 +109,10 @@
 //! destroy_bus(&mut bus as *mut *mut BusHandle);
 //! ```
 
+pub mod can;
 pub mod i2c;
 pub mod spi;
+pub mod time;
 pub mod uart;
 
 use libc::c_char;
@@ -0,0 +1,229 @@
+//! This module contains thin wrappers over the NOSEngine simulation time API. A time sender
+//! publishes the simulation time on a bus, and every time client on that bus follows it.
+//! Simulation time is measured in ticks; how long a tick is depends on the simulation
+//! configuration.
+//!
+//! # Examples
+//!
+//! ```
+//! # extern crate nosengine_rust;
+//! # use nosengine_rust::ffi::time::*;
+//! # use std::ffi::CString;
+//! # use std::{thread, time};
+//! let connection = CString::new("tcp://localhost:12001").unwrap();
+//! let bus = CString::new("time10").unwrap();
+//! let sender_name = CString::new("sender").unwrap();
+//! let client_name = CString::new("client").unwrap();
+//!
+//! let mut sender = time_sender_init(sender_name.as_ptr(), connection.as_ptr(), bus.as_ptr());
+//! let mut client = time_client_init(client_name.as_ptr(), connection.as_ptr(), bus.as_ptr());
+//!
+//! assert_eq!(time_sender_send(sender, 42), TimeStatus::Success);
+//! thread::sleep(time::Duration::from_millis(100));
+//! assert_eq!(time_client_get_time(client), 42);
+//!
+//! time_client_close(&mut client as *mut *mut TimeClientHandle);
+//! time_sender_close(&mut sender as *mut *mut TimeSenderHandle);
+//! ```
+
+use libc::{c_char, c_void};
+
+/// This enum represents a handle to an opaque C struct.
+pub enum TimeSenderHandle {}
+
+/// This enum represents a handle to an opaque C struct.
+pub enum TimeClientHandle {}
+
+/// This function creates a time sender, which acts as the source of simulation time on a bus.
+/// Only one time sender can exist per bus. If a second is attempted, this function returns a
+/// null pointer.
+///
+/// # Arguments
+///
+/// * `name`: Name of this time sender. Must be unique on a bus
+/// * `connection`: NOSEngine connection string
+/// * `bus`: Name of the time bus
+///
+/// # Safety
+///
+/// After a time sender is created, it must be cleaned up using `time_sender_close`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate nosengine_rust;
+/// # use nosengine_rust::ffi::time::*;
+/// # use std::ffi::CString;
+/// let connection = CString::new("tcp://localhost:12001").unwrap();
+/// let bus = CString::new("time1").unwrap();
+/// let name1 = CString::new("sender1").unwrap();
+/// let name2 = CString::new("sender2").unwrap();
+/// let mut sender1 = time_sender_init(name1.as_ptr(), connection.as_ptr(), bus.as_ptr());
+/// assert!(!sender1.is_null());
+/// let mut sender2 = time_sender_init(name2.as_ptr(), connection.as_ptr(), bus.as_ptr());
+/// assert!(sender2.is_null());
+/// time_sender_close(&mut sender1 as *mut *mut TimeSenderHandle);
+/// time_sender_close(&mut sender2 as *mut *mut TimeSenderHandle);
+/// ```
+pub fn time_sender_init(
+    name: *const c_char,
+    connection: *const c_char,
+    bus: *const c_char,
+) -> *mut TimeSenderHandle {
+    unsafe { NE_time_sender_init(name, connection, bus) }
+}
+
+/// This function publishes a new simulation time to every time client on the bus.
+///
+/// # Arguments
+///
+/// * `sender`: Handle to a time sender
+/// * `time`: The new simulation time, in ticks
+///
+/// # Examples
+///
+/// See [`nosengine-rust::ffi::time`](../time/index.html#examples)
+pub fn time_sender_send(sender: *mut TimeSenderHandle, time: i64) -> TimeStatus {
+    unsafe { NE_time_sender_send(sender, time) }
+}
+
+/// This function closes a time sender and frees up all associated memory.
+///
+/// # Arguments
+///
+/// * `sender`: A pointer to a pointer to a time sender handle. This handle will be made null by
+///     this function.
+pub fn time_sender_close(sender: *mut *mut TimeSenderHandle) {
+    unsafe { NE_time_sender_close(sender) }
+}
+
+/// This function creates a time client, which follows the simulation time published on a bus.
+///
+/// # Arguments
+///
+/// * `name`: Name of this time client. Must be unique on a bus
+/// * `connection`: NOSEngine connection string
+/// * `bus`: Name of the time bus
+///
+/// # Safety
+///
+/// After a time client is created, it must be cleaned up using `time_client_close`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate nosengine_rust;
+/// # use nosengine_rust::ffi::time::*;
+/// # use std::ffi::CString;
+/// let connection = CString::new("tcp://localhost:12001").unwrap();
+/// let bus = CString::new("time2").unwrap();
+/// let name = CString::new("client1").unwrap();
+/// let mut client = time_client_init(name.as_ptr(), connection.as_ptr(), bus.as_ptr());
+/// assert!(!client.is_null());
+/// time_client_close(&mut client as *mut *mut TimeClientHandle);
+/// assert!(client.is_null());
+/// ```
+pub fn time_client_init(
+    name: *const c_char,
+    connection: *const c_char,
+    bus: *const c_char,
+) -> *mut TimeClientHandle {
+    unsafe { NE_time_client_init(name, connection, bus) }
+}
+
+/// This function returns the most recent simulation time received by this client, in ticks.
+///
+/// # Arguments
+///
+/// * `client`: Handle to a time client
+///
+/// # Examples
+///
+/// See [`nosengine-rust::ffi::time`](../time/index.html#examples)
+pub fn time_client_get_time(client: *mut TimeClientHandle) -> i64 {
+    unsafe { NE_time_client_get_time(client) }
+}
+
+/// This function sets a callback which will execute every time this client receives a new
+/// simulation time.
+///
+/// # Arguments
+///
+/// * `client`: Handle to a time client
+/// * `callback`: Callback which has the following parameters:
+///     * `time`: The new simulation time, in ticks
+///     * `user`: User-specified data that is passed every time this callback runs
+/// * `user`: User-specified data that will be passed to the callback every time it runs
+///
+/// # Examples
+///
+/// ```
+/// # extern crate nosengine_rust;
+/// # extern crate libc;
+/// # use nosengine_rust::ffi::time::*;
+/// # use std::ffi::CString;
+/// # use libc::c_void;
+/// # use std::ptr;
+/// # let connection = CString::new("tcp://localhost:12001").unwrap();
+/// # let bus = CString::new("time3").unwrap();
+/// # let sender_name = CString::new("sender").unwrap();
+/// # let client_name = CString::new("client").unwrap();
+/// let mut sender = time_sender_init(sender_name.as_ptr(), connection.as_ptr(), bus.as_ptr());
+/// let mut client = time_client_init(client_name.as_ptr(), connection.as_ptr(), bus.as_ptr());
+///
+/// extern "C" fn callback(time: i64, _user: *mut c_void) {
+///     assert_eq!(time, 7);
+/// }
+/// time_client_set_tick_callback(client, callback, ptr::null_mut());
+/// time_sender_send(sender, 7);
+///
+/// # time_client_close(&mut client as *mut *mut TimeClientHandle);
+/// # time_sender_close(&mut sender as *mut *mut TimeSenderHandle);
+/// ```
+pub fn time_client_set_tick_callback(
+    client: *mut TimeClientHandle,
+    callback: extern "C" fn(time: i64, user: *mut c_void),
+    user: *mut c_void,
+) {
+    unsafe { NE_time_client_set_tick_callback(client, callback, user) }
+}
+
+/// This function closes a time client and frees up all associated memory.
+///
+/// # Arguments
+///
+/// * `client`: A pointer to a pointer to a time client handle. This handle will be made null by
+///     this function.
+pub fn time_client_close(client: *mut *mut TimeClientHandle) {
+    unsafe { NE_time_client_close(client) }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+#[repr(C)]
+#[allow(missing_docs)]
+pub enum TimeStatus {
+    Success,
+    Failure,
+}
+
+extern "C" {
+    fn NE_time_sender_init(
+        name: *const c_char,
+        connection: *const c_char,
+        bus: *const c_char,
+    ) -> *mut TimeSenderHandle;
+    fn NE_time_sender_send(sender: *mut TimeSenderHandle, time: i64) -> TimeStatus;
+    fn NE_time_sender_close(sender: *mut *mut TimeSenderHandle);
+    fn NE_time_client_init(
+        name: *const c_char,
+        connection: *const c_char,
+        bus: *const c_char,
+    ) -> *mut TimeClientHandle;
+    fn NE_time_client_get_time(client: *mut TimeClientHandle) -> i64;
+    fn NE_time_client_set_tick_callback(
+        client: *mut TimeClientHandle,
+        callback: extern "C" fn(i64, *mut c_void),
+        user: *mut c_void,
+    );
+    fn NE_time_client_close(client: *mut *mut TimeClientHandle);
+}
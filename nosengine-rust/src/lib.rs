@@ -64,7 +64,13 @@
 
 #![deny(missing_docs)]
 
+extern crate bincode;
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
+extern crate serde;
+extern crate serde_cbor;
+extern crate serde_json;
 
 pub mod client;
 pub mod ffi;
@@ -140,6 +146,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn typed_big_endian_test() {
+        use client::typed::*;
+
+        let bus = client::Bus::new("testbus", "tcp://localhost:12001").unwrap();
+        let node9: TypedNode<(u16, u32, [u8; 2]), BigEndian> =
+            TypedNode::new(&bus, "node9").unwrap();
+        let node10 = client::DataNode::new(&bus, "node10").unwrap();
+
+        node10.set_message_handler(|data: &[u8]| {
+            if data == &[0x12u8, 0x34, 0xde, 0xad, 0xbe, 0xef, 1, 2] {
+                Some(vec![0x00u8, 0x01, 0x00, 0x00, 0x00, 0x02, 3, 4])
+            } else {
+                // Too short to decode
+                Some(vec![0x00u8])
+            }
+        });
+
+        let response = node9
+            .request("node10", &(0x1234, 0xdeadbeef, [1, 2]))
+            .unwrap();
+        assert_eq!(response, (1, 2, [3, 4]));
+
+        match node9.request("node10", &(0, 0, [0, 0])) {
+            Err(TypedError::DecodeError { description: _ }) => {}
+            _ => panic!("Expected decode error."),
+        }
+    }
+
     #[test]
     fn spi_test() {
         use client::spi::*;
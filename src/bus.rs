@@ -0,0 +1,37 @@
+//! Sharing of hardware buses between bridges.
+//!
+//! Bridges never open an adapter themselves. They ask the `BusManager` for it, which opens each
+//! adapter once and hands every bridge on it the same handle. A bridge holds the handle's lock for
+//! the whole of each access from the simulated master, so accesses from different bridges never
+//! interleave on the wire.
+
+use i2c_linux::I2c;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// A Linux I2C adapter, shared by every bridge which uses it.
+pub type I2CAdapter = Arc<Mutex<I2c<File>>>;
+
+#[derive(Default)]
+pub struct BusManager {
+    i2c_adapters: Mutex<HashMap<String, I2CAdapter>>,
+}
+
+impl BusManager {
+    pub fn new() -> BusManager {
+        BusManager::default()
+    }
+
+    /// The I2C adapter at `path`, which is opened the first time it is asked for.
+    pub fn i2c_adapter(&self, path: &str) -> io::Result<I2CAdapter> {
+        let mut adapters = self.i2c_adapters.lock().unwrap();
+        if let Some(adapter) = adapters.get(path) {
+            return Ok(adapter.clone());
+        }
+        let adapter = Arc::new(Mutex::new(I2c::from_path(path)?));
+        adapters.insert(path.to_string(), adapter.clone());
+        Ok(adapter)
+    }
+}
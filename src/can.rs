@@ -0,0 +1,327 @@
+//! Bridges a Linux SocketCAN interface to a NOS CAN bus.
+//!
+//! Every frame received on the interface is written to the NOS bus, and every frame received
+//! from the NOS bus is sent on the interface. The same filters apply in both directions. To try
+//! it out without hardware, use a virtual interface:
+//!
+//! ```text
+//! sudo modprobe vcan
+//! sudo ip link add dev vcan0 type vcan
+//! sudo ip link set up vcan0
+//! ```
+
+use crate::capture::{Capture, Direction};
+use crate::config;
+use crate::status::BridgeStatus;
+use nix::fcntl::OFlag;
+use nix::poll::{self, PollFd, PollFlags};
+use nix::unistd;
+use nosengine_rust::client::can::{
+    CANFilter, CANFrame, CANId, CAN, CAN_EFF_MASK, CAN_MAX_DLEN, CAN_SFF_MASK,
+};
+use socketcan::{self, CANSocket};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::Instant;
+use toml::value::Table;
+
+/// Set on a SocketCAN identifier when the frame is extended
+const EFF_FLAG: u32 = 0x8000_0000;
+/// Set on a SocketCAN identifier when the frame is a remote transmission request
+const RTR_FLAG: u32 = 0x4000_0000;
+/// Size of the kernel's `struct can_frame`
+const CAN_FRAME_SIZE: usize = 16;
+
+pub struct CANConfig {
+    pub interface: String,
+    pub nos_bus: String,
+    pub nos_node: String,
+    pub filters: Vec<CANFilter>,
+    /// Record to the capture file, if there is one
+    pub capture: bool,
+}
+
+impl CANConfig {
+    pub fn from_table(name: &str, mut table: Table) -> CANConfig {
+        let section = format!("can.{}", name);
+        let interface = config::required(&mut table, &section, "interface");
+        let nos_bus = config::required(&mut table, &section, "nos_bus");
+        let nos_node = config::optional(&mut table, &section, "nos_node")
+            .unwrap_or_else(|| format!("nos3_io_can_{}", name));
+
+        // e.g. filters = [{ id = 0x100, mask = 0x7F0 }, { id = 0x18FF0000, extended = true }]
+        let filters =
+            config::optional::<Vec<Table>>(&mut table, &section, "filters")
+                .unwrap_or_default()
+                .into_iter()
+                .map(|mut filter| {
+                    let id: u32 = config::required(&mut filter, &section, "id");
+                    let extended = config::optional(&mut filter, &section, "extended")
+                        .unwrap_or(id > CAN_SFF_MASK);
+                    let id = if extended {
+                        CANId::Extended(id & CAN_EFF_MASK)
+                    } else {
+                        CANId::Standard((id & CAN_SFF_MASK) as u16)
+                    };
+                    // Without a mask, only the exact identifier is accepted
+                    let mask = config::optional(&mut filter, &section, "mask")
+                        .unwrap_or(if extended { CAN_EFF_MASK } else { CAN_SFF_MASK });
+                    CANFilter::new(id, mask)
+                })
+                .collect();
+        let capture = config::optional(&mut table, &section, "capture").unwrap_or(true);
+
+        CANConfig {
+            interface,
+            nos_bus,
+            nos_node,
+            filters,
+            capture,
+        }
+    }
+}
+
+pub fn can_init(config: CANConfig, status: Arc<BridgeStatus>, capture: Capture) {
+    let mut can = match CAN::new(&config.nos_node, crate::NOS_CONNECTION, &config.nos_bus) {
+        Ok(can) => {
+            println!("Established CAN connection to NOS! Starting...");
+            can
+        }
+        Err(_) => {
+            println!("NOS connection failure. Try restarting NOS3");
+            return;
+        }
+    };
+    can.set_filters(config.filters.clone());
+
+    let socket = match CANSocket::open(&config.interface) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!(
+                "Error opening the CAN interface '{}', details: {}",
+                &config.interface, err
+            );
+            return;
+        }
+    };
+    if let Err(err) = set_filters(&socket, &config.filters) {
+        println!(
+            "Error filtering the CAN interface '{}', details: {}",
+            &config.interface, err
+        );
+        return;
+    }
+    let (nos_frames, wakeup) = match receive_from_nos(&mut can) {
+        Ok(receiver) => receiver,
+        Err(err) => {
+            println!("<can: error => waiting on NOS: {}", err);
+            return;
+        }
+    };
+
+    // Keep this thread working for the lifetime of the program
+    loop {
+        // Wait until there is a frame on either side
+        let mut fds = [
+            PollFd::new(socket.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(wakeup, PollFlags::POLLIN),
+        ];
+        // If interrupted, look at both sides anyway. Errors and hang-ups are for the read to find.
+        let waited = poll::poll(&mut fds, -1).is_ok();
+        let returned =
+            |fd: &PollFd| !waited || fd.revents().map_or(false, |events| !events.is_empty());
+
+        // incoming CAN frames to NOS
+        let read = if returned(&fds[0]) {
+            Some(socket.read_frame())
+        } else {
+            None
+        };
+        match read {
+            Some(Ok(frame)) if !frame.is_error() => {
+                let received = Instant::now();
+                let id = if frame.is_extended() {
+                    CANId::Extended(frame.id())
+                } else {
+                    CANId::Standard(frame.id() as u16)
+                };
+                let frame = if frame.is_rtr() {
+                    CANFrame::remote(id, frame.data().len())
+                } else {
+                    CANFrame::new(id, frame.data())
+                };
+                if let Ok(frame) = frame {
+                    capture.record_can(Direction::HardwareToNos, &frame);
+                    match can.write(&frame) {
+                        Ok(()) => {
+                            status.transfer();
+                            status.latency(received.elapsed());
+                        }
+                        Err(err) => {
+                            println!("<can: error => {}", err);
+                            status.failure(&io::Error::new(io::ErrorKind::Other, err.to_string()));
+                        }
+                    }
+                }
+            }
+            Some(Err(ref err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+            // Counted rather than printed, since an interface which has gone fails every read
+            Some(Err(err)) => status.failure(&err),
+            _ => {}
+        }
+        // outgoing NOS frames to CAN
+        if returned(&fds[1]) {
+            drain(wakeup);
+        }
+        while let Ok(frame) = nos_frames.try_recv() {
+            let received = Instant::now();
+            capture.record_can(Direction::NosToHardware, &frame);
+            match write_frame(&socket, &frame) {
+                Ok(()) => {
+                    status.transfer();
+                    status.latency(received.elapsed());
+                }
+                Err(err) => {
+                    println!("<can: error => {}", err);
+                    status.failure(&err);
+                }
+            }
+        }
+    }
+}
+
+/// Have the kernel drop frames which do not pass the filters. Without any filters, every frame is
+/// received.
+fn set_filters(socket: &CANSocket, filters: &[CANFilter]) -> io::Result<()> {
+    if filters.is_empty() {
+        return Ok(());
+    }
+    let filters = filters
+        .iter()
+        .map(|filter| {
+            // Include the extended flag in the mask, so standard and extended frames never match
+            // each other's filters
+            let id = match filter.id {
+                CANId::Standard(id) => u32::from(id),
+                CANId::Extended(id) => id | EFF_FLAG,
+            };
+            socketcan::CANFilter::new(id, filter.mask | EFF_FLAG)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))
+        })
+        .collect::<io::Result<Vec<socketcan::CANFilter>>>()?;
+    socket.set_filter(&filters)
+}
+
+/// Pass the frames NOS delivers through a channel, and write a byte to a pipe for each one, so
+/// that the bridge can wait on the pipe alongside the interface. Returns the channel and the read
+/// end of the pipe.
+fn receive_from_nos(can: &mut CAN) -> io::Result<(Receiver<CANFrame>, RawFd)> {
+    let (wakeup, notify) = unistd::pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+    let (frames, received) = mpsc::channel();
+    can.set_callback(move |frame: &CANFrame| {
+        if frames.send(frame.clone()).is_ok() {
+            // A full pipe already has the bridge awake
+            let _ = unistd::write(notify, &[0]);
+        }
+    });
+    Ok((received, wakeup))
+}
+
+/// Empty the wakeup pipe, before the frames it announced are taken from the channel.
+fn drain(wakeup: RawFd) {
+    let mut buf = [0u8; 64];
+    while let Ok(n) = unistd::read(wakeup, &mut buf) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+/// Send a frame on the interface. `socketcan::CANFrame` marks a frame as extended from its
+/// identifier alone, which would send an extended frame with an identifier below 0x800 as a
+/// standard one, so the kernel's `struct can_frame` is written directly instead.
+fn write_frame(socket: &CANSocket, frame: &CANFrame) -> io::Result<()> {
+    match unistd::write(socket.as_raw_fd(), &encode(frame)?) {
+        Ok(CAN_FRAME_SIZE) => Ok(()),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "the CAN frame was only partly written",
+        )),
+        Err(err) => Err(io::Error::from(err)),
+    }
+}
+
+/// Lay a frame out as the kernel's `struct can_frame`: the identifier with its flags, the
+/// length, three bytes of padding, then the payload.
+fn encode(frame: &CANFrame) -> io::Result<[u8; CAN_FRAME_SIZE]> {
+    if frame.data.len() > CAN_MAX_DLEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("a CAN frame carries at most {} bytes", CAN_MAX_DLEN),
+        ));
+    }
+    let mut id = match frame.id {
+        CANId::Standard(id) => u32::from(id),
+        CANId::Extended(id) => id | EFF_FLAG,
+    };
+    if frame.rtr {
+        id |= RTR_FLAG;
+    }
+    let mut raw = [0u8; CAN_FRAME_SIZE];
+    raw[..4].copy_from_slice(&id.to_ne_bytes());
+    raw[4] = frame.data.len() as u8;
+    raw[8..8 + frame.data.len()].copy_from_slice(&frame.data);
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(raw: &[u8; CAN_FRAME_SIZE]) -> u32 {
+        u32::from_ne_bytes([raw[0], raw[1], raw[2], raw[3]])
+    }
+
+    #[test]
+    fn extended_frames_keep_the_flag_below_0x800() {
+        let frame = CANFrame::new(CANId::Extended(0x123), &[1, 2, 3]).unwrap();
+        let raw = encode(&frame).unwrap();
+        assert_eq!(id(&raw), 0x123 | EFF_FLAG);
+        assert_eq!(raw[4], 3);
+        assert_eq!(&raw[8..11], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn standard_and_remote_frames() {
+        let raw = encode(&CANFrame::new(CANId::Standard(0x7FF), &[]).unwrap()).unwrap();
+        assert_eq!(id(&raw), 0x7FF);
+        let raw = encode(&CANFrame::remote(CANId::Standard(0x10), 4).unwrap()).unwrap();
+        assert_eq!(id(&raw), 0x10 | RTR_FLAG);
+        assert_eq!(raw[4], 4);
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let frame = CANFrame {
+            id: CANId::Standard(1),
+            rtr: false,
+            data: vec![0; 9],
+        };
+        assert!(encode(&frame).is_err());
+    }
+
+    #[test]
+    fn draining_empties_the_wakeup_pipe() {
+        let (wakeup, notify) = unistd::pipe2(OFlag::O_NONBLOCK).unwrap();
+        for _ in 0..100 {
+            unistd::write(notify, &[0]).unwrap();
+        }
+        drain(wakeup);
+        let mut fds = [PollFd::new(wakeup, PollFlags::POLLIN)];
+        assert_eq!(poll::poll(&mut fds, 0).unwrap(), 0);
+        unistd::close(wakeup).unwrap();
+        unistd::close(notify).unwrap();
+    }
+}
@@ -0,0 +1,715 @@
+//! Recording bridge traffic to a pcapng file, which Wireshark can open.
+//!
+//! With a `[capture]` section in the config, every bridge records what it passes in each
+//! direction to the one file at `path`, unless the bridge has `capture = false`. Each bridge is an
+//! interface in the file, named after the bridge, and each packet carries its direction in its
+//! flags: inbound for hardware to NOS, and outbound for NOS to hardware. Interfaces use a user
+//! link type for each kind of bridge, except for CAN, which uses SocketCAN's. I2C packets start
+//! with a 3-byte header: the address, big-endian with bit 15 set for a 10-bit address, then 1 for
+//! a read or 0 for a write. Each packet also has a comment describing it, for reading in
+//! Wireshark, which for a UART bridge with CCSDS framing gives the packet's APID, sequence count
+//! and length. I2C and SPI bridges also record each attempt at a transfer which failed, with bit
+//! 24 set in its flags and the error in its comment: what was to be written, or for a read,
+//! nothing.
+//!
+//! A UART or I2C bridge with `record = "<path>"` also records to a session file of its own, in
+//! the same format, which the `replay` command can play back (see `replay.rs`).
+//!
+//! Bridges also pass their traffic to the monitor (see `monitor.rs`), and count it in their
+//! status, captured or not.
+//!
+//! The `convert` command turns a capture into CSV or JSON.
+
+use crate::ccsds::PrimaryHeader;
+use crate::config;
+use crate::monitor::Monitor;
+use crate::status::BridgeStatus;
+use nosengine_rust::client::can::{CANFrame, CANId};
+use nosengine_rust::client::i2c::I2CAddress;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use toml::value::Table;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+/// Set on a SocketCAN identifier when the frame is extended
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+/// Set on a SocketCAN identifier when the frame is a remote request
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+/// Set on a captured I2C address when it is 10-bit
+const I2C_TEN_BIT: u16 = 0x8000;
+/// Set in a packet's flags when the transfer failed. The top byte of the flags is for errors
+/// defined by the link type, which for the user link types is ours to define.
+const EPB_FAILED: u32 = 1 << 24;
+
+/// The kind of bridge an interface belongs to, which sets its link type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Link {
+    Uart,
+    I2C,
+    Spi,
+    Udp,
+    Can,
+}
+
+impl Link {
+    fn link_type(self) -> u16 {
+        match self {
+            // LINKTYPE_USER0 to LINKTYPE_USER3
+            Link::Uart => 147,
+            Link::I2C => 148,
+            Link::Spi => 149,
+            Link::Udp => 150,
+            // LINKTYPE_CAN_SOCKETCAN
+            Link::Can => 227,
+        }
+    }
+
+    fn from_link_type(link_type: u16) -> Option<Link> {
+        match link_type {
+            147 => Some(Link::Uart),
+            148 => Some(Link::I2C),
+            149 => Some(Link::Spi),
+            150 => Some(Link::Udp),
+            227 => Some(Link::Can),
+            _ => None,
+        }
+    }
+}
+
+/// Which way traffic went through a bridge.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    HardwareToNos,
+    NosToHardware,
+}
+
+impl Direction {
+    /// The direction bits of a packet's flags.
+    fn flags(self) -> u32 {
+        match self {
+            Direction::HardwareToNos => 1,
+            Direction::NosToHardware => 2,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::HardwareToNos => write!(f, "HW->NOS"),
+            Direction::NosToHardware => write!(f, "NOS->HW"),
+        }
+    }
+}
+
+/// Read the `[capture]` section, if there is one, and return the path to capture to.
+pub fn capture_path(config: &mut Table) -> Option<String> {
+    let mut table = config::section(config, "capture")?;
+    Some(config::required(&mut table, "capture", "path"))
+}
+
+/// A pcapng file which bridges record to.
+pub struct CaptureFile {
+    file: Mutex<(File, u32)>,
+}
+
+impl CaptureFile {
+    /// Create the file, replacing any earlier capture at `path`.
+    pub fn create(path: &str) -> io::Result<CaptureFile> {
+        let mut file = File::create(path)?;
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Section length not given
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        end_options(&mut body);
+        file.write_all(&block(SECTION_HEADER, &body))?;
+        Ok(CaptureFile {
+            file: Mutex::new((file, 0)),
+        })
+    }
+
+    /// Add an interface for a bridge, and return its ID.
+    fn interface(&self, name: &str, link: Link) -> io::Result<u32> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&link.link_type().to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut body, IF_NAME, name.as_bytes());
+        end_options(&mut body);
+
+        let mut guard = self.file.lock().unwrap();
+        let (ref mut out, ref mut interfaces) = *guard;
+        out.write_all(&block(INTERFACE_DESCRIPTION, &body))?;
+        let id = *interfaces;
+        *interfaces += 1;
+        Ok(id)
+    }
+}
+
+/// What a bridge records its traffic to: an interface in the capture file, if there is one and
+/// the bridge records to it, the monitor, and the bridge's status.
+pub fn interface(
+    file: &Option<Arc<CaptureFile>>,
+    enabled: bool,
+    name: &str,
+    link: Link,
+    monitor: &Arc<Monitor>,
+    status: &Arc<BridgeStatus>,
+) -> Capture {
+    let mut capture = Capture {
+        sinks: Vec::new(),
+        monitor: monitor.clone(),
+        status: status.clone(),
+        bridge: name.to_string(),
+    };
+    if let Some(file) = file.as_ref().filter(|_| enabled) {
+        match file.interface(name, link) {
+            Ok(id) => capture.sinks.push((file.clone(), id)),
+            Err(err) => println!("<capture: error => {}", err),
+        }
+    }
+    capture
+}
+
+/// Open a session file of a bridge's own, and add it to what the bridge records to.
+pub fn with_session(mut capture: Capture, path: Option<&String>, link: Link) -> Capture {
+    let path = match path {
+        Some(path) => path,
+        None => return capture,
+    };
+    let session = CaptureFile::create(path).and_then(|file| {
+        let id = file.interface(&capture.bridge, link)?;
+        Ok((Arc::new(file), id))
+    });
+    match session {
+        Ok(session) => capture.sinks.push(session),
+        Err(err) => println!("<capture: error => creating '{}': {}", path, err),
+    }
+    capture
+}
+
+/// What one bridge records to: its interface in the capture file, and in its session file if it
+/// has one, the monitor, and the bridge's traffic counts.
+#[derive(Clone)]
+pub struct Capture {
+    sinks: Vec<(Arc<CaptureFile>, u32)>,
+    monitor: Arc<Monitor>,
+    status: Arc<BridgeStatus>,
+    /// The bridge's name, such as "uart 1"
+    bridge: String,
+}
+
+impl Capture {
+    /// Record bytes passed by a UART, SPI or UDP bridge.
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        let comment = format!("{} {} bytes", direction, data.len());
+        self.observe(direction, None, &comment, data);
+        self.write(direction, data, &comment, false);
+    }
+
+    /// Record an attempt by an SPI bridge to write bytes, or to read, which failed with `err`.
+    /// For a read, `data` is empty.
+    pub fn record_failure(&self, direction: Direction, data: &[u8], err: &io::Error) {
+        let comment = match direction {
+            Direction::NosToHardware => {
+                format!("{} write {} bytes, failed: {}", direction, data.len(), err)
+            }
+            Direction::HardwareToNos => format!("{} read, failed: {}", direction, err),
+        };
+        self.monitor
+            .show(&self.bridge, direction, None, &comment, data);
+        self.write(direction, data, &comment, true);
+    }
+
+    /// Record a space packet passed by a UART bridge with CCSDS framing, described by its primary
+    /// header.
+    pub fn record_packet(&self, direction: Direction, packet: &[u8]) {
+        let comment = match PrimaryHeader::parse(packet) {
+            Some(header) => format!("{} {}", direction, header),
+            None => format!("{} {} bytes", direction, packet.len()),
+        };
+        self.observe(direction, None, &comment, packet);
+        self.write(direction, packet, &comment, false);
+    }
+
+    /// Record bytes written to or read from an I2C device.
+    pub fn record_i2c(&self, direction: Direction, address: I2CAddress, read: bool, data: &[u8]) {
+        let comment = format!(
+            "{} {} {} {} bytes",
+            direction,
+            address,
+            if read { "read" } else { "write" },
+            data.len()
+        );
+        self.observe(direction, Some(address), &comment, data);
+        self.write(direction, &i2c_packet(address, read, data), &comment, false);
+    }
+
+    /// Record an attempt to write bytes to or read from an I2C device which failed with `err`.
+    /// For a read, `data` is empty.
+    pub fn record_i2c_failure(
+        &self,
+        direction: Direction,
+        address: I2CAddress,
+        read: bool,
+        data: &[u8],
+        err: &io::Error,
+    ) {
+        let comment = if read {
+            format!("{} {} read, failed: {}", direction, address, err)
+        } else {
+            format!(
+                "{} {} write {} bytes, failed: {}",
+                direction,
+                address,
+                data.len(),
+                err
+            )
+        };
+        self.monitor
+            .show(&self.bridge, direction, Some(address), &comment, data);
+        self.write(direction, &i2c_packet(address, read, data), &comment, true);
+    }
+
+    /// Record a CAN frame, in SocketCAN's format.
+    pub fn record_can(&self, direction: Direction, frame: &CANFrame) {
+        let mut id = match frame.id {
+            CANId::Standard(id) => u32::from(id),
+            CANId::Extended(id) => id | CAN_EFF_FLAG,
+        };
+        if frame.rtr {
+            id |= CAN_RTR_FLAG;
+        }
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[frame.data.len() as u8, 0, 0, 0]);
+        if !frame.rtr {
+            packet.extend_from_slice(&frame.data);
+        }
+        let comment = format!("{} {} {} bytes", direction, frame.id, frame.data.len());
+        self.observe(direction, None, &comment, &frame.data);
+        self.write(direction, &packet, &comment, false);
+    }
+
+    /// Count the data in the bridge's status, and show it on the monitor.
+    fn observe(
+        &self,
+        direction: Direction,
+        address: Option<I2CAddress>,
+        comment: &str,
+        data: &[u8],
+    ) {
+        self.status.traffic(direction, data.len());
+        self.monitor
+            .show(&self.bridge, direction, address, comment, data);
+    }
+
+    /// Write a packet to every file the bridge records to, flagged if the transfer failed.
+    fn write(&self, direction: Direction, packet: &[u8], comment: &str, failed: bool) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        // Microseconds, the default resolution
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs() * 1_000_000 + u64::from(time.subsec_micros()))
+            .unwrap_or(0);
+        let mut body = Vec::with_capacity(packet.len() + comment.len() + 40);
+        // Interface ID, filled in for each file below
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((time >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(time as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        pad(&mut body);
+        let mut flags = direction.flags();
+        if failed {
+            flags |= EPB_FAILED;
+        }
+        option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+        option(&mut body, OPT_COMMENT, comment.as_bytes());
+        end_options(&mut body);
+
+        for (file, id) in &self.sinks {
+            body[..4].copy_from_slice(&id.to_le_bytes());
+            let mut guard = file.file.lock().unwrap();
+            if let Err(err) = guard.0.write_all(&block(ENHANCED_PACKET, &body)) {
+                println!("<capture: error => {}", err);
+            }
+        }
+    }
+}
+
+/// An I2C packet: the address, whether it was a read, then the data.
+fn i2c_packet(address: I2CAddress, read: bool, data: &[u8]) -> Vec<u8> {
+    let raw = if address.is_ten_bit() {
+        address.raw() | I2C_TEN_BIT
+    } else {
+        address.raw()
+    };
+    let mut packet = raw.to_be_bytes().to_vec();
+    packet.push(read as u8);
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Wrap a block's body with its type and lengths.
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let length = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(length as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&length.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&length.to_le_bytes());
+    block
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn end_options(body: &mut Vec<u8>) {
+    body.extend_from_slice(&OPT_END.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+}
+
+/// Pad to a multiple of 4 bytes.
+fn pad(body: &mut Vec<u8>) {
+    while body.len() % 4 != 0 {
+        body.push(0);
+    }
+}
+
+/// One packet read back from a capture.
+pub struct Record {
+    /// Microseconds since the Unix epoch
+    pub time: u64,
+    pub bridge: String,
+    pub link: Option<Link>,
+    pub direction: Option<Direction>,
+    /// I2C address or CAN identifier
+    pub address: Option<String>,
+    /// For I2C, "read" or "write"
+    pub operation: Option<&'static str>,
+    /// For I2C, the address and whether it was a read
+    pub i2c: Option<(I2CAddress, bool)>,
+    /// The transfer failed, and `data` is what was to be written
+    pub failed: bool,
+    pub data: Vec<u8>,
+}
+
+/// Read every packet from a capture written by `CaptureFile`, or by anything else which writes
+/// pcapng with microsecond timestamps. A capture still being written, or cut off when nos3_io
+/// stopped, can end part way through a block, so a truncated last block ends the capture rather
+/// than failing it.
+pub fn read_capture(path: &str) -> io::Result<Vec<Record>> {
+    let bytes = fs::read(path)?;
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut interfaces: Vec<(Option<Link>, String)> = Vec::new();
+    let mut records = Vec::new();
+    let mut big_endian = false;
+    let mut at = 0;
+    while at + 12 <= bytes.len() {
+        if bytes[at..at + 4] == SECTION_HEADER.to_le_bytes() {
+            big_endian = bytes[at + 8..at + 12] == BYTE_ORDER_MAGIC.to_be_bytes();
+            interfaces.clear();
+        }
+        let u16_at = |i: usize| {
+            let field = [bytes[i], bytes[i + 1]];
+            if big_endian {
+                u16::from_be_bytes(field)
+            } else {
+                u16::from_le_bytes(field)
+            }
+        };
+        let u32_at = |i: usize| {
+            let field = [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]];
+            if big_endian {
+                u32::from_be_bytes(field)
+            } else {
+                u32::from_le_bytes(field)
+            }
+        };
+        let block_type = u32_at(at);
+        let length = u32_at(at + 4) as usize;
+        if length < 12 {
+            return Err(invalid("block shorter than its header"));
+        }
+        if at + length > bytes.len() {
+            break;
+        }
+        let body = at + 8..at + length - 4;
+        // Options as (code, where the value starts, value length), from `start` to the end of
+        // the body
+        let options = |start: usize| {
+            let mut options = Vec::new();
+            let mut i = start;
+            while i + 4 <= body.end {
+                let (code, len) = (u16_at(i), u16_at(i + 2) as usize);
+                if code == OPT_END || i + 4 + len > body.end {
+                    break;
+                }
+                options.push((code, i + 4, len));
+                i += 4 + (len + 3) / 4 * 4;
+            }
+            options
+        };
+
+        match block_type {
+            INTERFACE_DESCRIPTION => {
+                if body.len() < 8 {
+                    return Err(invalid("interface description too short"));
+                }
+                let link = Link::from_link_type(u16_at(body.start));
+                let name = options(body.start + 8)
+                    .into_iter()
+                    .find(|(code, _, _)| *code == IF_NAME)
+                    .map(|(_, start, len)| {
+                        String::from_utf8_lossy(&bytes[start..start + len]).into_owned()
+                    })
+                    .unwrap_or_else(|| format!("interface {}", interfaces.len()));
+                interfaces.push((link, name));
+            }
+            ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    return Err(invalid("packet block too short"));
+                }
+                let interface = u32_at(body.start) as usize;
+                let time =
+                    u64::from(u32_at(body.start + 4)) << 32 | u64::from(u32_at(body.start + 8));
+                let captured = u32_at(body.start + 12) as usize;
+                let data_start = body.start + 20;
+                if data_start + captured > body.end {
+                    return Err(invalid("truncated packet"));
+                }
+                let (link, bridge) = interfaces
+                    .get(interface)
+                    .cloned()
+                    .ok_or_else(|| invalid("packet before its interface"))?;
+                let flags = options(data_start + (captured + 3) / 4 * 4)
+                    .into_iter()
+                    .find(|(code, _, len)| *code == EPB_FLAGS && *len == 4)
+                    .map_or(0, |(_, start, _)| u32_at(start));
+                let direction = match flags & 3 {
+                    1 => Some(Direction::HardwareToNos),
+                    2 => Some(Direction::NosToHardware),
+                    _ => None,
+                };
+                let mut record = Record {
+                    time,
+                    bridge,
+                    link,
+                    direction,
+                    address: None,
+                    operation: None,
+                    i2c: None,
+                    failed: flags & EPB_FAILED != 0,
+                    data: bytes[data_start..data_start + captured].to_vec(),
+                };
+                decode_header(&mut record);
+                records.push(record);
+            }
+            _ => (),
+        }
+        at += length;
+    }
+    Ok(records)
+}
+
+/// Take the I2C or CAN header off the front of a record's data.
+fn decode_header(record: &mut Record) {
+    match record.link {
+        Some(Link::I2C) if record.data.len() >= 3 => {
+            let raw = u16::from_be_bytes([record.data[0], record.data[1]]);
+            let address = if raw & I2C_TEN_BIT != 0 {
+                I2CAddress::TenBit(raw & !I2C_TEN_BIT)
+            } else {
+                I2CAddress::SevenBit(raw)
+            };
+            let read = record.data[2] != 0;
+            record.address = Some(address.to_string());
+            record.operation = Some(if read { "read" } else { "write" });
+            record.i2c = Some((address, read));
+            record.data.drain(..3);
+        }
+        Some(Link::Can) if record.data.len() >= 8 => {
+            let id = u32::from_be_bytes([
+                record.data[0],
+                record.data[1],
+                record.data[2],
+                record.data[3],
+            ]);
+            let len = (record.data[4] as usize).min(record.data.len() - 8);
+            record.address = Some(if id & CAN_EFF_FLAG != 0 {
+                format!("{:#010X}", id & 0x1FFF_FFFF)
+            } else {
+                format!("{:#05X}", id & 0x7FF)
+            });
+            if id & CAN_RTR_FLAG != 0 {
+                record.operation = Some("remote");
+            }
+            record.data = record.data[8..8 + len].to_vec();
+        }
+        _ => (),
+    }
+}
+
+/// Convert a capture to CSV or JSON, next to it with the format as its extension. Returns where
+/// the conversion was written.
+pub fn convert(path: &str, format: &str) -> io::Result<String> {
+    let records = read_capture(path)?;
+    let out = Path::new(path)
+        .with_extension(format)
+        .to_string_lossy()
+        .into_owned();
+    let text = match format {
+        "csv" => {
+            let mut text =
+                String::from("time,bridge,direction,address,operation,length,data,failed\n");
+            for record in &records {
+                text.push_str(&format!(
+                    "{},\"{}\",{},{},{},{},{},{}\n",
+                    seconds(record.time),
+                    record.bridge.replace('"', "\"\""),
+                    record.direction.map(|d| d.to_string()).unwrap_or_default(),
+                    record.address.clone().unwrap_or_default(),
+                    record.operation.unwrap_or_default(),
+                    record.data.len(),
+                    hex(&record.data),
+                    record.failed
+                ));
+            }
+            text
+        }
+        "json" => {
+            let records: Vec<serde_json::Value> = records
+                .iter()
+                .map(|record| {
+                    serde_json::json!({
+                        "time": seconds(record.time),
+                        "bridge": record.bridge,
+                        "direction": record.direction.map(|d| d.to_string()),
+                        "address": record.address,
+                        "operation": record.operation,
+                        "data": hex(&record.data),
+                        "failed": record.failed,
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&records)? + "\n"
+        }
+        format => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown format '{}', expected csv or json", format),
+            ))
+        }
+    };
+    fs::write(&out, text)?;
+    Ok(out)
+}
+
+/// Seconds since the Unix epoch, from microseconds.
+fn seconds(time: u64) -> String {
+    format!("{}.{:06}", time / 1_000_000, time % 1_000_000)
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// A path in the temporary directory, unique to this process and test.
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("nos3_io_{}_{}.pcapng", process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Record some traffic from an I2C and a UART bridge to a new capture at `path`.
+    fn write_capture(path: &str) {
+        let file = Some(Arc::new(CaptureFile::create(path).unwrap()));
+        let monitor = Arc::new(Monitor::new());
+        let status = Arc::new(BridgeStatus::new());
+        let i2c = interface(&file, true, "i2c 0", Link::I2C, &monitor, &status);
+        let uart = interface(&file, true, "uart 1", Link::Uart, &monitor, &status);
+        let address = I2CAddress::TenBit(0x250);
+        i2c.record_i2c(Direction::NosToHardware, address, false, &[0x10]);
+        let err = io::Error::from_raw_os_error(libc::ENXIO);
+        i2c.record_i2c_failure(Direction::HardwareToNos, address, true, &[], &err);
+        uart.record(Direction::HardwareToNos, b"PING\r\n");
+    }
+
+    #[test]
+    fn captures_read_back_as_written() {
+        let path = temp_path("round_trip");
+        write_capture(&path);
+        let records = read_capture(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].bridge, "i2c 0");
+        assert_eq!(records[0].direction, Some(Direction::NosToHardware));
+        assert_eq!(records[0].i2c, Some((I2CAddress::TenBit(0x250), false)));
+        assert_eq!(records[0].data, [0x10]);
+        assert!(!records[0].failed);
+        assert_eq!(records[1].i2c, Some((I2CAddress::TenBit(0x250), true)));
+        assert!(records[1].failed);
+        assert!(records[1].data.is_empty());
+        assert_eq!(records[2].bridge, "uart 1");
+        assert_eq!(records[2].link, Some(Link::Uart));
+        assert_eq!(records[2].data, b"PING\r\n");
+        assert!(records[0].time <= records[2].time);
+    }
+
+    #[test]
+    fn a_truncated_last_block_ends_the_capture() {
+        let path = temp_path("truncated");
+        write_capture(&path);
+        let mut bytes = fs::read(&path).unwrap();
+        let length = bytes.len();
+        bytes.truncate(length - 10);
+        fs::write(&path, &bytes).unwrap();
+        let records = read_capture(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn short_blocks_are_invalid() {
+        let path = temp_path("short");
+        write_capture(&path);
+        let mut bytes = fs::read(&path).unwrap();
+        // A packet block of 12 bytes has no room for its fields
+        bytes.extend_from_slice(&block(ENHANCED_PACKET, &[]));
+        fs::write(&path, &bytes).unwrap();
+        let err = read_capture(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
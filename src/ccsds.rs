@@ -0,0 +1,201 @@
+//! CCSDS space packets (CCSDS 133.0-B), as spoken by NOS3 flight software.
+//!
+//! With `framing = "ccsds"` a stream is split into whole space packets: a packet is only sent on
+//! once all of it has arrived, and bytes which do not start a valid primary header are dropped
+//! until one does. Packets may also be checked against a CRC or checksum before they are sent.
+
+use crate::config;
+use std::fmt;
+use toml::value::Table;
+
+/// Size of the primary header
+pub const HEADER_LEN: usize = 6;
+
+/// Largest packet accepted when the bridge does not set `max_frame`, so that a false header with
+/// a large length is skipped rather than waited on. A header allows up to 65542 bytes.
+pub const DEFAULT_MAX_PACKET: usize = 4096;
+
+/// The primary header at the start of every space packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrimaryHeader {
+    pub version: u8,
+    /// A telecommand rather than telemetry
+    pub telecommand: bool,
+    pub secondary_header: bool,
+    pub apid: u16,
+    pub sequence_flags: u8,
+    pub sequence_count: u16,
+    /// The packet data field's length, less one
+    pub data_length: u16,
+}
+
+impl PrimaryHeader {
+    /// Read the header at the start of `bytes`, if there are enough of them and the version is 0.
+    pub fn parse(bytes: &[u8]) -> Option<PrimaryHeader> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let header = PrimaryHeader {
+            version: bytes[0] >> 5,
+            telecommand: bytes[0] & 0x10 != 0,
+            secondary_header: bytes[0] & 0x08 != 0,
+            apid: u16::from(bytes[0] & 0x07) << 8 | u16::from(bytes[1]),
+            sequence_flags: bytes[2] >> 6,
+            sequence_count: u16::from(bytes[2] & 0x3F) << 8 | u16::from(bytes[3]),
+            data_length: u16::from(bytes[4]) << 8 | u16::from(bytes[5]),
+        };
+        if header.version == 0 {
+            Some(header)
+        } else {
+            None
+        }
+    }
+
+    /// Length of the whole packet, header included.
+    pub fn packet_length(&self) -> usize {
+        HEADER_LEN + usize::from(self.data_length) + 1
+    }
+}
+
+impl fmt::Display for PrimaryHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} APID 0x{:03X}, seq {}, {} bytes",
+            if self.telecommand { "TC" } else { "TM" },
+            self.apid,
+            self.sequence_count,
+            self.packet_length()
+        )
+    }
+}
+
+/// How the integrity of a packet is checked.
+#[derive(Clone, Copy)]
+pub enum Check {
+    None,
+    /// The packet ends with a CRC-16-CCITT of the rest of it, as in the packet error control
+    /// field of the CCSDS telecommand standards.
+    Crc16,
+    /// Commands with a secondary header carry the cFS checksum, so that every byte of the packet
+    /// XORs to 0xFF. Telemetry is not checked.
+    CfsChecksum,
+}
+
+impl Check {
+    pub fn verify(self, packet: &[u8]) -> bool {
+        match self {
+            Check::None => true,
+            Check::Crc16 => packet.len() >= HEADER_LEN + 2 && crc16(packet) == 0,
+            Check::CfsChecksum => match PrimaryHeader::parse(packet) {
+                Some(ref header) if header.telecommand && header.secondary_header => {
+                    packet.iter().fold(0, |sum, byte| sum ^ byte) == 0xFF
+                }
+                _ => true,
+            },
+        }
+    }
+}
+
+/// CRC-16-CCITT (polynomial 0x1021, initial value 0xFFFF). Over a packet which ends with its own
+/// CRC, this is 0.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, byte| {
+        (0..8).fold(crc ^ u16::from(*byte) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[derive(Clone)]
+pub struct CcsdsConfig {
+    pub check: Check,
+    /// Only headers with these APIDs are synchronized on, if set
+    pub apids: Option<Vec<u16>>,
+    /// Print the header of every packet
+    pub log: bool,
+}
+
+impl CcsdsConfig {
+    pub fn from_table(section: &str, table: &mut Table) -> CcsdsConfig {
+        let check = config::optional::<String>(table, section, "ccsds_check")
+            .unwrap_or_else(|| "none".to_string());
+        let check = match check.as_str() {
+            "none" => Check::None,
+            "crc16" => Check::Crc16,
+            "cfs_checksum" => Check::CfsChecksum,
+            check => panic!(
+                "Error parsing config.toml: [{}] unknown 'ccsds_check' {}",
+                section, check
+            ),
+        };
+        let apids = config::optional::<Vec<u16>>(table, section, "ccsds_apids");
+        if let Some(apid) = apids.iter().flatten().find(|apid| **apid > 0x7FF) {
+            panic!(
+                "Error parsing config.toml: [{}] APID {:#X} is more than 11 bits",
+                section, apid
+            );
+        }
+
+        CcsdsConfig {
+            check,
+            apids,
+            log: config::optional(table, section, "ccsds_log").unwrap_or(false),
+        }
+    }
+
+    /// Whether a header is one to synchronize on.
+    pub fn accepts(&self, header: &PrimaryHeader) -> bool {
+        self.apids
+            .as_ref()
+            .map_or(true, |apids| apids.contains(&header.apid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_are_parsed() {
+        let header = PrimaryHeader::parse(&[0x18, 0x42, 0xC0, 0x05, 0x00, 0x03]).unwrap();
+        assert!(header.telecommand);
+        assert!(header.secondary_header);
+        assert_eq!(header.apid, 0x042);
+        assert_eq!(header.sequence_flags, 3);
+        assert_eq!(header.sequence_count, 5);
+        assert_eq!(header.packet_length(), 10);
+        assert_eq!(header.to_string(), "TC APID 0x042, seq 5, 10 bytes");
+        // Version 1, or too short
+        assert!(PrimaryHeader::parse(&[0x20, 0, 0, 0, 0, 0]).is_none());
+        assert!(PrimaryHeader::parse(&[0x08, 0x42, 0xC0]).is_none());
+    }
+
+    #[test]
+    fn crc16_is_ccitt() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        let mut packet = vec![0x08, 0x42, 0xC0, 0x00, 0x00, 0x02, 0xAB];
+        let crc = crc16(&packet);
+        packet.extend_from_slice(&crc.to_be_bytes());
+        assert!(Check::Crc16.verify(&packet));
+        packet[6] ^= 1;
+        assert!(!Check::Crc16.verify(&packet));
+    }
+
+    #[test]
+    fn cfs_checksums_are_checked_on_commands_only() {
+        // Command with a secondary header: function code, then a checksum making it all XOR to
+        // 0xFF
+        let mut command = vec![0x18, 0x42, 0xC0, 0x00, 0x00, 0x01, 0x03, 0x00];
+        command[7] = command.iter().fold(0xFF, |sum, byte| sum ^ byte);
+        assert!(Check::CfsChecksum.verify(&command));
+        command[6] = 0x04;
+        assert!(!Check::CfsChecksum.verify(&command));
+        // Telemetry is not checked
+        assert!(Check::CfsChecksum.verify(&[0x08, 0x42, 0xC0, 0x00, 0x00, 0x00, 0x00]));
+    }
+}
@@ -0,0 +1,661 @@
+//! Compares a run's bridge traffic with a known-good (golden) run, both recorded as captures (see
+//! `capture.rs`), to show exactly how the traffic changed after a flight software change.
+//!
+//! Each bridge and direction is compared on its own. Where every byte of it splits into CCSDS
+//! space packets, it is compared packet by packet, and packets are aligned by APID, so that a
+//! packet is matched even though its sequence count and contents have changed. Otherwise it is
+//! compared frame by frame, and frames are aligned by their bytes. Either way, alignment keeps the
+//! frames in order, as in a diff, and what is left over is reported as inserted or removed, or as
+//! changed where a golden frame and a run frame are left over at the same place. A frame matched in
+//! both runs is reported as shifted if it came earlier or later, relative to the start of its run,
+//! by more than the tolerance.
+
+use crate::capture::{self, Link, Record};
+use crate::ccsds::{PrimaryHeader, HEADER_LEN};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Timing shifts up to this are not reported, unless the command gives `tolerance_ms`
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_millis(50);
+
+/// Bytes of a frame shown in the text report
+const TEXT_BYTES: usize = 32;
+
+/// A frame or space packet from one run.
+#[derive(Clone)]
+pub struct Frame {
+    /// Position in its bridge and direction, from 0
+    pub index: usize,
+    /// Microseconds since the start of its run
+    pub offset: u64,
+    /// The space packet's header, for packets
+    pub label: Option<String>,
+    pub data: Vec<u8>,
+    /// What frames are aligned by
+    key: Vec<u8>,
+    /// What frames are compared by, once aligned
+    content: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Inserted,
+    Removed,
+    Changed,
+    Shifted,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Inserted => "inserted",
+            Kind::Removed => "removed",
+            Kind::Changed => "changed",
+            Kind::Shifted => "shifted",
+        }
+    }
+}
+
+pub struct Difference {
+    pub kind: Kind,
+    pub golden: Option<Frame>,
+    pub run: Option<Frame>,
+}
+
+impl Difference {
+    /// How much later the run's frame came than the golden one, in microseconds.
+    fn shift(&self) -> Option<i64> {
+        match (&self.golden, &self.run) {
+            (Some(golden), Some(run)) => Some(run.offset as i64 - golden.offset as i64),
+            _ => None,
+        }
+    }
+}
+
+/// The differences in one bridge and direction.
+pub struct Stream {
+    pub bridge: String,
+    pub direction: String,
+    /// "frame" or "ccsds packet"
+    pub unit: &'static str,
+    pub golden_count: usize,
+    pub run_count: usize,
+    pub differences: Vec<Difference>,
+}
+
+pub struct Report {
+    pub golden: String,
+    pub run: String,
+    pub tolerance: Duration,
+    pub streams: Vec<Stream>,
+}
+
+/// Compare the capture `run` with the capture `golden`.
+pub fn compare(golden: &str, run: &str, tolerance: Duration) -> io::Result<Report> {
+    let golden_streams = streams(capture::read_capture(golden)?);
+    let mut run_streams = streams(capture::read_capture(run)?);
+    let mut streams = Vec::new();
+    for (key, golden_records) in golden_streams {
+        let run_records = run_streams.remove(&key).unwrap_or_default();
+        streams.push(compare_stream(
+            key,
+            &golden_records,
+            &run_records,
+            tolerance,
+        ));
+    }
+    for (key, run_records) in run_streams {
+        streams.push(compare_stream(key, &[], &run_records, tolerance));
+    }
+    Ok(Report {
+        golden: golden.to_string(),
+        run: run.to_string(),
+        tolerance,
+        streams,
+    })
+}
+
+/// Each bridge and direction's records, with their times made relative to the start of the run.
+fn streams(mut records: Vec<Record>) -> BTreeMap<(String, String), Vec<Record>> {
+    let start = records.iter().map(|record| record.time).min().unwrap_or(0);
+    let mut streams: BTreeMap<(String, String), Vec<Record>> = BTreeMap::new();
+    for mut record in records.drain(..) {
+        record.time -= start;
+        let direction = record
+            .direction
+            .map(|direction| direction.to_string())
+            .unwrap_or_else(|| "?".to_string());
+        streams
+            .entry((record.bridge.clone(), direction))
+            .or_default()
+            .push(record);
+    }
+    streams
+}
+
+fn compare_stream(
+    (bridge, direction): (String, String),
+    golden: &[Record],
+    run: &[Record],
+    tolerance: Duration,
+) -> Stream {
+    // Both runs must split into packets for the packets to be compared. Only UART and UDP
+    // bridges carry packets.
+    let carries_packets = golden.iter().chain(run).all(|record| match record.link {
+        Some(Link::Uart) | Some(Link::Udp) => true,
+        _ => false,
+    });
+    let packets = match (packets(golden), packets(run)) {
+        (Some(golden), Some(run)) if carries_packets && !(golden.is_empty() && run.is_empty()) => {
+            Some((golden, run))
+        }
+        _ => None,
+    };
+    let (unit, golden, run) = match packets {
+        Some((golden, run)) => ("ccsds packet", golden, run),
+        None => ("frame", frames(golden), frames(run)),
+    };
+
+    let golden_keys: Vec<&[u8]> = golden.iter().map(|frame| &frame.key[..]).collect();
+    let run_keys: Vec<&[u8]> = run.iter().map(|frame| &frame.key[..]).collect();
+    let mut differences = Vec::new();
+    let (mut g, mut r) = (0, 0);
+    let mut common = align(&golden_keys, &run_keys);
+    common.push((golden.len(), run.len()));
+    for (next_g, next_r) in common {
+        // Frames left over before the next aligned pair, paired up as changed where both runs
+        // have one. Packets left over have different APIDs, so are never paired.
+        while g < next_g || r < next_r {
+            let (kind, golden, run) = match (g < next_g, r < next_r) {
+                (true, true) if unit == "frame" => {
+                    (Kind::Changed, Some(golden[g].clone()), Some(run[r].clone()))
+                }
+                (true, _) => (Kind::Removed, Some(golden[g].clone()), None),
+                _ => (Kind::Inserted, None, Some(run[r].clone())),
+            };
+            g += golden.is_some() as usize;
+            r += run.is_some() as usize;
+            differences.push(Difference { kind, golden, run });
+        }
+        if next_g == golden.len() {
+            break;
+        }
+        let difference = Difference {
+            kind: Kind::Changed,
+            golden: Some(golden[g].clone()),
+            run: Some(run[r].clone()),
+        };
+        let shifted = difference
+            .shift()
+            .map_or(false, |shift| shift.abs() as u128 > tolerance.as_micros());
+        if golden[g].content != run[r].content {
+            differences.push(difference);
+        } else if shifted {
+            differences.push(Difference {
+                kind: Kind::Shifted,
+                ..difference
+            });
+        }
+        g += 1;
+        r += 1;
+    }
+
+    Stream {
+        bridge,
+        direction,
+        unit,
+        golden_count: golden.len(),
+        run_count: run.len(),
+        differences,
+    }
+}
+
+/// Each record as a frame, aligned and compared by its bytes.
+fn frames(records: &[Record]) -> Vec<Frame> {
+    records
+        .iter()
+        .enumerate()
+        .map(|(index, record)| Frame {
+            index,
+            offset: record.time,
+            label: record.address.clone(),
+            data: record.data.clone(),
+            key: record.data.clone(),
+            content: record.data.clone(),
+        })
+        .collect()
+}
+
+/// The space packets in the records, if all of their bytes split into packets. Packets are
+/// aligned by their type and APID, and compared without their sequence count.
+fn packets(records: &[Record]) -> Option<Vec<Frame>> {
+    let mut packets = Vec::new();
+    let mut bytes: Vec<u8> = Vec::new();
+    // When the packet at the start of `bytes` began
+    let mut offset = 0;
+    for record in records {
+        if bytes.is_empty() {
+            offset = record.time;
+        }
+        bytes.extend_from_slice(&record.data);
+        while let Some(header) = PrimaryHeader::parse(&bytes) {
+            let length = header.packet_length();
+            if bytes.len() < length {
+                break;
+            }
+            let data: Vec<u8> = bytes.drain(..length).collect();
+            let mut content = data.clone();
+            content[2] &= 0xC0;
+            content[3] = 0;
+            packets.push(Frame {
+                index: packets.len(),
+                offset,
+                label: Some(header.to_string()),
+                key: data[..2].to_vec(),
+                content,
+                data,
+            });
+            offset = record.time;
+        }
+        if bytes.len() >= HEADER_LEN && PrimaryHeader::parse(&bytes).is_none() {
+            return None;
+        }
+    }
+    if bytes.is_empty() {
+        Some(packets)
+    } else {
+        None
+    }
+}
+
+/// The longest common subsequence of `a` and `b`, as pairs of indices in order, by Myers' diff
+/// algorithm, which takes time in proportion to the number of differences. This is the
+/// linear-space version: rather than keep every round to trace the path back, it finds the middle
+/// snake of the path and divides the problem there.
+fn align(a: &[&[u8]], b: &[&[u8]]) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    align_within(a, b, (0, a.len()), (0, b.len()), &mut pairs);
+    pairs
+}
+
+/// Add the aligned pairs of `a[a_range]` and `b[b_range]` to `pairs`.
+fn align_within(
+    a: &[&[u8]],
+    b: &[&[u8]],
+    (mut a_start, mut a_end): (usize, usize),
+    (mut b_start, mut b_end): (usize, usize),
+    pairs: &mut Vec<(usize, usize)>,
+) {
+    while a_start < a_end && b_start < b_end && a[a_start] == b[b_start] {
+        pairs.push((a_start, b_start));
+        a_start += 1;
+        b_start += 1;
+    }
+    let mut suffix = 0;
+    while a_start < a_end - suffix
+        && b_start < b_end - suffix
+        && a[a_end - suffix - 1] == b[b_end - suffix - 1]
+    {
+        suffix += 1;
+    }
+    a_end -= suffix;
+    b_end -= suffix;
+    if a_start < a_end && b_start < b_end {
+        let (x, y, u, v) = middle_snake(&a[a_start..a_end], &b[b_start..b_end]);
+        align_within(a, b, (a_start, a_start + x), (b_start, b_start + y), pairs);
+        pairs.extend((0..u - x).map(|i| (a_start + x + i, b_start + y + i)));
+        align_within(a, b, (a_start + u, a_end), (b_start + v, b_end), pairs);
+    }
+    pairs.extend((0..suffix).map(|i| (a_end + i, b_end + i)));
+}
+
+/// Find where the paths from each end of the edit graph of `a` and `b` meet, searching forwards
+/// from the start and backwards from the end in turn. Returns the start and end of the snake
+/// where they meet, as (x, y) and (u, v), which divides the shortest edit script in half.
+fn middle_snake(a: &[&[u8]], b: &[&[u8]]) -> (usize, usize, usize, usize) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    // The furthest x reached on each diagonal k = x - y, at index k + offset, going forwards, and
+    // on each diagonal k = delta + c, at index c + offset, going backwards
+    let offset = max + 1;
+    let mut forward = vec![0isize; 2 * offset as usize + 1];
+    let mut backward = vec![0isize; 2 * offset as usize + 1];
+    backward[(offset + 1) as usize] = n + 1;
+    for d in 0..=max {
+        let mut k = -d;
+        while k <= d {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && forward[i - 1] < forward[i + 1]) {
+                forward[i + 1]
+            } else {
+                forward[i - 1] + 1
+            };
+            let mut y = x - k;
+            let start = (x, y);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[i] = x;
+            let c = k - delta;
+            if odd && c > -d && c < d && x >= backward[(c + offset) as usize] {
+                return (start.0 as usize, start.1 as usize, x as usize, y as usize);
+            }
+            k += 2;
+        }
+        let mut c = -d;
+        while c <= d {
+            let i = (c + offset) as usize;
+            let k = c + delta;
+            let mut x = if c == -d || (c != d && backward[i + 1] <= backward[i - 1]) {
+                backward[i + 1] - 1
+            } else {
+                backward[i - 1]
+            };
+            let mut y = x - k;
+            let end = (x, y);
+            while x > 0 && y > 0 && a[x as usize - 1] == b[y as usize - 1] {
+                x -= 1;
+                y -= 1;
+            }
+            backward[i] = x;
+            if !odd && k >= -d && k <= d && x <= forward[(k + offset) as usize] {
+                return (x as usize, y as usize, end.0 as usize, end.1 as usize);
+            }
+            c += 2;
+        }
+    }
+    unreachable!("the paths from each end always meet")
+}
+
+impl Report {
+    /// How many differences were found, in every bridge and direction.
+    pub fn differences(&self) -> usize {
+        self.streams
+            .iter()
+            .map(|stream| stream.differences.len())
+            .sum()
+    }
+
+    /// The report for people, one line per difference.
+    pub fn text(&self) -> String {
+        let mut text = format!(
+            "compare: {} => {}, tolerance {} ms\n",
+            self.golden,
+            self.run,
+            self.tolerance.as_millis()
+        );
+        for stream in &self.streams {
+            text += &format!(
+                "{} {} ({}s): {} golden, {} run, {} difference(s)\n",
+                stream.bridge,
+                stream.direction,
+                stream.unit,
+                stream.golden_count,
+                stream.run_count,
+                stream.differences.len()
+            );
+            for difference in &stream.differences {
+                let describe = |frame: &Frame, run: &str| {
+                    let mut bytes = capture::hex(&frame.data[..frame.data.len().min(TEXT_BYTES)]);
+                    if frame.data.len() > TEXT_BYTES {
+                        bytes += "...";
+                    }
+                    format!(
+                        "{} #{} at {:.3} s{} [{}]",
+                        run,
+                        frame.index,
+                        frame.offset as f64 / 1e6,
+                        frame
+                            .label
+                            .as_ref()
+                            .map(|label| format!(", {}", label))
+                            .unwrap_or_default(),
+                        bytes
+                    )
+                };
+                let frames = match (&difference.golden, &difference.run) {
+                    (Some(golden), Some(run)) => {
+                        format!("{} => {}", describe(golden, "golden"), describe(run, "run"))
+                    }
+                    (Some(golden), None) => describe(golden, "golden"),
+                    (None, Some(run)) => describe(run, "run"),
+                    (None, None) => String::new(),
+                };
+                text += &format!("  {:<8} {}", difference.kind.name(), frames);
+                if let Some(shift) = difference.shift() {
+                    text += &format!(", {:+.3} ms", shift as f64 / 1e3);
+                }
+                text += "\n";
+            }
+        }
+        text += &format!("{} difference(s)\n", self.differences());
+        text
+    }
+
+    /// The report for CI, with `passed` false if there were any differences.
+    pub fn json(&self) -> serde_json::Value {
+        let frame = |frame: &Option<Frame>| {
+            frame.as_ref().map(|frame| {
+                serde_json::json!({
+                    "index": frame.index,
+                    "offset_us": frame.offset,
+                    "label": frame.label,
+                    "data": capture::hex(&frame.data),
+                })
+            })
+        };
+        let streams: Vec<serde_json::Value> = self
+            .streams
+            .iter()
+            .map(|stream| {
+                let differences: Vec<serde_json::Value> = stream
+                    .differences
+                    .iter()
+                    .map(|difference| {
+                        serde_json::json!({
+                            "kind": difference.kind.name(),
+                            "golden": frame(&difference.golden),
+                            "run": frame(&difference.run),
+                            "shift_us": difference.shift(),
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "bridge": stream.bridge,
+                    "direction": stream.direction,
+                    "unit": stream.unit,
+                    "golden_count": stream.golden_count,
+                    "run_count": stream.run_count,
+                    "differences": differences,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "golden": self.golden,
+            "run": self.run,
+            "tolerance_us": self.tolerance.as_micros() as u64,
+            "differences": self.differences(),
+            "passed": self.differences() == 0,
+            "streams": streams,
+        })
+    }
+}
+
+/// Compare as the `compare` command does, from `[golden] [run] [tolerance_ms=N]`. Prints the
+/// text report, writes the JSON report next to the run, and returns the report.
+pub fn command(args: &[&str]) -> io::Result<Report> {
+    let (golden, run, tolerance) = parse_args(args)?;
+    let report = compare(golden, run, tolerance)?;
+    print!("{}", report.text());
+    let json = Path::new(run)
+        .with_extension("compare.json")
+        .to_string_lossy()
+        .into_owned();
+    std::fs::write(&json, serde_json::to_string_pretty(&report.json())? + "\n")?;
+    println!("<compare: wrote {}", json);
+    Ok(report)
+}
+
+/// `nos3_io compare [golden] [run] [tolerance_ms=N]`, for CI: prints the JSON report, and returns
+/// the exit code, 0 if the runs match, 1 if they differ and 2 if they could not be compared.
+pub fn main(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let report = parse_args(&args).and_then(|(golden, run, tolerance)| {
+        let report = compare(golden, run, tolerance)?;
+        println!("{}", serde_json::to_string_pretty(&report.json())?);
+        Ok(report)
+    });
+    match report {
+        Ok(report) if report.differences() == 0 => 0,
+        Ok(_) => 1,
+        Err(err) => {
+            eprintln!("compare: {}", err);
+            2
+        }
+    }
+}
+
+fn parse_args<'a>(args: &[&'a str]) -> io::Result<(&'a str, &'a str, Duration)> {
+    let usage = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: compare [golden.pcapng] [run.pcapng] [tolerance_ms=N]",
+        )
+    };
+    let (golden, run) = match (args.get(0), args.get(1)) {
+        (Some(golden), Some(run)) => (*golden, *run),
+        _ => return Err(usage()),
+    };
+    let tolerance = match args.get(2) {
+        None => DEFAULT_TOLERANCE,
+        Some(arg) if arg.starts_with("tolerance_ms=") => arg["tolerance_ms=".len()..]
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| usage())?,
+        Some(_) => return Err(usage()),
+    };
+    Ok((golden, run, tolerance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Direction;
+
+    /// Align sequences of single bytes.
+    fn align_bytes(a: &[u8], b: &[u8]) -> Vec<(usize, usize)> {
+        let a: Vec<&[u8]> = a.chunks(1).collect();
+        let b: Vec<&[u8]> = b.chunks(1).collect();
+        align(&a, &b)
+    }
+
+    /// Length of the longest common subsequence, the slow way.
+    fn lcs_length(a: &[u8], b: &[u8]) -> usize {
+        let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                lengths[i + 1][j + 1] = if a[i] == b[j] {
+                    lengths[i][j] + 1
+                } else {
+                    lengths[i][j + 1].max(lengths[i + 1][j])
+                };
+            }
+        }
+        lengths[a.len()][b.len()]
+    }
+
+    fn record(time: u64, data: &[u8]) -> Record {
+        Record {
+            time,
+            bridge: "uart 1".to_string(),
+            link: Some(Link::Uart),
+            direction: Some(Direction::HardwareToNos),
+            address: None,
+            operation: None,
+            i2c: None,
+            failed: false,
+            data: data.to_vec(),
+        }
+    }
+
+    /// A telemetry packet with the given APID and sequence count, and one byte of data.
+    fn packet(apid: u8, sequence: u8, data: u8) -> Vec<u8> {
+        vec![0x08, apid, 0xC0, sequence, 0x00, 0x00, data]
+    }
+
+    #[test]
+    fn align_pairs_what_is_left_unchanged() {
+        assert_eq!(align_bytes(b"", b""), []);
+        assert_eq!(align_bytes(b"abc", b""), []);
+        assert_eq!(align_bytes(b"abc", b"abc"), [(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(align_bytes(b"abc", b"axc"), [(0, 0), (2, 2)]);
+        assert_eq!(align_bytes(b"abc", b"abxc"), [(0, 0), (1, 1), (2, 3)]);
+        assert_eq!(align_bytes(b"xabc", b"abc"), [(1, 0), (2, 1), (3, 2)]);
+    }
+
+    #[test]
+    fn align_finds_a_longest_common_subsequence() {
+        // Small sequences over a small alphabet, so that there are many ways to align them
+        let mut state = 1u32;
+        let mut next = |bound: u32| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) % bound
+        };
+        for _ in 0..2000 {
+            let a: Vec<u8> = (0..next(14)).map(|_| next(3) as u8).collect();
+            let b: Vec<u8> = (0..next(14)).map(|_| next(3) as u8).collect();
+            let pairs = align_bytes(&a, &b);
+            assert_eq!(pairs.len(), lcs_length(&a, &b), "{:?} {:?}", a, b);
+            for (i, &(x, y)) in pairs.iter().enumerate() {
+                assert_eq!(a[x], b[y], "{:?} {:?}", a, b);
+                if i > 0 {
+                    assert!(x > pairs[i - 1].0 && y > pairs[i - 1].1, "{:?} {:?}", a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packets_split_across_records_are_joined() {
+        let first = packet(0x10, 1, 0xAA);
+        let second = packet(0x11, 2, 0xBB);
+        let mut bytes = first.clone();
+        bytes.extend_from_slice(&second);
+        let records = [
+            record(5, &bytes[..3]),
+            record(9, &bytes[3..10]),
+            record(12, &bytes[10..]),
+        ];
+
+        let packets = packets(&records).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].data, first);
+        assert_eq!(packets[0].offset, 5);
+        assert_eq!(packets[0].key, [0x08, 0x10]);
+        assert_eq!(packets[1].data, second);
+        assert_eq!(packets[1].offset, 9);
+        assert_eq!(packets[1].index, 1);
+    }
+
+    #[test]
+    fn packets_are_compared_without_their_sequence_count() {
+        let golden = packets(&[record(0, &packet(0x10, 1, 0xAA))]).unwrap();
+        let run = packets(&[record(0, &packet(0x10, 7, 0xAA))]).unwrap();
+        assert_eq!(golden[0].content, run[0].content);
+        assert_ne!(golden[0].data, run[0].data);
+    }
+
+    #[test]
+    fn records_which_are_not_packets_have_none() {
+        // Version 7 is not a space packet
+        assert!(packets(&[record(0, &[0xE0, 0, 0, 0, 0, 0, 0])]).is_none());
+        // A packet cut short
+        assert!(packets(&[record(0, &packet(0x10, 1, 0xAA)[..5])]).is_none());
+        assert_eq!(packets(&[]).unwrap().len(), 0);
+    }
+}
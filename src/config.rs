@@ -0,0 +1,57 @@
+//! Helpers for reading bridge settings out of `config.toml`.
+//!
+//! A bad config is a programming error in the same way a bad `include_str!` path is, so these
+//! panic with a message naming the offending section and key rather than returning errors.
+
+use serde::de::DeserializeOwned;
+use toml::value::Table;
+
+/// Remove the table for one kind of bridge (e.g. `[can.x]`) from the config, and return each of
+/// its named entries. Returns nothing if the config has no bridges of that kind.
+pub fn sections(config: &mut Table, kind: &str) -> Vec<(String, Table)> {
+    let table = match config.remove(kind) {
+        Some(table) => table,
+        None => return Vec::new(),
+    };
+    let table = table
+        .try_into::<Table>()
+        .unwrap_or_else(|err| panic!("Error parsing config.toml: [{}] {}", kind, err));
+    table
+        .into_iter()
+        .map(|(name, entry)| {
+            let entry = entry.try_into::<Table>().unwrap_or_else(|err| {
+                panic!("Error parsing config.toml: [{}.{}] {}", kind, name, err)
+            });
+            (name, entry)
+        })
+        .collect()
+}
+
+/// Remove a section which is a single table (e.g. `[capture]`) from the config, if it has one.
+pub fn section(config: &mut Table, name: &str) -> Option<Table> {
+    config.remove(name).map(|table| {
+        table
+            .try_into::<Table>()
+            .unwrap_or_else(|err| panic!("Error parsing config.toml: [{}] {}", name, err))
+    })
+}
+
+/// Remove a setting which must be present.
+pub fn required<T: DeserializeOwned>(table: &mut Table, section: &str, key: &str) -> T {
+    match optional(table, section, key) {
+        Some(value) => value,
+        None => panic!(
+            "Error parsing config.toml: [{}] is missing '{}'",
+            section, key
+        ),
+    }
+}
+
+/// Remove a setting which may be left out.
+pub fn optional<T: DeserializeOwned>(table: &mut Table, section: &str, key: &str) -> Option<T> {
+    table.remove(key).map(|value| {
+        value.try_into::<T>().unwrap_or_else(|err| {
+            panic!("Error parsing config.toml: [{}] '{}' {}", section, key, err)
+        })
+    })
+}
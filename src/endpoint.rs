@@ -0,0 +1,696 @@
+//! The local end of a UART bridge, which the NOS UART is connected to.
+//!
+//! An endpoint is a byte stream which also gives the descriptors to wait on for bytes to read,
+//! so that a bridge can wait on all of its endpoints at once, for up to `POLL_INTERVAL` before it
+//! checks the NOS side again. Reads give up after `POLL_INTERVAL` as well, in case they are made
+//! before anything is ready.
+
+use crate::config;
+use crate::rfc2217::Telnet;
+use crate::status::BridgeStatus;
+use nix::fcntl::{self, FcntlArg, OFlag};
+use nix::poll::{self, PollFd, PollFlags};
+use nix::pty;
+use nix::sys::termios::{self, FlushArg, SetArg};
+use nix::unistd;
+use serial::prelude::*;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::symlink;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use toml::value::Table;
+
+const CHAR_SIZE: serial::CharSize = serial::Bits8;
+const PARITY: serial::Parity = serial::ParityNone;
+const STOP_BITS: serial::StopBits = serial::Stop1;
+const FLOW_CONTROL: serial::FlowControl = serial::FlowNone;
+/// How long to wait for bytes before checking the NOS side, and any partial frames
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait for a terminal server to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait for a TCP peer to take bytes before giving up on it
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Unread bytes on a pty's slave side past which frames are dropped rather than queued, as nothing
+/// is keeping up with them. The same as the size of the kernel's line discipline buffer.
+const PTY_BACKLOG: usize = 4096;
+
+pub trait Endpoint: Read + Write + Send {
+    /// The descriptors which become readable when there is something to read. With none, as while
+    /// a TCP endpoint reconnects, the endpoint is read every time the bridge checks.
+    fn poll_fds(&self) -> Vec<RawFd>;
+}
+
+impl Endpoint for serial::SystemPort {
+    fn poll_fds(&self) -> Vec<RawFd> {
+        vec![self.as_raw_fd()]
+    }
+}
+
+#[derive(Clone)]
+pub enum EndpointConfig {
+    /// A serial device, e.g. `/dev/ttyUSB0`.
+    Serial(String),
+    /// A new pseudo-terminal, whose slave side is linked to from `link` if it is set.
+    Pty { link: Option<String> },
+    /// A TCP server, which takes one client at a time. A new client replaces the last one.
+    TcpListen { address: String, rfc2217: bool },
+    /// A TCP client of e.g. a terminal server, which reconnects whenever the connection is lost.
+    TcpConnect {
+        address: String,
+        reconnect: Duration,
+        /// Use RFC 2217, and ask the server for this baud rate
+        rfc2217: Option<u32>,
+    },
+    /// A file which bytes from the NOS UART are appended to. Nothing is ever read from it.
+    File(String),
+}
+
+impl EndpointConfig {
+    pub fn from_table(section: &str, table: &mut Table) -> EndpointConfig {
+        let endpoint = config::optional::<String>(table, section, "endpoint")
+            .unwrap_or_else(|| "serial".to_string());
+        match endpoint.as_str() {
+            "serial" => EndpointConfig::Serial(config::required(table, section, "serial_port")),
+            "pty" => EndpointConfig::Pty {
+                link: config::optional(table, section, "pty_link"),
+            },
+            "tcp_listen" => EndpointConfig::TcpListen {
+                address: config::required(table, section, "listen"),
+                rfc2217: config::optional(table, section, "rfc2217").unwrap_or(false),
+            },
+            "tcp_connect" => {
+                let address = config::required(table, section, "connect");
+                let reconnect = config::optional(table, section, "reconnect_ms").unwrap_or(1000);
+                let baud_rate = config::optional(table, section, "baud_rate").unwrap_or(9600);
+                let rfc2217 = config::optional(table, section, "rfc2217").unwrap_or(false);
+                EndpointConfig::TcpConnect {
+                    address,
+                    reconnect: Duration::from_millis(reconnect),
+                    rfc2217: if rfc2217 { Some(baud_rate) } else { None },
+                }
+            }
+            "file" => EndpointConfig::File(config::required(table, section, "path")),
+            endpoint => panic!(
+                "Error parsing config.toml: [{}] unknown 'endpoint' {}",
+                section, endpoint
+            ),
+        }
+    }
+
+    /// Open the endpoint. A TCP endpoint counts each connection after its first on `status`.
+    pub fn open(&self, status: &Arc<BridgeStatus>) -> io::Result<Box<dyn Endpoint>> {
+        match *self {
+            EndpointConfig::Serial(ref path) => {
+                let mut port = serial::open(path)?;
+                port.set_timeout(POLL_INTERVAL)?;
+                let settings = serial::PortSettings {
+                    baud_rate: serial::BaudRate::Baud9600,
+                    char_size: CHAR_SIZE,
+                    parity: PARITY,
+                    stop_bits: STOP_BITS,
+                    flow_control: FLOW_CONTROL,
+                };
+                port.configure(&settings)?;
+                Ok(Box::new(port))
+            }
+            EndpointConfig::Pty { ref link } => {
+                Ok(Box::new(Pty::open(link.as_ref(), status.clone())?))
+            }
+            EndpointConfig::TcpListen {
+                ref address,
+                rfc2217,
+            } => {
+                let listener = TcpListener::bind(address)?;
+                listener.set_nonblocking(true)?;
+                println!("<uart: tcp => listening on {}", listener.local_addr()?);
+                Ok(Box::new(Tcp::new(
+                    Peer::Listen(listener),
+                    rfc2217,
+                    status.clone(),
+                )))
+            }
+            EndpointConfig::TcpConnect {
+                ref address,
+                reconnect,
+                rfc2217,
+            } => {
+                let peer = Peer::Connect {
+                    address: address.clone(),
+                    reconnect,
+                    baud_rate: rfc2217,
+                    next_attempt: Instant::now(),
+                    attempt: None,
+                };
+                Ok(Box::new(Tcp::new(peer, rfc2217.is_some(), status.clone())))
+            }
+            EndpointConfig::File(ref path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(Box::new(Capture(file)))
+            }
+        }
+    }
+
+    /// Whether bytes can ever be read from the endpoint.
+    pub fn readable(&self) -> bool {
+        match *self {
+            EndpointConfig::File(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for EndpointConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EndpointConfig::Serial(ref path) => write!(f, "serial_port '{}'", path),
+            EndpointConfig::Pty {
+                link: Some(ref link),
+            } => write!(f, "pty linked from '{}'", link),
+            EndpointConfig::Pty { link: None } => write!(f, "pty"),
+            EndpointConfig::TcpListen { ref address, .. } => {
+                write!(f, "tcp server on '{}'", address)
+            }
+            EndpointConfig::TcpConnect { ref address, .. } => {
+                write!(f, "tcp connection to '{}'", address)
+            }
+            EndpointConfig::File(ref path) => write!(f, "file '{}'", path),
+        }
+    }
+}
+
+/// A pseudo-terminal, which the bridge holds the master side of. Other programs open the slave
+/// side as they would a serial port.
+///
+/// Frames are written whole or not at all. While the program on the slave side is not reading,
+/// or there is none, frames are dropped once `PTY_BACKLOG` bytes are waiting for it or the kernel
+/// has no room for them, and counted as "pty frames dropped". If the kernel takes only part of a
+/// frame, the rest is written as soon as there is room, before anything else.
+pub struct Pty {
+    master: File,
+    /// Held open so that the master can be read while nothing else has the slave open
+    slave: File,
+    link: Option<PathBuf>,
+    /// The part of the last frame the kernel has not taken yet
+    pending: Vec<u8>,
+    status: Arc<BridgeStatus>,
+}
+
+impl Pty {
+    fn open(link: Option<&String>, status: Arc<BridgeStatus>) -> io::Result<Pty> {
+        let pair = pty::openpty(None, None)?;
+        // Owned from here on, so that they are closed if anything below fails
+        let master = unsafe { File::from_raw_fd(pair.master) };
+        let slave = unsafe { File::from_raw_fd(pair.slave) };
+
+        // Pass bytes through untouched, as a serial port would
+        let mut attrs = termios::tcgetattr(slave.as_raw_fd())?;
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &attrs)?;
+        termios::tcflush(slave.as_raw_fd(), FlushArg::TCIFLUSH)?;
+        // Nothing may be reading the slave side, and the bridge must not wait for it to
+        fcntl::fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+        let path = unistd::ttyname(slave.as_raw_fd())?;
+        println!("<uart: pty => {}", path.display());
+        let link = match link {
+            Some(link) => {
+                let link = PathBuf::from(link);
+                // Left over from an earlier run, or another bridge
+                if fs::symlink_metadata(&link).is_ok() {
+                    fs::remove_file(&link)?;
+                }
+                symlink(&path, &link)?;
+                println!("<uart: pty => linked from {}", link.display());
+                Some(link)
+            }
+            None => None,
+        };
+
+        Ok(Pty {
+            master,
+            slave,
+            link,
+            pending: Vec::new(),
+            status,
+        })
+    }
+
+    /// Bytes written to the master which the slave side has not read yet.
+    fn unread(&self) -> io::Result<usize> {
+        let mut unread: libc::c_int = 0;
+        if unsafe { libc::ioctl(self.slave.as_raw_fd(), libc::FIONREAD, &mut unread) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unread as usize)
+    }
+
+    /// Write as much of the rest of the last frame as the kernel takes. Returns whether it has
+    /// all been written.
+    fn write_pending(&mut self) -> io::Result<bool> {
+        while !self.pending.is_empty() {
+            match self.master.write(&self.pending) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        let mut fds = [PollFd::new(self.master.as_raw_fd(), PollFlags::POLLIN)];
+        if poll::poll(&mut fds, POLL_INTERVAL.as_millis() as i32)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no bytes from the pty",
+            ));
+        }
+        self.master.read(buf)
+    }
+}
+
+impl Write for Pty {
+    /// Write one frame, or drop it if there is no room. Either way all of `buf` is taken.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let unread = self.unread()?;
+        if !self.write_pending()? || (unread > 0 && unread + buf.len() > PTY_BACKLOG) {
+            self.status.count("pty frames dropped", 1);
+            return Ok(buf.len());
+        }
+        match self.master.write(buf) {
+            Ok(n) => self.pending.extend_from_slice(&buf[n..]),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                self.status.count("pty frames dropped", 1);
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+impl Endpoint for Pty {
+    fn poll_fds(&self) -> Vec<RawFd> {
+        vec![self.master.as_raw_fd()]
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(ref link) = self.link {
+            let _ = fs::remove_file(link);
+        }
+    }
+}
+
+/// Where a TCP endpoint's connection comes from.
+enum Peer {
+    Listen(TcpListener),
+    Connect {
+        address: String,
+        reconnect: Duration,
+        /// Set if RFC 2217 is in use
+        baud_rate: Option<u32>,
+        next_attempt: Instant,
+        /// The connection being made on a thread of its own, so that looking up the address and
+        /// waiting for the server never hold up the bridge
+        attempt: Option<Receiver<io::Result<TcpStream>>>,
+    },
+}
+
+/// A TCP connection, which may come and go. Bytes from the NOS UART are dropped while there is no
+/// connection.
+pub struct Tcp {
+    peer: Peer,
+    rfc2217: bool,
+    stream: Option<(TcpStream, Option<Telnet>)>,
+    status: Arc<BridgeStatus>,
+    /// Set once there has been a connection, so that later ones count as reconnects
+    connected_before: bool,
+}
+
+impl Tcp {
+    fn new(peer: Peer, rfc2217: bool, status: Arc<BridgeStatus>) -> Tcp {
+        Tcp {
+            peer,
+            rfc2217,
+            stream: None,
+            status,
+            connected_before: false,
+        }
+    }
+
+    /// Take a new client, or reconnect to the server if it is time to.
+    fn connect(&mut self) -> io::Result<()> {
+        let (stream, telnet) = match self.peer {
+            Peer::Listen(ref listener) => match listener.accept() {
+                Ok((stream, address)) => {
+                    if self.stream.is_some() {
+                        println!("<uart: tcp => {} replaces the last client", address);
+                    } else {
+                        println!("<uart: tcp => {} connected", address);
+                    }
+                    stream.set_nonblocking(false)?;
+                    (
+                        stream,
+                        if self.rfc2217 {
+                            Some(Telnet::server())
+                        } else {
+                            None
+                        },
+                    )
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            },
+            Peer::Connect {
+                ref address,
+                reconnect,
+                baud_rate,
+                ref mut next_attempt,
+                ref mut attempt,
+            } => {
+                if self.stream.is_some() {
+                    return Ok(());
+                }
+                if attempt.is_none() {
+                    if Instant::now() < *next_attempt {
+                        return Ok(());
+                    }
+                    *next_attempt = Instant::now() + reconnect;
+                    *attempt = Some(connect_in_background(address.clone()));
+                }
+                let stream = match attempt.as_ref().map(Receiver::try_recv) {
+                    Some(Ok(stream)) => stream,
+                    Some(Err(TryRecvError::Empty)) => return Ok(()),
+                    _ => Err(io::Error::new(
+                        ErrorKind::Other,
+                        "connecting thread stopped",
+                    )),
+                };
+                *attempt = None;
+                let stream = stream?;
+                println!("<uart: tcp => connected to {}", address);
+                (stream, baud_rate.map(Telnet::client))
+            }
+        };
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let mut stream = (stream, telnet);
+        if let Some(ref mut telnet) = stream.1 {
+            stream.0.write_all(&telnet.start())?;
+        }
+        self.stream = Some(stream);
+        if self.connected_before {
+            self.status.reconnect();
+        }
+        self.connected_before = true;
+        Ok(())
+    }
+
+    fn disconnected(&mut self, reason: &str) {
+        println!("<uart: tcp => {}", reason);
+        self.stream = None;
+    }
+}
+
+/// Connect to the first of the addresses `address` resolves to which accepts, on a new thread.
+fn connect_in_background(address: String) -> Receiver<io::Result<TcpStream>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stream = Err(io::Error::new(ErrorKind::NotFound, "no address"));
+        match address.to_socket_addrs() {
+            Ok(addresses) => {
+                for address in addresses {
+                    stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT);
+                    if stream.is_ok() {
+                        break;
+                    }
+                }
+            }
+            Err(err) => stream = Err(err),
+        }
+        // The endpoint may have been closed since
+        let _ = sender.send(stream);
+    });
+    receiver
+}
+
+impl Read for Tcp {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Err(err) = self.connect() {
+            println!("<uart: tcp => error connecting: {}", err);
+        }
+        let result = match self.stream {
+            Some((ref mut stream, _)) => stream.read(buf),
+            None => return Err(io::Error::new(ErrorKind::TimedOut, "not connected")),
+        };
+        let n = match result {
+            Ok(0) => {
+                self.disconnected("connection closed");
+                return Err(io::Error::new(ErrorKind::TimedOut, "not connected"));
+            }
+            Ok(n) => n,
+            Err(ref err)
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+            {
+                return Err(io::Error::new(ErrorKind::TimedOut, "no bytes from tcp"));
+            }
+            Err(err) => {
+                self.disconnected(&format!("connection lost: {}", err));
+                return Err(err);
+            }
+        };
+        if let Some((ref mut stream, Some(ref mut telnet))) = self.stream {
+            let (n, reply) = telnet.decode(&mut buf[..n]);
+            if !reply.is_empty() {
+                stream.write_all(&reply)?;
+            }
+            return Ok(n);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Tcp {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = match self.stream {
+            Some((ref mut stream, Some(ref telnet))) => stream.write_all(&telnet.encode(buf)),
+            Some((ref mut stream, None)) => stream.write_all(buf),
+            None => Ok(()),
+        };
+        if let Err(err) = result {
+            self.disconnected(&format!("connection lost: {}", err));
+            return Err(err);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Endpoint for Tcp {
+    /// The connection, and for a server, the listener, which is readable when a new client
+    /// connects.
+    fn poll_fds(&self) -> Vec<RawFd> {
+        let mut fds = Vec::new();
+        if let Some((ref stream, _)) = self.stream {
+            fds.push(stream.as_raw_fd());
+        }
+        if let Peer::Listen(ref listener) = self.peer {
+            fds.push(listener.as_raw_fd());
+        }
+        fds
+    }
+}
+
+/// A file which only records bytes.
+pub struct Capture(File);
+
+impl Read for Capture {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(ErrorKind::TimedOut, "files are not read"))
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Endpoint for Capture {
+    fn poll_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+    use std::process;
+
+    #[test]
+    fn bytes_pass_both_ways_through_a_pty() {
+        let link = env::temp_dir()
+            .join(format!("nos3_io_{}_pty", process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let config = EndpointConfig::Pty {
+            link: Some(link.clone()),
+        };
+        let mut endpoint = config.open(&Arc::new(BridgeStatus::new())).unwrap();
+        assert_eq!(endpoint.poll_fds().len(), 1);
+        // What another program would do with the link
+        let mut tty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&link)
+            .unwrap();
+
+        tty.write_all(b"PING\r\n").unwrap();
+        let mut read = Vec::new();
+        let mut buf = [0; 16];
+        for _ in 0..100 {
+            match endpoint.read(&mut buf) {
+                Ok(n) => read.extend_from_slice(&buf[..n]),
+                Err(ref err) if err.kind() == ErrorKind::TimedOut => (),
+                Err(err) => panic!("reading the pty: {}", err),
+            }
+            if read.len() >= 6 {
+                break;
+            }
+        }
+        // Raw, so nothing is translated
+        assert_eq!(read, b"PING\r\n");
+
+        endpoint.write_all(b"PONG\n").unwrap();
+        let mut buf = [0; 5];
+        tty.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"PONG\n");
+
+        drop(endpoint);
+        assert!(fs::symlink_metadata(Path::new(&link)).is_err());
+    }
+
+    #[test]
+    fn a_pty_nobody_reads_drops_whole_frames() {
+        let link = env::temp_dir()
+            .join(format!("nos3_io_{}_pty_unread", process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let status = Arc::new(BridgeStatus::new());
+        let mut endpoint = EndpointConfig::Pty {
+            link: Some(link.clone()),
+        }
+        .open(&status)
+        .unwrap();
+
+        // Far more than the kernel holds, with nothing reading
+        let frames: Vec<Vec<u8>> = (0..1000u32)
+            .map(|i| format!("{:0>99}\n", i).into_bytes())
+            .collect();
+        for frame in &frames {
+            endpoint.write_all(frame).unwrap();
+        }
+        assert!(status.to_string().contains("pty frames dropped"));
+
+        // Whoever opens it later finds only whole frames, in order, once the bridge has written
+        // the rest of any it could only write part of
+        let mut tty = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&link)
+            .unwrap();
+        let mut read = Vec::new();
+        let mut buf = [0; 1024];
+        let mut idle = 0;
+        while idle < 3 {
+            idle += 1;
+            while let Ok(n) = tty.read(&mut buf) {
+                read.extend_from_slice(&buf[..n]);
+                idle = 0;
+            }
+            let _ = endpoint.read(&mut buf);
+        }
+        assert!(!read.is_empty());
+        assert_eq!(read.len() % 100, 0);
+        let mut last = None;
+        for frame in read.chunks(100) {
+            let i: usize = String::from_utf8_lossy(&frame[..99]).parse().unwrap();
+            assert_eq!(frame, &frames[i][..]);
+            assert!(last.map_or(true, |last| i > last));
+            last = Some(i);
+        }
+    }
+
+    #[test]
+    fn a_tcp_client_connects_without_holding_up_reads() {
+        let status = Arc::new(BridgeStatus::new());
+        // Nothing answers there, so each attempt waits out the connect timeout
+        let mut unreachable = EndpointConfig::TcpConnect {
+            address: String::from("10.255.255.1:9"),
+            reconnect: Duration::from_millis(10),
+            rfc2217: None,
+        }
+        .open(&status)
+        .unwrap();
+        let started = Instant::now();
+        for _ in 0..5 {
+            let mut buf = [0; 16];
+            assert!(unreachable.read(&mut buf).is_err());
+        }
+        assert!(started.elapsed() < CONNECT_TIMEOUT);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut endpoint = EndpointConfig::TcpConnect {
+            address: listener.local_addr().unwrap().to_string(),
+            reconnect: Duration::from_millis(10),
+            rfc2217: None,
+        }
+        .open(&status)
+        .unwrap();
+        let mut buf = [0; 16];
+        assert!(endpoint.read(&mut buf).is_err());
+        let (mut server, _) = listener.accept().unwrap();
+        server.write_all(b"PING").unwrap();
+        let mut read = Vec::new();
+        for _ in 0..100 {
+            match endpoint.read(&mut buf) {
+                Ok(n) => read.extend_from_slice(&buf[..n]),
+                // Still connecting
+                Err(_) => thread::sleep(POLL_INTERVAL),
+            }
+            if read.len() >= 4 {
+                break;
+            }
+        }
+        assert_eq!(read, b"PING");
+    }
+}
@@ -0,0 +1,474 @@
+//! Splitting a byte stream into frames, for bridges whose buses carry streams.
+//!
+//! Frames are forwarded whole and unchanged: framing only decides where each one ends, so that a
+//! frame is never split across writes or merged with the next one. Delimiters, length prefixes
+//! and SLIP or COBS encoding all stay in the frame. CCSDS framing is the exception, in that bytes
+//! outside of a valid space packet are dropped (see `ccsds.rs`).
+
+use crate::ccsds::{self, CcsdsConfig, PrimaryHeader};
+use crate::config;
+use crate::status::BridgeStatus;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use toml::value::{Table, Value};
+
+/// SLIP frame delimiter
+const SLIP_END: u8 = 0xC0;
+/// COBS frame delimiter
+const COBS_END: u8 = 0x00;
+/// Largest length-prefixed frame unless `max_frame` is set, so that a corrupt length field is not
+/// waited on for up to 4 GiB
+pub const DEFAULT_MAX_LENGTH_PREFIXED: usize = 4096;
+
+/// Where one frame ends and the next begins.
+#[derive(Clone)]
+pub enum Framing {
+    /// Whatever has arrived is a frame.
+    Raw,
+    /// A frame ends when no bytes arrive for the given gap.
+    Idle(Duration),
+    /// A frame ends with the given bytes.
+    Delimiter(Vec<u8>),
+    /// Every frame has the given number of bytes.
+    Fixed(usize),
+    /// A frame starts with its length, and ends after that many more bytes.
+    LengthPrefixed {
+        /// Size of the length field, from 1 to 4 bytes
+        bytes: usize,
+        big_endian: bool,
+        /// Added to the length field to give the number of bytes after it
+        adjust: i64,
+    },
+    /// SLIP (RFC 1055), where frames end with 0xC0.
+    Slip,
+    /// COBS, where frames end with 0x00.
+    Cobs,
+    /// CCSDS space packets, each of which is a frame.
+    Ccsds(CcsdsConfig),
+}
+
+#[derive(Clone)]
+pub struct FramingConfig {
+    pub framing: Framing,
+    /// A frame which reaches this size is sent as it is, complete or not
+    pub max_frame: Option<usize>,
+    /// A partial frame which has waited this long is sent as it is
+    pub flush_timeout: Option<Duration>,
+}
+
+impl FramingConfig {
+    /// Read the framing settings of a bridge, for bytes from the hardware and then for bytes from
+    /// NOS. Both use `framing` if it is set. If it is not, bytes from the hardware default to
+    /// 100 ms idle-gap framing, and bytes from NOS are passed on as they come rather than held
+    /// back for the gap.
+    pub fn from_table(section: &str, table: &mut Table) -> (FramingConfig, FramingConfig) {
+        let explicit = table.contains_key("framing");
+        let framing = config::optional::<String>(table, section, "framing")
+            .unwrap_or_else(|| "idle".to_string());
+        let framing = match framing.as_str() {
+            "raw" => Framing::Raw,
+            "idle" => Framing::Idle(Duration::from_millis(
+                config::optional(table, section, "idle_gap_ms").unwrap_or(100),
+            )),
+            "delimiter" => {
+                // Either a string, e.g. "\r\n", or bytes, e.g. [0x0D, 0x0A]
+                let delimiter = match config::required::<Value>(table, section, "delimiter") {
+                    Value::String(delimiter) => delimiter.into_bytes(),
+                    delimiter => delimiter.try_into().unwrap_or_else(|err| {
+                        panic!(
+                            "Error parsing config.toml: [{}] 'delimiter' {}",
+                            section, err
+                        )
+                    }),
+                };
+                if delimiter.is_empty() {
+                    panic!(
+                        "Error parsing config.toml: [{}] 'delimiter' must not be empty",
+                        section
+                    );
+                }
+                Framing::Delimiter(delimiter)
+            }
+            "fixed" => {
+                let length = config::required(table, section, "frame_length");
+                if length == 0 {
+                    panic!(
+                        "Error parsing config.toml: [{}] 'frame_length' must be at least 1",
+                        section
+                    );
+                }
+                Framing::Fixed(length)
+            }
+            "length_prefixed" => {
+                let bytes = config::optional(table, section, "length_bytes").unwrap_or(1);
+                if bytes < 1 || bytes > 4 {
+                    panic!(
+                        "Error parsing config.toml: [{}] 'length_bytes' must be 1-4, not {}",
+                        section, bytes
+                    );
+                }
+                Framing::LengthPrefixed {
+                    bytes,
+                    big_endian: config::optional(table, section, "length_big_endian")
+                        .unwrap_or(true),
+                    adjust: config::optional(table, section, "length_adjust").unwrap_or(0),
+                }
+            }
+            "slip" => Framing::Slip,
+            "cobs" => Framing::Cobs,
+            "ccsds" => Framing::Ccsds(CcsdsConfig::from_table(section, table)),
+            framing => panic!(
+                "Error parsing config.toml: [{}] unknown 'framing' {}",
+                section, framing
+            ),
+        };
+
+        let max_frame = config::optional(table, section, "max_frame");
+        let max_frame = match framing {
+            // Raw frames are only split by a cap, so there is nothing to reject
+            Framing::Raw => max_frame,
+            _ if max_frame == Some(0) => panic!(
+                "Error parsing config.toml: [{}] 'max_frame' must be at least 1",
+                section
+            ),
+            Framing::Ccsds(_) => max_frame.or(Some(ccsds::DEFAULT_MAX_PACKET)),
+            Framing::LengthPrefixed { .. } => max_frame.or(Some(DEFAULT_MAX_LENGTH_PREFIXED)),
+            _ => max_frame,
+        };
+
+        let incoming = FramingConfig {
+            framing,
+            max_frame,
+            flush_timeout: config::optional::<u64>(table, section, "flush_timeout_ms")
+                .map(Duration::from_millis),
+        };
+        let outgoing = if explicit {
+            incoming.clone()
+        } else {
+            FramingConfig {
+                framing: Framing::Raw,
+                max_frame: incoming.max_frame,
+                flush_timeout: None,
+            }
+        };
+        (incoming, outgoing)
+    }
+}
+
+/// Collects bytes from one direction of a stream, and hands them back as frames.
+pub struct Framer {
+    config: FramingConfig,
+    buffer: Vec<u8>,
+    /// When the first byte of the partial frame arrived
+    started: Instant,
+    /// When the last byte arrived
+    last: Instant,
+    /// Where dropped and malformed packets are counted
+    status: Arc<BridgeStatus>,
+}
+
+impl Framer {
+    pub fn new(config: FramingConfig, status: Arc<BridgeStatus>) -> Framer {
+        let now = Instant::now();
+        Framer {
+            config,
+            buffer: Vec::new(),
+            started: now,
+            last: now,
+            status,
+        }
+    }
+
+    /// Add bytes which have arrived, and return every frame they complete.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut frames = Vec::new();
+        if data.is_empty() {
+            return frames;
+        }
+        self.last = now;
+        if let Framing::Raw = self.config.framing {
+            match self.config.max_frame {
+                Some(max) if max > 0 => frames.extend(data.chunks(max).map(|c| c.to_vec())),
+                _ => frames.push(data.to_vec()),
+            }
+            return frames;
+        }
+        if let Framing::Ccsds(ref ccsds) = self.config.framing {
+            if self.buffer.is_empty() {
+                self.started = now;
+            }
+            self.buffer.extend_from_slice(data);
+            let (packets, dropped, malformed) =
+                split_packets(&mut self.buffer, ccsds, self.config.max_frame);
+            self.status.count("ccsds packets", packets.len());
+            self.status.count("ccsds bytes dropped", dropped);
+            self.status.count("ccsds packets malformed", malformed);
+            if !self.buffer.is_empty() && dropped + malformed + packets.len() > 0 {
+                self.started = now;
+            }
+            return packets;
+        }
+
+        for byte in data {
+            if self.buffer.is_empty() {
+                self.started = now;
+            }
+            self.buffer.push(*byte);
+            let complete = match self.config.framing {
+                Framing::Raw | Framing::Idle(_) | Framing::Ccsds(_) => false,
+                Framing::Delimiter(ref delimiter) => self.buffer.ends_with(delimiter),
+                Framing::Fixed(length) => self.buffer.len() >= length,
+                Framing::LengthPrefixed {
+                    bytes,
+                    big_endian,
+                    adjust,
+                } => {
+                    self.buffer.len() >= bytes && {
+                        let field = &self.buffer[..bytes];
+                        let length = if big_endian {
+                            field.iter().fold(0i64, |n, b| n << 8 | i64::from(*b))
+                        } else {
+                            field.iter().rev().fold(0i64, |n, b| n << 8 | i64::from(*b))
+                        };
+                        self.buffer.len() as i64 >= bytes as i64 + (length + adjust).max(0)
+                    }
+                }
+                // A delimiter at the start of a frame only separates it from the last one
+                Framing::Slip => *byte == SLIP_END && self.buffer.len() > 1,
+                Framing::Cobs => *byte == COBS_END && self.buffer.len() > 1,
+            };
+            let full = self
+                .config
+                .max_frame
+                .map_or(false, |max| self.buffer.len() >= max);
+            if complete || full {
+                frames.push(self.buffer.split_off(0));
+            }
+        }
+        frames
+    }
+
+    /// Return the partial frame if it has waited long enough to be sent as it is: for idle-gap
+    /// framing, once no bytes have arrived for the gap, and otherwise after the flush timeout. A
+    /// partial space packet is never sent: it is dropped after the flush timeout instead.
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        let idle = match self.config.framing {
+            Framing::Idle(gap) => now.duration_since(self.last) >= gap,
+            _ => false,
+        };
+        let timed_out = self
+            .config
+            .flush_timeout
+            .map_or(false, |timeout| now.duration_since(self.started) >= timeout);
+        if let Framing::Ccsds(_) = self.config.framing {
+            if timed_out {
+                self.status.count("ccsds bytes dropped", self.buffer.len());
+                self.buffer.clear();
+            }
+            None
+        } else if idle || timed_out {
+            Some(self.buffer.split_off(0))
+        } else {
+            None
+        }
+    }
+}
+
+/// Take every whole space packet from the start of `buffer`, leaving any partial one. Returns the
+/// packets which passed the check, the number of bytes dropped while looking for a header, and the
+/// number of packets which failed the check.
+fn split_packets(
+    buffer: &mut Vec<u8>,
+    ccsds: &CcsdsConfig,
+    max_frame: Option<usize>,
+) -> (Vec<Vec<u8>>, usize, usize) {
+    let mut packets = Vec::new();
+    let mut start = 0;
+    let mut dropped = 0;
+    let mut malformed = 0;
+    while buffer.len() - start >= ccsds::HEADER_LEN {
+        let length = match PrimaryHeader::parse(&buffer[start..]) {
+            Some(ref header)
+                if ccsds.accepts(header)
+                    && max_frame.map_or(true, |max| header.packet_length() <= max) =>
+            {
+                header.packet_length()
+            }
+            _ => {
+                start += 1;
+                dropped += 1;
+                continue;
+            }
+        };
+        if buffer.len() - start < length {
+            break;
+        }
+        let packet = &buffer[start..start + length];
+        if ccsds.check.verify(packet) {
+            packets.push(packet.to_vec());
+            start += length;
+        } else {
+            // The header may have been a false one, so look for the next from the byte after it
+            malformed += 1;
+            start += 1;
+            dropped += 1;
+        }
+    }
+    buffer.drain(..start);
+    (packets, dropped, malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framer(framing: Framing, max_frame: Option<usize>) -> Framer {
+        let config = FramingConfig {
+            framing,
+            max_frame,
+            flush_timeout: None,
+        };
+        Framer::new(config, Arc::new(BridgeStatus::new()))
+    }
+
+    /// Push each read in turn, and return every frame completed.
+    fn frames(framer: &mut Framer, reads: &[&[u8]]) -> Vec<Vec<u8>> {
+        reads.iter().flat_map(|read| framer.push(read)).collect()
+    }
+
+    /// The framing for bytes from the hardware, and for bytes from NOS.
+    fn configs(text: &str) -> (FramingConfig, FramingConfig) {
+        let mut table = text.parse::<Value>().unwrap().try_into::<Table>().unwrap();
+        FramingConfig::from_table("uart.test", &mut table)
+    }
+
+    fn config(text: &str) -> FramingConfig {
+        configs(text).0
+    }
+
+    #[test]
+    fn delimited_frames_keep_their_delimiter() {
+        let mut framer = framer(Framing::Delimiter(b"\r\n".to_vec()), None);
+        let frames = frames(&mut framer, &[b"ab\r", b"\ncd\r\nef"]);
+        assert_eq!(frames, [b"ab\r\n".to_vec(), b"cd\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn fixed_and_length_prefixed_frames() {
+        let mut fixed = framer(Framing::Fixed(3), None);
+        assert_eq!(frames(&mut fixed, &[b"abcde", b"f"]), [b"abc", b"def"]);
+
+        let prefixed = Framing::LengthPrefixed {
+            bytes: 2,
+            big_endian: false,
+            adjust: 1,
+        };
+        let mut prefixed = framer(prefixed, None);
+        let frames = frames(
+            &mut prefixed,
+            &[&[0x01, 0x00, 0xAA], &[0xBB, 0x00, 0x00, 0xCC]],
+        );
+        assert_eq!(
+            frames,
+            [vec![0x01, 0x00, 0xAA, 0xBB], vec![0x00, 0x00, 0xCC]]
+        );
+    }
+
+    #[test]
+    fn a_leading_slip_end_does_not_end_a_frame() {
+        let mut framer = framer(Framing::Slip, None);
+        let frames = frames(&mut framer, &[&[SLIP_END, 1, 2], &[SLIP_END, 3, SLIP_END]]);
+        assert_eq!(frames, [vec![SLIP_END, 1, 2, SLIP_END], vec![3, SLIP_END]]);
+    }
+
+    #[test]
+    fn max_frame_splits_frames() {
+        let mut raw = framer(Framing::Raw, Some(2));
+        assert_eq!(frames(&mut raw, &[b"abcde"]), [&b"ab"[..], b"cd", b"e"]);
+        let mut delimited = framer(Framing::Delimiter(b"\n".to_vec()), Some(3));
+        assert_eq!(frames(&mut delimited, &[b"abcd\n"]), [&b"abc"[..], b"d\n"]);
+    }
+
+    #[test]
+    fn idle_frames_end_with_the_gap() {
+        let mut framer = framer(Framing::Idle(Duration::from_millis(0)), None);
+        assert!(framer.push(b"abc").is_empty());
+        assert_eq!(framer.poll(), Some(b"abc".to_vec()));
+        assert_eq!(framer.poll(), None);
+    }
+
+    #[test]
+    fn ccsds_frames_are_whole_packets() {
+        let ccsds = CcsdsConfig {
+            check: ccsds::Check::None,
+            apids: None,
+            log: false,
+        };
+        let mut framer = framer(Framing::Ccsds(ccsds), None);
+        let packet = [0x08, 0x42, 0xC0, 0x01, 0x00, 0x01, 0xAA, 0xBB];
+        // A byte of noise, then the packet in two reads
+        let frames = frames(&mut framer, &[&[0xFF, 0x08, 0x42, 0xC0], &packet[3..]]);
+        assert_eq!(frames, [packet.to_vec()]);
+        assert_eq!(framer.poll(), None);
+    }
+
+    #[test]
+    fn ccsds_framing_skips_huge_headers_by_default() {
+        let config = config("framing = \"ccsds\"");
+        assert_eq!(config.max_frame, Some(ccsds::DEFAULT_MAX_PACKET));
+        let mut framer = Framer::new(config, Arc::new(BridgeStatus::new()));
+        // A false header claiming 64 KiB, then a real packet
+        let packet = [0x08, 0x42, 0xC0, 0x01, 0x00, 0x00, 0xAA];
+        let mut bytes = vec![0x08, 0x00, 0xC0, 0x00, 0xFF, 0xFF];
+        bytes.extend_from_slice(&packet);
+        assert_eq!(framer.push(&bytes), [packet.to_vec()]);
+
+        let config = self::config("framing = \"ccsds\"\nmax_frame = 100000");
+        assert_eq!(config.max_frame, Some(100_000));
+    }
+
+    #[test]
+    fn bytes_from_nos_are_only_framed_if_framing_is_set() {
+        let (incoming, outgoing) = configs("");
+        assert!(match incoming.framing {
+            Framing::Idle(gap) => gap == Duration::from_millis(100),
+            _ => false,
+        });
+        assert!(match outgoing.framing {
+            Framing::Raw => true,
+            _ => false,
+        });
+
+        let (incoming, outgoing) = configs("framing = \"delimiter\"\ndelimiter = \"\\n\"");
+        for config in &[incoming, outgoing] {
+            assert!(match config.framing {
+                Framing::Delimiter(ref delimiter) => delimiter == b"\n",
+                _ => false,
+            });
+        }
+    }
+
+    #[test]
+    fn length_prefixed_frames_are_capped_by_default() {
+        let prefixed = config("framing = \"length_prefixed\"\nlength_bytes = 4");
+        assert_eq!(prefixed.max_frame, Some(DEFAULT_MAX_LENGTH_PREFIXED));
+        assert_eq!(config("framing = \"raw\"").max_frame, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "'frame_length' must be at least 1")]
+    fn a_fixed_frame_length_of_0_is_rejected() {
+        config("framing = \"fixed\"\nframe_length = 0");
+    }
+
+    #[test]
+    #[should_panic(expected = "'max_frame' must be at least 1")]
+    fn a_max_frame_of_0_is_rejected() {
+        config("framing = \"slip\"\nmax_frame = 0");
+    }
+}
@@ -0,0 +1,564 @@
+//! Bridges devices on a Linux I2C adapter to a NOS I2C bus.
+//!
+//! Each device in a bridge appears on the NOS bus as an `I2CSlave` at its `nos_addr`, and is
+//! reached on the adapter at its `hw_addr`. One thread serves every device in the bridge, and the
+//! adapter itself is shared with any other bridges on it through the `BusManager`, so transfers to
+//! different devices never overlap. A bridge with a single device can give its addresses as
+//! `nos_slave_addr` and `slave_address` instead of a `devices` table. Addresses are 7-bit, unless
+//! given as `{ ten_bit = 0x250 }`.
+//!
+//! By default, every read and write from the simulated master is passed to the device unchanged,
+//! as an `I2C_RDWR` message sequence, so no assumptions are made about command bytes or lengths.
+//! NOSEngine delivers a master's `transaction` as a write followed by a read, with nothing to mark
+//! them as one, so they are paired by timing (see `pairing.rs`): a write followed within
+//! `transaction_window_us` by a read from the same device goes to it with the read as one
+//! sequence with a repeated start.
+//!
+//! With `mode = "register"`, a device is instead treated as a map of registers behind a register
+//! pointer `register_width` bits wide. Each write from the master starts with the pointer,
+//! optionally followed by values to store from there, and each read returns the registers from
+//! the pointer onwards. Either way the pointer then moves past the registers accessed. If the
+//! device does not advance its own pointer (`auto_increment = false`), each register is accessed
+//! in its own transfer. Registers listed in `cached_registers` are read from the device only once.
+//!
+//! A transfer which fails with a NACK, lost arbitration or a timeout is tried again up to
+//! `retries` times, `retry_delay_ms` apart. If it still fails, a read from the simulated master is
+//! answered with nothing, as if the device had not acknowledged, or with `fill_byte`s if
+//! `on_failure = "fill"`. Writes are acknowledged to the simulated master as soon as they arrive,
+//! since they may be waiting to combine with a read, so a failed write is only counted and logged,
+//! unless `transaction_window_us = 0`. With `stop_on_error = true`, the bridge stops after the
+//! first failure.
+
+use crate::bus::{BusManager, I2CAdapter};
+use crate::capture::{Capture, Direction};
+use crate::config;
+use crate::pairing::{self, Transfer};
+use crate::status::BridgeStatus;
+use i2c_linux::{I2c, Message, ReadFlags, WriteFlags};
+use nosengine_rust::client::i2c::{I2CAddress, I2CSlave};
+use nosengine_rust::ffi::i2c::I2CDirection;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use toml::value::{Table, Value};
+
+pub struct I2CConfig {
+    pub device_path: String,
+    pub nos_bus: String,
+    pub transaction_window: Duration,
+    pub errors: ErrorPolicy,
+    pub devices: Vec<DeviceConfig>,
+    /// Record to the capture file, if there is one
+    pub capture: bool,
+    /// Session file to record to, for the `replay` command
+    pub record: Option<String>,
+}
+
+/// What the bridge does when a transfer with the hardware fails.
+pub struct ErrorPolicy {
+    /// How many times to try a transfer again after a NACK, lost arbitration or timeout
+    pub retries: u32,
+    pub retry_delay: Duration,
+    /// Byte to answer a failed read with, or `None` to answer with nothing, like a NACK
+    pub fill: Option<u8>,
+    pub stop_on_error: bool,
+}
+
+pub struct DeviceConfig {
+    /// Address of the simulated device on the NOS bus
+    pub nos_addr: I2CAddress,
+    /// Address of the real device on the adapter
+    pub hw_addr: I2CAddress,
+    pub mode: Mode,
+}
+
+/// How reads and writes from the master are passed to the device.
+pub enum Mode {
+    /// As-is, combining a write and a following read into one transfer
+    Passthrough,
+    /// As register accesses, see the module documentation
+    Register(RegisterMap),
+}
+
+pub struct RegisterMap {
+    /// Size of the register pointer in bytes, sent most significant byte first
+    pub width: usize,
+    /// Whether the device advances its register pointer after each register accessed
+    pub auto_increment: bool,
+    /// Read-only registers, such as ID registers, which only need to be read once
+    pub cached: HashSet<u16>,
+}
+
+impl RegisterMap {
+    fn encode(&self, register: u16) -> Vec<u8> {
+        register.to_be_bytes()[2 - self.width..].to_vec()
+    }
+
+    fn decode(&self, data: &[u8]) -> u16 {
+        data[..self.width]
+            .iter()
+            .fold(0, |register, byte| (register << 8) | u16::from(*byte))
+    }
+
+    /// The register `offset` registers after `register`, wrapping around the pointer width
+    fn offset(&self, register: u16, offset: usize) -> u16 {
+        let next = register.wrapping_add(offset as u16);
+        if self.width == 1 {
+            next & 0xFF
+        } else {
+            next
+        }
+    }
+}
+
+impl I2CConfig {
+    pub fn from_table(name: &str, mut table: Table) -> I2CConfig {
+        let section = format!("i2c.{}", name);
+        let device_path = config::required(&mut table, &section, "device_path");
+        let nos_bus = config::required(&mut table, &section, "nos_bus");
+        let transaction_window = Duration::from_micros(
+            config::optional(&mut table, &section, "transaction_window_us").unwrap_or(1000),
+        );
+
+        let errors = ErrorPolicy {
+            retries: config::optional(&mut table, &section, "retries").unwrap_or(0),
+            retry_delay: Duration::from_millis(
+                config::optional(&mut table, &section, "retry_delay_ms").unwrap_or(1),
+            ),
+            fill: match config::optional::<String>(&mut table, &section, "on_failure").as_ref() {
+                None => None,
+                Some(response) if response == "nack" => None,
+                Some(response) if response == "fill" => {
+                    Some(config::optional(&mut table, &section, "fill_byte").unwrap_or(0xFF))
+                }
+                Some(response) => panic!(
+                    "Error parsing config.toml: [{}] 'on_failure' must be nack or fill, not {}",
+                    section, response
+                ),
+            },
+            stop_on_error: config::optional(&mut table, &section, "stop_on_error").unwrap_or(false),
+        };
+
+        // e.g. devices = [{ nos_addr = 8, hw_addr = 0x2B }, { nos_addr = 0x68, hw_addr = 0x68 }]
+        let devices = match config::optional::<Vec<Table>>(&mut table, &section, "devices") {
+            Some(devices) => devices
+                .into_iter()
+                .map(|mut device| {
+                    let nos_addr = address(&mut device, &section, "nos_addr");
+                    let hw_addr = address(&mut device, &section, "hw_addr");
+                    DeviceConfig::from_table(&section, nos_addr, hw_addr, &mut device)
+                })
+                .collect(),
+            None => {
+                let nos_addr = address(&mut table, &section, "nos_slave_addr");
+                let hw_addr = address(&mut table, &section, "slave_address");
+                vec![DeviceConfig::from_table(
+                    &section, nos_addr, hw_addr, &mut table,
+                )]
+            }
+        };
+
+        let capture = config::optional(&mut table, &section, "capture").unwrap_or(true);
+        let record = config::optional(&mut table, &section, "record");
+
+        I2CConfig {
+            device_path,
+            nos_bus,
+            transaction_window,
+            errors,
+            devices,
+            capture,
+            record,
+        }
+    }
+}
+
+impl DeviceConfig {
+    fn from_table(
+        section: &str,
+        nos_addr: I2CAddress,
+        hw_addr: I2CAddress,
+        table: &mut Table,
+    ) -> DeviceConfig {
+        let mode = match config::optional::<String>(table, section, "mode").as_ref() {
+            None => Mode::Passthrough,
+            Some(mode) if mode == "passthrough" => Mode::Passthrough,
+            Some(mode) if mode == "register" => {
+                let width = match config::optional::<u8>(table, section, "register_width") {
+                    None | Some(8) => 1,
+                    Some(16) => 2,
+                    Some(width) => panic!(
+                        "Error parsing config.toml: [{}] 'register_width' must be 8 or 16, not {}",
+                        section, width
+                    ),
+                };
+                let auto_increment =
+                    config::optional(table, section, "auto_increment").unwrap_or(true);
+                let cached = config::optional::<Vec<u16>>(table, section, "cached_registers")
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect();
+                Mode::Register(RegisterMap {
+                    width,
+                    auto_increment,
+                    cached,
+                })
+            }
+            Some(mode) => panic!(
+                "Error parsing config.toml: [{}] 'mode' must be passthrough or register, not {}",
+                section, mode
+            ),
+        };
+
+        DeviceConfig {
+            nos_addr,
+            hw_addr,
+            mode,
+        }
+    }
+}
+
+/// Read an address, which is either a number for a 7-bit address, or `{ ten_bit = 0x250 }` for a
+/// 10-bit address.
+pub fn address(table: &mut Table, section: &str, key: &str) -> I2CAddress {
+    let address = match config::required::<Value>(table, section, key) {
+        Value::Table(mut address) => {
+            I2CAddress::TenBit(config::required(&mut address, section, "ten_bit"))
+        }
+        address => I2CAddress::SevenBit(address.try_into().unwrap_or_else(|err| {
+            panic!("Error parsing config.toml: [{}] '{}' {}", section, key, err)
+        })),
+    };
+    address
+        .validate()
+        .unwrap_or_else(|err| panic!("Error parsing config.toml: [{}] '{}' {}", section, key, err))
+}
+
+pub fn i2c_init(
+    config: I2CConfig,
+    buses: Arc<BusManager>,
+    status: Arc<BridgeStatus>,
+    capture: Capture,
+) {
+    let adapter = match buses.i2c_adapter(&config.device_path) {
+        Ok(adapter) => adapter,
+        Err(err) => {
+            println!(
+                "Error opening the I2C device '{}', details: {}",
+                &config.device_path, err
+            );
+            return;
+        }
+    };
+
+    // Transfers are for devices by their index in the bridge
+    let (callbacks, mut pairing) = pairing::pairing(config.transaction_window);
+    let mut slaves = Vec::with_capacity(config.devices.len());
+    for (index, device) in config.devices.iter().enumerate() {
+        let callbacks = callbacks.slave(index);
+        let slave = I2CSlave::with_handler(
+            device.nos_addr,
+            crate::NOS_CONNECTION,
+            &config.nos_bus,
+            move |dir, data| match dir {
+                I2CDirection::Write => callbacks.write(data),
+                I2CDirection::Read => callbacks.read(data),
+            },
+        );
+        match slave {
+            Ok(slave) => slaves.push(slave),
+            Err(err) => {
+                println!(
+                    "<i2c: error => connecting address {} to NOS: {}",
+                    device.nos_addr, err
+                );
+                return;
+            }
+        }
+    }
+    drop(callbacks);
+    println!("Established I2C connection to NOS! Starting...");
+
+    let mut bridge = Bridge {
+        adapter,
+        devices: config.devices.iter().map(Device::new).collect(),
+        retry: Retry {
+            policy: &config.errors,
+            status: &status,
+            capture,
+        },
+    };
+
+    // Keep this thread working for the lifetime of the program
+    while let Some(next) = pairing.next() {
+        let result = match next {
+            Transfer::Write(device, data, ack) => {
+                let result = bridge.write(device, &data);
+                ack.send(result.is_ok());
+                result
+            }
+            Transfer::Read(device, tx, len, reply) => {
+                let received = Instant::now();
+                let result = bridge.read(device, &tx, len);
+                let response = match (&result, config.errors.fill) {
+                    (Ok(rx), _) => rx.clone(),
+                    (Err(_), Some(fill)) => vec![fill; len],
+                    (Err(_), None) => Vec::new(),
+                };
+                let _ = reply.send(response);
+                status.latency(received.elapsed());
+                result.map(|_| ())
+            }
+        };
+        if let Err(err) = result {
+            println!("<i2c: error => {}", err);
+            status.failure(&err);
+            if config.errors.stop_on_error {
+                println!("<i2c: stopping bridge on {}", &config.device_path);
+                return;
+            }
+        }
+    }
+}
+
+/// A device in a bridge, along with what the bridge tracks about it.
+struct Device<'a> {
+    config: &'a DeviceConfig,
+    /// Register pointer, in register mode
+    pointer: u16,
+    /// Values of cached registers which have been read, in register mode
+    cache: HashMap<u16, u8>,
+}
+
+impl<'a> Device<'a> {
+    fn new(config: &'a DeviceConfig) -> Device<'a> {
+        Device {
+            config,
+            pointer: 0,
+            cache: HashMap::new(),
+        }
+    }
+}
+
+struct Bridge<'a> {
+    adapter: I2CAdapter,
+    devices: Vec<Device<'a>>,
+    retry: Retry<'a>,
+}
+
+impl<'a> Bridge<'a> {
+    fn write(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        let device = &mut self.devices[index];
+        let address = device.config.hw_addr;
+        let map = match device.config.mode {
+            Mode::Passthrough => {
+                let mut i2c = self.adapter.lock().unwrap();
+                return self.retry.transfer(&mut i2c, address, data, 0).map(|_| ());
+            }
+            Mode::Register(ref map) => map,
+        };
+
+        if data.len() < map.width {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "write of {} bytes to {} has no register",
+                    data.len(),
+                    address
+                ),
+            ));
+        }
+        device.pointer = map.decode(data);
+        let values = &data[map.width..];
+        let mut i2c = self.adapter.lock().unwrap();
+        let retry = &self.retry;
+        let result = if map.auto_increment || values.len() <= 1 {
+            if values.is_empty() {
+                // Only moves the pointer, which is sent along with the next read
+                Ok(())
+            } else {
+                retry.transfer(&mut i2c, address, data, 0).map(|_| ())
+            }
+        } else {
+            let pointer = device.pointer;
+            values.iter().enumerate().try_for_each(|(i, value)| {
+                let mut tx = map.encode(map.offset(pointer, i));
+                tx.push(*value);
+                retry.transfer(&mut i2c, address, &tx, 0).map(|_| ())
+            })
+        };
+        device.pointer = map.offset(device.pointer, values.len());
+        result
+    }
+
+    /// Writes `tx`, if there is anything to write, then reads `len` bytes from a device.
+    fn read(&mut self, index: usize, tx: &[u8], len: usize) -> io::Result<Vec<u8>> {
+        if let Mode::Register(_) = self.devices[index].config.mode {
+            if !tx.is_empty() {
+                self.write(index, tx)?;
+            }
+        }
+        let device = &mut self.devices[index];
+        let address = device.config.hw_addr;
+        let mut i2c = self.adapter.lock().unwrap();
+        let retry = &self.retry;
+        match device.config.mode {
+            Mode::Passthrough => retry.transfer(&mut i2c, address, tx, len),
+            Mode::Register(ref map) => {
+                let pointer = device.pointer;
+                let registers: Vec<u16> = (0..len).map(|i| map.offset(pointer, i)).collect();
+                let cached: Option<Vec<u8>> = registers
+                    .iter()
+                    .map(|register| device.cache.get(register).cloned())
+                    .collect();
+                let rx = match cached {
+                    Some(rx) => rx,
+                    None if map.auto_increment => {
+                        retry.transfer(&mut i2c, address, &map.encode(pointer), len)?
+                    }
+                    None => registers
+                        .iter()
+                        .map(|register| {
+                            retry
+                                .transfer(&mut i2c, address, &map.encode(*register), 1)
+                                .map(|rx| rx[0])
+                        })
+                        .collect::<io::Result<_>>()?,
+                };
+                for (register, value) in registers.iter().zip(&rx) {
+                    if map.cached.contains(register) {
+                        device.cache.insert(*register, *value);
+                    }
+                }
+                device.pointer = map.offset(pointer, len);
+                Ok(rx)
+            }
+        }
+    }
+}
+
+/// Linux error numbers for failures which may not happen again: a NACK (`ENXIO`, or `EREMOTEIO`
+/// from some adapters), lost arbitration (`EAGAIN`) and a timeout (`ETIMEDOUT`).
+const RETRYABLE_ERRORS: [i32; 4] = [libc::ENXIO, libc::EREMOTEIO, libc::EAGAIN, libc::ETIMEDOUT];
+
+/// Applies the bridge's retry policy to transfers, and counts them in its status and records
+/// them in its capture, failed attempts included.
+struct Retry<'a> {
+    policy: &'a ErrorPolicy,
+    status: &'a BridgeStatus,
+    capture: Capture,
+}
+
+impl<'a> Retry<'a> {
+    fn transfer(
+        &self,
+        i2c: &mut I2c<File>,
+        address: I2CAddress,
+        tx: &[u8],
+        rx_len: usize,
+    ) -> io::Result<Vec<u8>> {
+        let mut attempts = 0;
+        loop {
+            let err = match transfer(i2c, address, tx, rx_len) {
+                Ok(rx) => {
+                    self.status.transfer();
+                    if !tx.is_empty() {
+                        self.capture
+                            .record_i2c(Direction::NosToHardware, address, false, tx);
+                    }
+                    if rx_len > 0 {
+                        self.capture
+                            .record_i2c(Direction::HardwareToNos, address, true, &rx);
+                    }
+                    return Ok(rx);
+                }
+                Err(err) => err,
+            };
+            if !tx.is_empty() {
+                self.capture
+                    .record_i2c_failure(Direction::NosToHardware, address, false, tx, &err);
+            }
+            if rx_len > 0 {
+                self.capture
+                    .record_i2c_failure(Direction::HardwareToNos, address, true, &[], &err);
+            }
+            let retryable = err
+                .raw_os_error()
+                .map_or(false, |errno| RETRYABLE_ERRORS.contains(&errno));
+            if !retryable || attempts >= self.policy.retries {
+                return Err(io::Error::new(
+                    err.kind(),
+                    DeviceError {
+                        address,
+                        source: err,
+                    },
+                ));
+            }
+            attempts += 1;
+            self.status.retry();
+            thread::sleep(self.policy.retry_delay);
+        }
+    }
+}
+
+/// A failed transfer, naming the device. The adapter's error is kept as its source, so that the
+/// failure is still counted by its error number.
+#[derive(Debug)]
+struct DeviceError {
+    address: I2CAddress,
+    source: io::Error,
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.address, self.source)
+    }
+}
+
+impl Error for DeviceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Writes `tx`, then reads `rx_len` bytes, with a repeated start rather than a stop in between.
+fn transfer(
+    i2c: &mut I2c<File>,
+    address: I2CAddress,
+    tx: &[u8],
+    rx_len: usize,
+) -> io::Result<Vec<u8>> {
+    let (write_flags, read_flags) = if address.is_ten_bit() {
+        (WriteFlags::TENBIT_ADDR, ReadFlags::TENBIT_ADDR)
+    } else {
+        (WriteFlags::empty(), ReadFlags::empty())
+    };
+    let address = address.raw();
+    let mut rx = vec![0u8; rx_len];
+    {
+        let mut messages = Vec::with_capacity(2);
+        if !tx.is_empty() {
+            messages.push(Message::Write {
+                address,
+                data: tx,
+                flags: write_flags,
+            });
+        }
+        if rx_len > 0 {
+            messages.push(Message::Read {
+                address,
+                data: &mut rx,
+                flags: read_flags,
+            });
+        }
+        if !messages.is_empty() {
+            i2c.i2c_transfer(&mut messages)?;
+        }
+    }
+    Ok(rx)
+}
@@ -0,0 +1,219 @@
+//! Pokes simulated components by hand, for the `send` and `i2c-xfer` commands.
+//!
+//! `send` hands bytes to a running UART bridge, which writes them to its NOS UART as if they had
+//! come from its endpoint, and passes back whatever NOS sends in the following
+//! `RESPONSE_WINDOW`. `i2c-xfer` writes to and reads from a device on a NOS I2C bus through a
+//! master of nos3_io's own, one per bus, kept for as long as nos3_io runs. I2C bridges are only
+//! ever slaves on their NOS buses, so they have no master for `i2c-xfer` to share. Each master
+//! has the address `DEFAULT_MASTER_ADDRESS`, unless the config gives its bus another:
+//!
+//! ```toml
+//! [i2c_xfer.i2c_bus]
+//! master_addr = 0x0F
+//! ```
+
+use crate::config;
+use crate::i2c;
+use nosengine_rust::client::i2c::{I2CAddress, I2CMaster};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use toml::value::Table;
+
+/// How long NOS's answer to injected bytes is waited for
+pub const RESPONSE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Address of nos3_io's master on a NOS I2C bus, unless the config gives another. SMBus sets
+/// 0x08 aside for the host, so no device is expected to use it.
+const DEFAULT_MASTER_ADDRESS: I2CAddress = I2CAddress::SevenBit(0x08);
+
+/// Bytes for a UART bridge to send to NOS, and where to pass NOS's answer.
+pub struct Injection {
+    pub data: Vec<u8>,
+    /// Sent each frame from NOS until `RESPONSE_WINDOW` has passed, then dropped
+    pub responses: Sender<Vec<u8>>,
+}
+
+/// Read the bytes to send, given as `hex:01ff..`, as quoted text with escapes such as `\r\n` and
+/// `\x1b`, or as `@file.bin`.
+pub fn parse_data(arg: &str) -> io::Result<Vec<u8>> {
+    let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidInput, what);
+    if arg.starts_with("hex:") {
+        parse_hex(&arg["hex:".len()..])
+            .ok_or_else(|| invalid(format!("'{}' must be bytes in hex, such as hex:01ff", arg)))
+    } else if arg.starts_with('@') {
+        fs::read(&arg[1..])
+    } else if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+        unescape(&arg[1..arg.len() - 1]).ok_or_else(|| invalid(format!("bad escape in {}", arg)))
+    } else {
+        Err(invalid(format!(
+            "'{}' must be hex:<bytes>, \"text\" or @file",
+            arg
+        )))
+    }
+}
+
+/// Bytes in hex, ignoring any `,` or `_` between them.
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: String = text.chars().filter(|c| *c != ',' && *c != '_').collect();
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next()? {
+            'r' => b'\r',
+            'n' => b'\n',
+            't' => b'\t',
+            '0' => 0,
+            '\\' => b'\\',
+            '"' => b'"',
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&hex, 16).ok()?
+            }
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+/// nos3_io's masters on NOS I2C buses, by bus.
+pub struct Masters {
+    masters: HashMap<String, I2CMaster<'static>>,
+    /// The address of the master on each bus which does not use the default
+    addresses: HashMap<String, I2CAddress>,
+}
+
+impl Masters {
+    /// Read each `[i2c_xfer.<bus>]` section from the config, for the master addresses.
+    pub fn from_config(config: &mut Table) -> Masters {
+        let addresses = config::sections(config, "i2c_xfer")
+            .into_iter()
+            .map(|(bus, mut table)| {
+                let section = format!("i2c_xfer.{}", bus);
+                let address = i2c::address(&mut table, &section, "master_addr");
+                (bus, address)
+            })
+            .collect();
+        Masters {
+            masters: HashMap::new(),
+            addresses,
+        }
+    }
+
+    /// The address of nos3_io's master on `bus`.
+    pub fn address(&self, bus: &str) -> I2CAddress {
+        self.addresses
+            .get(bus)
+            .copied()
+            .unwrap_or(DEFAULT_MASTER_ADDRESS)
+    }
+
+    /// Write `tx` to the device at `address` on `bus`, then read `rx_len` bytes from it, in one
+    /// transaction if there are both.
+    pub fn transfer(
+        &mut self,
+        bus: &str,
+        address: I2CAddress,
+        tx: &[u8],
+        rx_len: usize,
+    ) -> Result<Vec<u8>, String> {
+        if !self.masters.contains_key(bus) {
+            // The master borrows its bus name, and is kept until nos3_io exits
+            let name: &'static str = Box::leak(bus.to_string().into_boxed_str());
+            let master = I2CMaster::new(self.address(bus), crate::NOS_CONNECTION, name)
+                .map_err(|err| format!("creating a master on '{}': {}", bus, err))?;
+            self.masters.insert(bus.to_string(), master);
+        }
+        let master = &self.masters[bus];
+        let result = match (tx.is_empty(), rx_len) {
+            (false, 0) => master.write(address, tx).map(|_| Vec::new()),
+            (true, _) => master.read(address, rx_len),
+            (false, _) => master.transaction(address, tx, rx_len),
+        };
+        result.map_err(|err| err.to_string())
+    }
+}
+
+/// Read the arguments of `i2c-xfer`: `[address] w:<hex>` and/or `r:<n>`, with the address in
+/// hex as `0x48`, or in decimal, and a 10-bit address as `{ten_bit=0x250}`, as in the config.
+pub fn parse_xfer(args: &[&str]) -> Result<(I2CAddress, Vec<u8>, usize), String> {
+    let address = args.get(0).ok_or("no address")?;
+    let ten_bit = address
+        .strip_prefix("{ten_bit=")
+        .and_then(|raw| raw.strip_suffix('}'));
+    let raw = match ten_bit {
+        Some(raw) => parse_number(raw).map(I2CAddress::TenBit),
+        None => parse_number(address).map(I2CAddress::SevenBit),
+    };
+    let address = raw
+        .ok_or_else(|| format!("'{}' must be an I2C address", address))
+        .and_then(|address| address.validate().map_err(|err| err.to_string()))?;
+    let (mut tx, mut rx_len) = (Vec::new(), 0);
+    for arg in &args[1..] {
+        if arg.starts_with("w:") {
+            tx = parse_hex(&arg[2..]).ok_or_else(|| format!("'{}' must be w:<hex>", arg))?;
+        } else if arg.starts_with("r:") {
+            rx_len = arg[2..]
+                .parse()
+                .map_err(|_| format!("'{}' must be r:<bytes>", arg))?;
+        } else {
+            return Err(format!("unknown argument '{}'", arg));
+        }
+    }
+    if tx.is_empty() && rx_len == 0 {
+        return Err("nothing to write or read".to_string());
+    }
+    Ok((address, tx, rx_len))
+}
+
+/// A number in hex, as `0x48`, or in decimal.
+fn parse_number(text: &str) -> Option<u16> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u16::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xfer_addresses() {
+        let (address, tx, rx_len) = parse_xfer(&["0x48", "w:0010", "r:2"]).unwrap();
+        assert_eq!(address, I2CAddress::SevenBit(0x48));
+        assert_eq!(tx, [0x00, 0x10]);
+        assert_eq!(rx_len, 2);
+        let (address, _, _) = parse_xfer(&["72", "r:1"]).unwrap();
+        assert_eq!(address, I2CAddress::SevenBit(0x48));
+        let (address, _, _) = parse_xfer(&["{ten_bit=0x250}", "r:1"]).unwrap();
+        assert_eq!(address, I2CAddress::TenBit(0x250));
+        assert!(parse_xfer(&["0x7F", "r:1"]).is_err());
+        assert!(parse_xfer(&["{ten_bit=0x400}", "r:1"]).is_err());
+        assert!(parse_xfer(&["{seven_bit=0x48}", "r:1"]).is_err());
+        assert!(parse_xfer(&["0x48"]).is_err());
+    }
+
+    #[test]
+    fn the_default_master_address_is_valid() {
+        assert!(DEFAULT_MASTER_ADDRESS.validate().is_ok());
+    }
+}
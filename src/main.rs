@@ -1,44 +1,196 @@
 extern crate serial;
 
-use i2c_linux::I2c;
-use nosengine_rust::client::i2c::I2CMaster;
-use nosengine_rust::client::uart::*;
-use serial::prelude::*;
+mod bus;
+mod capture;
+mod compare;
+mod can;
+mod ccsds;
+mod config;
+mod endpoint;
+mod framing;
+mod i2c;
+mod inject;
+mod monitor;
+mod pairing;
+mod replay;
+mod rfc2217;
+mod spi;
+mod status;
+mod term;
+mod uart;
+mod udp;
+
+use bus::BusManager;
+use can::CANConfig;
+use capture::{Capture, CaptureFile, Link};
+use i2c::I2CConfig;
+use inject::{Injection, Masters};
+use monitor::Monitor;
 use serial::unix::TTYPort;
 use serial::SystemPort;
+use spi::SPIConfig;
+use status::BridgeStatus;
+use uart::UARTConfig;
+use udp::UDPConfig;
 use std::env;
 use std::io;
-use std::io::prelude::*;
-use std::io::ErrorKind;
 use std::path::Path;
+use std::process;
 use std::thread;
 use std::sync::mpsc::{self, TryRecvError, Sender, Receiver};
 use std::time::Duration;
 use toml;
 use toml::map::Map;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-const CHAR_SIZE: serial::CharSize = serial::Bits8;
-const PARITY: serial::Parity = serial::ParityNone;
-const STOP_BITS: serial::StopBits = serial::Stop1;
-const FLOW_CONTROL: serial::FlowControl = serial::FlowNone;
 const TIMEOUT: Duration = Duration::from_millis(60);
 
-struct I2CConfig {
-    device_path: String,
-    slave_address: u16,
-    nos_bus: String,
-    nos_slave_addr: u16,
+/// A bridge's config, taken by a replay running in the bridge's place and given back when it ends.
+enum Replayed {
+    Uart(String, UARTConfig),
+    I2c(String, I2CConfig),
+}
+/// What starting a bridge needs from its config.
+trait BridgeConfig {
+    /// Printed after the bridge's name as it starts
+    const HINT: &'static str = "";
+
+    /// Whether to record to the capture file, if there is one
+    fn capture(&self) -> bool;
+
+    /// Where to record this run of the bridge on its own, if anywhere
+    fn record(&self) -> Option<&String> {
+        None
+    }
+}
+
+impl BridgeConfig for UARTConfig {
+    const HINT: &'static str = " => if it hangs, restart NOS3 and nos3_io";
+
+    fn capture(&self) -> bool {
+        self.capture
+    }
+
+    fn record(&self) -> Option<&String> {
+        self.record.as_ref()
+    }
+}
+
+impl BridgeConfig for I2CConfig {
+    fn capture(&self) -> bool {
+        self.capture
+    }
+
+    fn record(&self) -> Option<&String> {
+        self.record.as_ref()
+    }
 }
 
-struct UARTConfig {
-    serial_port: String,
-    nos_bus: String,
+impl BridgeConfig for CANConfig {
+    fn capture(&self) -> bool {
+        self.capture
+    }
 }
 
+impl BridgeConfig for SPIConfig {
+    fn capture(&self) -> bool {
+        self.capture
+    }
+}
+
+impl BridgeConfig for UDPConfig {
+    fn capture(&self) -> bool {
+        self.capture
+    }
+}
+
+/// Bridges which have been started, and what every bridge is started with.
+struct Bridges {
+    /// Capture file which bridges record their traffic to
+    capture_file: Option<Arc<CaptureFile>>,
+    /// Where bridges show their traffic, for the monitor command
+    monitor: Arc<Monitor>,
+    /// Each started bridge's status, for the status command
+    statuses: Vec<(String, Arc<BridgeStatus>)>,
+}
+
+impl Bridges {
+    /// Start the `kind` bridges which `arg` names: every one not yet started for "all", or the
+    /// one with that name. `init` is given each bridge's name, config, status and capture, and
+    /// returns what to run on the bridge's thread.
+    fn start<C, F>(
+        &mut self,
+        kind: &str,
+        link: Link,
+        arg: Option<&str>,
+        configs: &mut HashMap<String, C>,
+        mut init: F,
+    ) where
+        C: BridgeConfig,
+        F: FnMut(&str, C, Arc<BridgeStatus>, Capture) -> Box<dyn FnOnce() + Send>,
+    {
+        let arg = match arg {
+            Some(arg) => arg.trim(),
+            None => {
+                println!("<help: '{} all', '{} [name]'", kind, kind);
+                return;
+            }
+        };
+        let started: Vec<(String, C)> = if arg == "all" {
+            configs.drain().collect()
+        } else {
+            configs.remove_entry(arg).into_iter().collect()
+        };
+        if started.is_empty() {
+            if arg == "all" {
+                println!("<{}: error => no {} configs are available", kind, kind);
+            } else {
+                println!("<{}: error => {} config not available", kind, kind);
+            }
+            return;
+        }
+        for (name, config) in started {
+            println!("<{}: starting {} {}{}", kind, kind, name, C::HINT);
+            let bridge = format!("{} {}", kind, name);
+            let status = Arc::new(BridgeStatus::new());
+            self.statuses.push((bridge.clone(), status.clone()));
+            let capture = capture::interface(
+                &self.capture_file,
+                config.capture(),
+                &bridge,
+                link,
+                &self.monitor,
+                &status,
+            );
+            let capture = capture::with_session(capture, config.record(), link);
+            let run = init(&name, config, status.clone(), capture);
+            thread::spawn(move || {
+                run();
+                status.stop();
+            });
+        }
+    }
+}
+
+// TODO: get nos connection string from config
+const NOS_CONNECTION: &str = "tcp://localhost:12000";
+
 fn main() {
+    // `nos3_io compare [golden] [run]`, for CI, and `nos3_io term --bus [nos bus]`
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("compare") => process::exit(compare::main(&args[2..])),
+        Some("term") => process::exit(term::main(&args[2..])),
+        _ => (),
+    }
+
     let mut i2cs: HashMap<String, I2CConfig> = HashMap::new(); 
     let mut uarts: HashMap<String, UARTConfig> = HashMap::new(); 
+    let mut cans: HashMap<String, CANConfig> = HashMap::new();
+    let mut spis: HashMap<String, SPIConfig> = HashMap::new();
+    let mut udps: HashMap<String, UDPConfig> = HashMap::new();
     
     // Initialize config
     let mut config = (include_str!("./config.toml"))
@@ -47,223 +199,421 @@ fn main() {
         .try_into::<toml::value::Table>()
         .unwrap();
 
-    let mut uart_table = config
-        .remove("uart")
-        .ok_or(std::io::Error::new(
-            ErrorKind::Other,
-            "Error parsing config.toml",
-        ))
-        .unwrap()
-        .try_into::<toml::value::Table>()
-        .unwrap();
-
-    let mut i2c_table = config
-        .remove("i2c")
-        .ok_or(std::io::Error::new(
-            ErrorKind::Other,
-            "Error parsing config.toml",
-        ))
-        .unwrap()
-        .try_into::<toml::value::Table>()
-        .unwrap();
-
     // Register all uart configurations
-    for entry in uart_table.iter() {
-        let name = entry.0;
-        let config = entry.1.clone();
-        let mut entry_table = config.try_into::<toml::value::Table>().unwrap();
-
-        let serial_port = entry_table
-            .remove("serial_port")
-            .ok_or(std::io::Error::new(
-                ErrorKind::Other,
-                "Error parsing config.toml",
-            ))
-            .unwrap()
-            .try_into::<String>()
-            .unwrap();
-        
-        let nos_bus = entry_table
-            .remove("nos_bus")
-            .ok_or(std::io::Error::new(
-                ErrorKind::Other,
-                "Error parsing config.toml",
-            ))
-            .unwrap()
-            .try_into::<String>()
-            .unwrap();
-        
+    for (name, table) in config::sections(&mut config, "uart") {
+        let config = UARTConfig::from_table(&name, table);
         println!(
-            "<config: UART {} => serial_port '{}', nos_bus '{}'",
-            &name, &serial_port, &nos_bus
+            "<config: UART {} => {}, nos_bus '{}'",
+            &name,
+            config.describe_endpoints(),
+            &config.nos_bus
         );
-
-        let config = UARTConfig {
-            serial_port,
-            nos_bus,
-        };
-
-        uarts.insert(name.to_string(), config);
+        uarts.insert(name, config);
     }
-    
-    // Register all i2c configurations
-    for entry in i2c_table.iter() {
-        let name = entry.0;
-        let config = entry.1.clone();
-        let mut entry_table = config.try_into::<toml::value::Table>().unwrap();
-
-        let device_path = entry_table
-            .remove("device_path")
-            .ok_or(std::io::Error::new(
-                ErrorKind::Other,
-                "Error parsing config.toml",
-            ))
-            .unwrap()
-            .try_into::<String>()
-            .unwrap();
-        let slave_address = entry_table
-            .remove("slave_address")
-            .ok_or(std::io::Error::new(
-                ErrorKind::Other,
-                "Error parsing config.toml",
-            ))
-            .unwrap()
-            .try_into::<u16>()
-            .unwrap();                     
 
-        let nos_bus = entry_table
-            .remove("nos_bus")
-            .ok_or(std::io::Error::new(
-                ErrorKind::Other,
-                "Error parsing config.toml",
-            ))
-            .unwrap()
-            .try_into::<String>()
-            .unwrap();       
+    // Register all i2c configurations
+    let mut nos_addrs = HashSet::new();
+    for (name, table) in config::sections(&mut config, "i2c") {
+        let config = I2CConfig::from_table(&name, table);
+        // Bridges may share a NOS bus, but not an address on it. NOS only sees the raw address,
+        // so a 7-bit and a 10-bit address with the same number collide
+        for device in &config.devices {
+            if !nos_addrs.insert((config.nos_bus.clone(), device.nos_addr.raw())) {
+                panic!(
+                    "Error parsing config.toml: [i2c.{}] NOS address {} is already used on '{}'",
+                    name, device.nos_addr, config.nos_bus
+                );
+            }
+        }
+        println!(
+            "<config: I2C {} => device_path '{}', nos_bus '{}', {} device(s)",
+            &name,
+            &config.device_path,
+            &config.nos_bus,
+            config.devices.len()
+        );
+        i2cs.insert(name, config);
+    }
 
-        let nos_slave_addr = entry_table
-            .remove("nos_slave_addr")
-            .ok_or(std::io::Error::new(
-                ErrorKind::Other,
-                "Error parsing config.toml",
-            ))
-            .unwrap()
-            .try_into::<u16>()
-            .unwrap();      
+    // Register all can configurations
+    for (name, table) in config::sections(&mut config, "can") {
+        let config = CANConfig::from_table(&name, table);
+        println!(
+            "<config: CAN {} => interface '{}', nos_bus '{}'",
+            &name, &config.interface, &config.nos_bus
+        );
+        cans.insert(name, config);
+    }
 
+    // Register all spi configurations
+    for (name, table) in config::sections(&mut config, "spi") {
+        let config = SPIConfig::from_table(&name, table);
         println!(
-            "<config: I2C {} => device_path '{}', slave_address '{}'",
-            &name, &device_path, &slave_address
+            "<config: SPI {} => device_path '{}', nos_bus '{}', chip_select {}",
+            &name, &config.device_path, &config.nos_bus, config.chip_select
         );
+        spis.insert(name, config);
+    }
 
-        let config = I2CConfig {
-            device_path,
-            slave_address,
-            nos_bus,
-            nos_slave_addr,
-        };
+    // Register all udp configurations
+    for (name, table) in config::sections(&mut config, "udp") {
+        let config = UDPConfig::from_table(&name, table);
+        println!(
+            "<config: UDP {} => listen '{}', send_to '{}', nos_bus '{}'",
+            &name, &config.listen, &config.send_to, &config.nos_bus
+        );
+        udps.insert(name, config);
+    }
 
-        i2cs.insert(name.to_string(), config);                                  
+    // nos3_io's own I2C masters, for the i2c-xfer command, which must not take the address of a
+    // bridged device
+    let mut masters = Masters::from_config(&mut config);
+    for (bus, raw) in &nos_addrs {
+        if masters.address(bus).raw() == *raw {
+            panic!(
+                "Error parsing config.toml: the i2c-xfer master on '{}' has the NOS address {:#04X} of a device, so give it another with [i2c_xfer.{}] 'master_addr'",
+                bus, raw, bus
+            );
+        }
     }
 
+    // Capture file which bridges record their traffic to
+    let capture_file = capture::capture_path(&mut config).and_then(|path| {
+        match CaptureFile::create(&path) {
+            Ok(file) => {
+                println!("<config: capturing bridge traffic to '{}'", path);
+                Some(Arc::new(file))
+            }
+            Err(err) => {
+                println!("<capture: error => creating '{}': {}", path, err);
+                None
+            }
+        }
+    });
+
+    // Bridges which have been started, and what they are started with
+    let mut bridges = Bridges {
+        capture_file,
+        monitor: Arc::new(Monitor::new()),
+        statuses: Vec::new(),
+    };
+
+    // Hardware buses shared by the bridges
+    let buses = Arc::new(BusManager::new());
+    // Where to send bytes for each running uart bridge to inject, for the send command
+    let mut injectors: HashMap<String, Sender<Injection>> = HashMap::new();
+    // Replays running in the background: their stop flags, and their threads, which end with
+    // the config of the bridge they replaced
+    let mut replays: Vec<(Arc<AtomicBool>, thread::JoinHandle<Replayed>)> = Vec::new();
+
     println!("<help: type 'help' for commands...");
     
     // Main program loop
     loop {
         let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
+        let read = io::stdin().read_line(&mut input);
+        // Bridges which were replaced by a replay can be started again once it has ended
+        let (ended, running): (Vec<_>, Vec<_>) = replays
+            .drain(..)
+            .partition(|(_, replay)| replay.is_finished());
+        replays = running;
+        for (_, replay) in ended {
+            match replay.join() {
+                Ok(Replayed::Uart(name, config)) => {
+                    uarts.insert(name, config);
+                }
+                Ok(Replayed::I2c(name, config)) => {
+                    i2cs.insert(name, config);
+                }
+                Err(_) => println!("<replay: error => a replay stopped unexpectedly"),
+            }
+        }
+        match read {
             Ok(n) => match input.split_whitespace().next() {
                 Some(cmd) => match cmd {
                     "help" => {
                         println!(
-                            "<commands: 'uart', 'i2c'"
+                            "<commands: 'uart', 'i2c', 'can', 'spi', 'udp', 'status', 'stats', 'monitor', 'send', 'i2c-xfer', 'attach', 'convert', 'compare', 'replay'"
                         );
                     }
-                    "uart" => {
-                        if let Some(arg) = input.split_whitespace().nth(1) {
-                            let arg = arg.trim();
-                            match arg {
-                                "all" => {
-                                    if uarts.is_empty() {
-                                        println!("<uart: error => no uart configs are available");
-                                        continue;    
-                                    }
-                                    for (name, config) in uarts.iter() {
-                                        println!(
-                                            "<uart: starting uart {} => if it hangs, restart NOS3 and nos3_io", 
-                                            &name
-                                        );
-                                        let serial_port = config.serial_port.to_string();
-                                        let nos_bus = config.nos_bus.to_string();
-                                        thread::spawn(move || {
-                                            uart_init(serial_port, nos_bus);
-                                        });
-                                    }
-                                    uarts.clear();
-                                }
-                                _ => {
-                                    if (!uarts.contains_key(arg)) {
-                                        println!("<uart: error => uart config not available");
-                                        continue;
-                                    }
-                                    let config = uarts.get(arg).unwrap();
-                                    println!(
-                                        "<uart: starting uart {} => if it hangs, restart NOS3 and nos3_io", 
-                                        &arg
-                                    );
-                                    let serial_port = config.serial_port.to_string();
-                                    let nos_bus = config.nos_bus.to_string();
-                                    thread::spawn(move || {
-                                            uart_init(serial_port, nos_bus);
-                                    });
-                                    uarts.remove(arg);
+                    "uart" => bridges.start("uart", Link::Uart, input.split_whitespace().nth(1), &mut uarts, |name, config, status, capture| {
+                        let (injector, injections) = mpsc::channel();
+                        injectors.insert(name.to_string(), injector);
+                        Box::new(move || uart::uart_init(config, status, capture, injections))
+                    }),
+                    "i2c" => bridges.start("i2c", Link::I2C, input.split_whitespace().nth(1), &mut i2cs, |_, config, status, capture| {
+                        let buses = buses.clone();
+                        Box::new(move || i2c::i2c_init(config, buses, status, capture))
+                    }),
+                    "can" => bridges.start("can", Link::Can, input.split_whitespace().nth(1), &mut cans, |_, config, status, capture| {
+                        Box::new(move || can::can_init(config, status, capture))
+                    }),
+                    "spi" => bridges.start("spi", Link::Spi, input.split_whitespace().nth(1), &mut spis, |_, config, status, capture| {
+                        Box::new(move || spi::spi_init(config, status, capture))
+                    }),
+                    "udp" => bridges.start("udp", Link::Udp, input.split_whitespace().nth(1), &mut udps, |_, config, status, capture| {
+                        Box::new(move || udp::udp_init(config, status, capture))
+                    }),
+                    "send" => {
+                        // The data is the rest of the line, as text may have spaces in it
+                        let mut parts = input.trim().splitn(3, ' ');
+                        let (name, data) = match (parts.nth(1), parts.next()) {
+                            (Some(name), Some(data)) => (name, data.trim()),
+                            _ => {
+                                println!("<help: 'send [uart name] hex:01ff..|\"text\\r\\n\"|@file.bin'");
+                                continue;
+                            }
+                        };
+                        let data = match inject::parse_data(data) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                println!("<send: error => {}", err);
+                                continue;
+                            }
+                        };
+                        let injector = match injectors.get(name) {
+                            Some(injector) => injector,
+                            None => {
+                                println!("<send: error => uart {} is not running", name);
+                                continue;
+                            }
+                        };
+                        let (responses, answers) = mpsc::channel();
+                        let len = data.len();
+                        if injector.send(Injection { data, responses }).is_err() {
+                            println!("<send: error => uart {} has stopped", name);
+                            continue;
+                        }
+                        println!("<send: sent {} bytes to uart {}", len, name);
+                        // The bridge passes NOS's answer until the response window closes
+                        let mut answered = false;
+                        for frame in answers.iter() {
+                            println!("<send: response => {}", capture::hex(&frame));
+                            answered = true;
+                        }
+                        if !answered {
+                            println!("<send: no response");
+                        }
+                    }
+                    "i2c-xfer" => {
+                        let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                        let transfer = match args.split_first() {
+                            Some((bus, args)) => inject::parse_xfer(args).map(|xfer| (bus, xfer)),
+                            None => Err("no bus".to_string()),
+                        };
+                        let (bus, (address, tx, rx_len)) = match transfer {
+                            Ok(transfer) => transfer,
+                            Err(err) => {
+                                println!("<i2c-xfer: error => {}", err);
+                                println!("<help: 'i2c-xfer [nos bus] [address] w:<hex> r:<n>'");
+                                continue;
+                            }
+                        };
+                        match masters.transfer(bus, address, &tx, rx_len) {
+                            Ok(rx) if rx_len > 0 => println!("<i2c-xfer: read => {}", capture::hex(&rx)),
+                            Ok(_) => println!("<i2c-xfer: wrote {} bytes", tx.len()),
+                            Err(err) => println!("<i2c-xfer: error => {}", err),
+                        }
+                    }
+                    "attach" => {
+                        let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                        let name = match args.first() {
+                            Some(name) => *name,
+                            None => {
+                                println!("<help: 'attach [uart name] [echo] [crlf] [log=path]', Ctrl-] to detach");
+                                continue;
+                            }
+                        };
+                        // The terminal takes the bridge's place on its NOS UART, as replay does
+                        let nos_bus = match uarts.get(name) {
+                            Some(config) => config.nos_bus.clone(),
+                            None if bridges.statuses.iter().any(|(bridge, _)| *bridge == format!("uart {}", name)) => {
+                                println!("<attach: error => uart {} is running, and attach only works before the bridge is started", name);
+                                continue;
+                            }
+                            None => {
+                                println!("<attach: error => uart config not available");
+                                continue;
+                            }
+                        };
+                        let result = term::Options::parse(&args[1..])
+                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+                            .and_then(|options| term::run(&nos_bus, term::DEFAULT_PORT, &options));
+                        if let Err(err) = result {
+                            println!("<attach: error => {}", err);
+                        }
+                    }
+                    "monitor" => {
+                        let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                        // Bridges are named by kind and name, as in the status command
+                        let (bridge, filters) = match args.as_slice() {
+                            ["off"] => {
+                                let stopped = bridges.monitor.off(None);
+                                println!("<monitor: stopped watching {} bridge(s)", stopped);
+                                continue;
+                            }
+                            ["off", kind, name] => {
+                                let bridge = format!("{} {}", kind, name);
+                                if bridges.monitor.off(Some(&bridge)) == 0 {
+                                    println!("<monitor: error => {} is not being watched", bridge);
                                 }
+                                continue;
+                            }
+                            ["all", filters @ ..] => ("all".to_string(), filters),
+                            [kind, name, filters @ ..] => (format!("{} {}", kind, name), filters),
+                            _ => {
+                                println!("<help: 'monitor [kind] [name]|all [dir=hw|nos] [addr=N] [pattern=<hex>]', 'monitor off [kind name]'");
+                                continue;
+                            }
+                        };
+                        match monitor::Filter::parse(filters) {
+                            Ok(filter) => {
+                                println!("<monitor: watching {}", bridge);
+                                bridges.monitor.watch(&bridge, filter);
                             }
-                        } else {
-                            println!("<help: 'uart all', 'uart [name]'");
+                            Err(err) => println!("<monitor: error => {}", err),
+                        }
+                    }
+                    "convert" => {
+                        let mut args = input.split_whitespace().skip(1);
+                        match (args.next(), args.next()) {
+                            (Some(path), Some(format)) => match capture::convert(path, format) {
+                                Ok(out) => println!("<convert: wrote {}", out),
+                                Err(err) => println!("<convert: error => {}", err),
+                            },
+                            _ => println!("<help: 'convert [capture.pcapng] csv|json'"),
                         }
                     }
-                    "i2c" => {
-                        if let Some(arg) = input.split_whitespace().nth(1) {
-                            let arg = arg.trim();
-                            match arg {
-                                "all" => {
-                                    if i2cs.is_empty() {
-                                        println!("<i2c: error => no i2c configs are available");
-                                        continue;    
+                    "compare" => {
+                        let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                        if let Err(err) = compare::command(&args) {
+                            println!("<compare: error => {}", err);
+                        }
+                    }
+                    "replay" => {
+                        let args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                        if args.first() == Some(&"stop") {
+                            if replays.is_empty() {
+                                println!("<replay: error => no replay is running");
+                            }
+                            for (stop, _) in &replays {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                            continue;
+                        }
+                        if args.len() < 3 {
+                            println!(
+                                "<help: 'replay uart|i2c [name] [session] [speed=N] [step] [loop] [compare]', 'replay stop'"
+                            );
+                            continue;
+                        }
+                        let options = match replay::Options::parse(&args[3..]) {
+                            Ok(options) => options,
+                            Err(err) => {
+                                println!("<replay: error => {}", err);
+                                continue;
+                            }
+                        };
+                        let (name, path) = (args[1], args[2].to_string());
+                        let stop = Arc::new(AtomicBool::new(false));
+                        let stop_flag = stop.clone();
+                        // The bridge's config is only available while the bridge is not running.
+                        // The replay takes the bridge's place, so it takes the config too, until
+                        // it ends
+                        match args[0] {
+                            "uart" => {
+                                let config = match uarts.remove(name) {
+                                    Some(config) => config,
+                                    None => {
+                                        println!("<replay: error => uart config not available, as uart {} is running or being replayed", name);
+                                        continue;
                                     }
-                                    for (name, config) in i2cs.iter() {
-                                        println!("<i2c: starting i2c {}", &name);
-                                        let path = config.device_path.to_string();
-                                        let addr = config.slave_address;
-                                        let bus = config.nos_bus.to_string();
-                                        let nos_slave = config.nos_slave_addr;
-                                        thread::spawn(move || {
-                                            i2c_init(path, addr, bus, nos_slave);
-                                        });
+                                };
+                                if options.step {
+                                    // Step mode waits for Enter, so it runs in the foreground
+                                    if let Err(err) = replay::replay_uart(&config.nos_bus, &path, &options, &stop) {
+                                        println!("<replay: error => {}", err);
                                     }
-                                    i2cs.clear();
+                                    uarts.insert(name.to_string(), config);
+                                    continue;
                                 }
-                                _ => {
-                                    if (!i2cs.contains_key(arg)) {
-                                        println!("<i2c: error => i2c config not available");
-                                        continue;
+                                let name = name.to_string();
+                                let replay = thread::spawn(move || {
+                                    if let Err(err) = replay::replay_uart(&config.nos_bus, &path, &options, &stop) {
+                                        println!("<replay: error => {}", err);
                                     }
-                                    let config = i2cs.get(arg).unwrap();
-                                    println!("<i2c: starting i2c {}", &arg);
-                                    let path = config.device_path.to_string();
-                                    let addr = config.slave_address;
-                                    let bus = config.nos_bus.to_string();
-                                    let nos_slave = config.nos_slave_addr;
-                                    thread::spawn(move || {
-                                        i2c_init(path, addr, bus, nos_slave);
-                                    });
-                                    i2cs.remove(arg);
+                                    Replayed::Uart(name, config)
+                                });
+                                replays.push((stop_flag, replay));
+                            }
+                            "i2c" => {
+                                if options.step || options.speed != 1.0 {
+                                    println!("<replay: error => the I2C master sets the pace, so step and speed do not apply");
+                                    continue;
                                 }
+                                let config = match i2cs.remove(name) {
+                                    Some(config) => config,
+                                    None => {
+                                        println!("<replay: error => i2c config not available, as i2c {} is running or being replayed", name);
+                                        continue;
+                                    }
+                                };
+                                let name = name.to_string();
+                                let replay = thread::spawn(move || {
+                                    let devices: Vec<_> = config
+                                        .devices
+                                        .iter()
+                                        .map(|device| (device.nos_addr, device.hw_addr))
+                                        .collect();
+                                    if let Err(err) = replay::replay_i2c(&config.nos_bus, &devices, &path, &options, &stop) {
+                                        println!("<replay: error => {}", err);
+                                    }
+                                    Replayed::I2c(name, config)
+                                });
+                                replays.push((stop_flag, replay));
+                            }
+                            kind => println!("<replay: error => cannot replay '{}', only uart or i2c", kind),
+                        }
+                    }
+                    "stats" => {
+                        let mut args: Vec<&str> = input.split_whitespace().skip(1).collect();
+                        let reset = args.first() == Some(&"reset");
+                        if reset {
+                            args.remove(0);
+                        }
+                        // Every bridge, or one named by kind and name, as in the status command
+                        let name = args.join(" ");
+                        let selected: Vec<&(String, Arc<BridgeStatus>)> = bridges
+                            .statuses
+                            .iter()
+                            .filter(|(bridge, _)| name.is_empty() || *bridge == name)
+                            .collect();
+                        if selected.is_empty() {
+                            if name.is_empty() {
+                                println!("<stats: no bridges have been started");
+                            } else {
+                                println!("<stats: error => {} has not been started", name);
+                            }
+                            println!("<help: 'stats [kind name]', 'stats reset [kind name]'");
+                            continue;
+                        }
+                        for (bridge, status) in selected {
+                            if reset {
+                                status.reset();
+                                println!("<stats: {} => reset", bridge);
+                                continue;
                             }
-                            //println!("Starting I2C forwarding and receiving on '{}'", &path_arg);
-                        } else {
-                            println!("<help: 'i2c all', 'i2c [name]'");
+                            for line in status.stats() {
+                                println!("<stats: {} => {}", bridge, line);
+                            }
+                        }
+                    }
+                    "status" => {
+                        if bridges.statuses.is_empty() {
+                            println!("<status: no bridges with status have been started");
+                        }
+                        for (name, status) in &bridges.statuses {
+                            println!("<status: {} => {}", name, status);
                         }
                     }
                     _ => {
@@ -278,126 +628,3 @@ fn main() {
         }
     }
 }
-
-fn uart_init(serial_port: String, nos_bus: String) {
-    // TODO: get nos connection string from config
-    let mut uart = match UART::new("fsw", "tcp://localhost:12000", nos_bus.as_str(), 1) {
-        Ok(uart) => {
-            println!("Established UART connection to NOS! Starting...");
-            uart
-        },
-        Err(e) => {
-            println!("NOS connection failure. Try restarting NOS3");
-            return;
-        }
-    };
-
-    let mut port = match serial::open(serial_port.as_str()) {
-        Ok(port) => port,
-        Err(err) => {
-            println!(
-                "Error opening the serial port, details: {}",
-                err
-            );
-            return;
-        }
-    };
-
-    port.set_timeout(Duration::from_millis(100));
-    let settings = serial::PortSettings {
-        baud_rate: serial::BaudRate::Baud9600,
-        char_size: CHAR_SIZE,
-        parity: PARITY,
-        stop_bits: STOP_BITS,
-        flow_control: FLOW_CONTROL,
-    };
-    port.configure(&settings).unwrap();
-
-    let mut in_buf: Vec<u8> = vec![0; 512]; // transient incoming data read, differs each loop iteration
-    let mut in_data: Vec<u8> = Vec::new(); // entire data block to write to NOS (stringed together from in_bufs)
-    
-    // Keep this thread working for the lifetime of the program
-    loop {
-        // incoming UART data to NOS UART
-        match port.read(in_buf.as_mut_slice()) {
-            Ok(n) if {n > 0} => {
-                in_data.append(in_buf[0..n].to_vec().as_mut());
-            }
-            _ if {in_data.len() > 0} => {
-                uart.write(&in_data);
-                //println!("[ DEBUG ] UART data in {:?}", &in_data);
-                in_data.clear();
-            }
-            _ => {}
-        };
-        // outgoing NOS data to UART
-        let data = uart.read(512);
-        match &data.len() {
-            0 => {},
-            _ => {
-                let out = data.as_slice();
-                //println!("[ DEBUG ] UART data out [{}...{}]", &out[0], &out[data.len() as usize - 1]);
-                port.write_all(out);
-            },
-        };
-    }
-}
-
-fn i2c_init(path: String, addr: u16, nos_bus: String, nos_slave_addr: u16) {
-    // TODO: get nos connection string from config
-    let nos_i2c = match I2CMaster::new(119, "tcp://localhost:12000", nos_bus.as_str()) {
-        Ok(i2c) => {
-            println!("Established I2C connection to NOS! Starting...");
-            i2c
-        },
-        Err(e) => {
-            println!("NOS connection failure. Try restarting NOS3");
-            return;
-        }
-    };
-
-    let mut i2c = I2c::from_path(path.clone()).unwrap(); // real i2c device
-    let mut last_cmd: u8 = 0x00;  // keep track of the last cmd written to sim, so when the external device wants to read the response, we can tell the sim what command
-    // NOTE: linux_i2c cannot read or write block data more than 32 bytes (according to documentation). hopefully that's a non-issue?
-    
-    // Keep this thread working for the lifetime of the program
-    loop {
-        // outgoing NOS data to I2C
-        match nos_i2c.read(nos_slave_addr, 32) {
-            Ok(data) => {
-                match &data.len() {
-                    0 => {},
-                    _ => {
-                        i2c.smbus_set_slave_address(addr, false).unwrap();
-                        // data[0] might be the command byte
-                        // if so, replace cmd from last write with data[0] 
-                        i2c.i2c_write_block_data(last_cmd, data.as_slice());
-                    },
-                }
-            }
-            _ => {}
-        }
-        // incoming I2C data to NOS        
-        let mut data = vec![0; 32];
-        i2c.smbus_set_slave_address(addr, false).unwrap();
-        let cmd = match i2c.smbus_read_byte() {
-            Ok(cmd) => {
-                match i2c.i2c_read_block_data(cmd, &mut data) {
-                    Ok(n) if {n > 0} => {
-                        last_cmd = cmd;
-                        let data = data.as_slice();
-                        let cmd = &[cmd];           
-                        let comm: Vec<_> = cmd.iter().chain(data).cloned().collect();
-                        nos_i2c.write(nos_slave_addr, &comm).unwrap();
-                    }
-                    Ok(_) => {
-                        nos_i2c.write(nos_slave_addr, &[cmd]).unwrap();
-                    }
-                    _ => {}
-                }
-            },
-            _ => {}
-        };
-    }
-}
-
@@ -0,0 +1,239 @@
+//! Prints bridge traffic as it passes, for the `monitor` command.
+//!
+//! Every bridge reports what it passes to the one `Monitor` (through its `Capture`), whether or
+//! not anyone is watching, so a bridge can be watched, or stop being watched, while it runs.
+//! Each watched bridge has a filter, which can pick out a direction, an I2C address, or frames
+//! containing a pattern of bytes.
+
+use crate::capture::Direction;
+use crate::inject::parse_hex;
+use nosengine_rust::client::i2c::I2CAddress;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes on each line of a dump
+const LINE_BYTES: usize = 16;
+
+/// Which of a bridge's traffic is printed.
+#[derive(Default)]
+pub struct Filter {
+    pub direction: Option<Direction>,
+    /// I2C address, matched on its number alone
+    pub address: Option<u16>,
+    pub pattern: Option<Vec<u8>>,
+}
+
+impl Filter {
+    /// Parse `dir=hw|nos`, `addr=0x48` and `pattern=<hex>`, in any order.
+    pub fn parse(args: &[&str]) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("dir"), Some("hw")) => filter.direction = Some(Direction::HardwareToNos),
+                (Some("dir"), Some("nos")) => filter.direction = Some(Direction::NosToHardware),
+                (Some("addr"), Some(address)) => {
+                    let parsed = if address.starts_with("0x") || address.starts_with("0X") {
+                        u16::from_str_radix(&address[2..], 16)
+                    } else {
+                        address.parse()
+                    };
+                    filter.address =
+                        Some(parsed.map_err(|_| format!("'{}' must be an I2C address", address))?);
+                }
+                (Some("pattern"), Some(pattern)) => {
+                    filter.pattern = Some(parse_hex(pattern).ok_or_else(|| {
+                        format!("'{}' must be bytes in hex, such as 1acffc1d", pattern)
+                    })?)
+                }
+                _ => {
+                    return Err(format!(
+                        "unknown filter '{}', expected dir=hw|nos, addr=N or pattern=<hex>",
+                        arg
+                    ))
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, direction: Direction, address: Option<I2CAddress>, data: &[u8]) -> bool {
+        if self.direction.map_or(false, |filter| filter != direction) {
+            return false;
+        }
+        if let Some(filter) = self.address {
+            if address.map(|address| address.raw()) != Some(filter) {
+                return false;
+            }
+        }
+        match self.pattern {
+            Some(ref pattern) if !pattern.is_empty() => data
+                .windows(pattern.len())
+                .any(|window| window == &pattern[..]),
+            _ => true,
+        }
+    }
+}
+
+/// The bridges being watched, by name, such as "uart 1", or "all".
+pub struct Monitor {
+    watches: Mutex<HashMap<String, Filter>>,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor {
+            watches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start watching a bridge, or every bridge if `bridge` is "all", replacing any filter it had.
+    pub fn watch(&self, bridge: &str, filter: Filter) {
+        self.watches
+            .lock()
+            .unwrap()
+            .insert(bridge.to_string(), filter);
+    }
+
+    /// Stop watching a bridge, or every bridge. Returns how many watches were stopped.
+    pub fn off(&self, bridge: Option<&str>) -> usize {
+        let mut watches = self.watches.lock().unwrap();
+        match bridge {
+            Some(bridge) => watches.remove(bridge).map_or(0, |_| 1),
+            None => watches.drain().count(),
+        }
+    }
+
+    /// Print traffic from a bridge, if it is watched and passes the filter. `summary` describes
+    /// it, as in the capture's packet comments.
+    pub fn show(
+        &self,
+        bridge: &str,
+        direction: Direction,
+        address: Option<I2CAddress>,
+        summary: &str,
+        data: &[u8],
+    ) {
+        let watches = self.watches.lock().unwrap();
+        if watches.is_empty() {
+            return;
+        }
+        let shown = [bridge, "all"].iter().any(|name| {
+            watches
+                .get(*name)
+                .map_or(false, |filter| filter.matches(direction, address, data))
+        });
+        if shown {
+            println!(
+                "<monitor: {} {} => {}\n{}",
+                timestamp(),
+                bridge,
+                summary,
+                dump(data)
+            );
+        }
+    }
+}
+
+/// Hex and ASCII, 16 bytes to a line, each line starting with its offset.
+fn dump(data: &[u8]) -> String {
+    let lines: Vec<String> = data
+        .chunks(LINE_BYTES)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|byte| {
+                    if byte.is_ascii_graphic() || *byte == b' ' {
+                        *byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!(
+                "  {:04x}  {:<width$}  {}",
+                i * LINE_BYTES,
+                hex.join(" "),
+                ascii,
+                width = LINE_BYTES * 3 - 1
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Time of day, in UTC, to the microsecond.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_micros() as u64)
+        .unwrap_or(0);
+    let seconds = now / 1_000_000 % 86_400;
+    format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        now % 1_000_000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_are_parsed_in_any_order() {
+        let filter = Filter::parse(&["pattern=1acf", "addr=0x48", "dir=nos"]).unwrap();
+        assert_eq!(filter.direction, Some(Direction::NosToHardware));
+        assert_eq!(filter.address, Some(0x48));
+        assert_eq!(filter.pattern, Some(vec![0x1a, 0xcf]));
+
+        assert_eq!(Filter::parse(&["addr=72"]).unwrap().address, Some(72));
+        let filter = Filter::parse(&[]).unwrap();
+        assert!(filter.direction.is_none() && filter.address.is_none() && filter.pattern.is_none());
+
+        for bad in &["dir=up", "addr=0xZZ", "pattern=abc", "speed=2", "dir"] {
+            assert!(Filter::parse(&[bad]).is_err(), "{} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn filters_match_direction_address_and_pattern() {
+        let hw = Direction::HardwareToNos;
+        let nos = Direction::NosToHardware;
+        let everything = Filter::default();
+        assert!(everything.matches(hw, None, b""));
+
+        let direction = Filter::parse(&["dir=hw"]).unwrap();
+        assert!(direction.matches(hw, None, b"abc"));
+        assert!(!direction.matches(nos, None, b"abc"));
+
+        // The address is matched on its number, whether 7 or 10-bit, and traffic without one
+        // does not match
+        let address = Filter::parse(&["addr=0x48"]).unwrap();
+        assert!(address.matches(hw, Some(I2CAddress::SevenBit(0x48)), b""));
+        assert!(address.matches(hw, Some(I2CAddress::TenBit(0x48)), b""));
+        assert!(!address.matches(hw, Some(I2CAddress::SevenBit(0x49)), b""));
+        assert!(!address.matches(hw, None, b""));
+
+        let pattern = Filter::parse(&["pattern=cafe"]).unwrap();
+        assert!(pattern.matches(nos, None, &[0x00, 0xca, 0xfe, 0x01]));
+        assert!(!pattern.matches(nos, None, &[0xca, 0x00, 0xfe]));
+        assert!(!pattern.matches(nos, None, &[0xca]));
+    }
+
+    #[test]
+    fn dumps_show_offset_hex_and_ascii() {
+        let data: Vec<u8> = b"Hello, world!\r\n\x00ABC".to_vec();
+        assert_eq!(
+            dump(&data),
+            "  0000  48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0d 0a 00  Hello, world!...\n  \
+             0010  41 42 43                                         ABC"
+        );
+        assert_eq!(dump(&[]), "");
+    }
+}
@@ -0,0 +1,265 @@
+//! Pairs each write from a simulated master with the read which follows it, for the I2C and SPI
+//! bridges.
+//!
+//! A master's write-then-read reaches a NOSEngine slave as two separate callbacks, and neither
+//! says whether the other is coming, so NOSEngine gives no transaction boundaries to go by.
+//! Pairing is by timing instead: a write is held back for up to the bridge's
+//! `transaction_window_us`, and if a read from the same device follows in that time, the two are
+//! handed on as one transfer. Otherwise the write is handed on alone. The read cannot arrive
+//! until the write's callback has returned, so a held write is acknowledged to the master before
+//! it reaches the hardware, and if it fails, that can only be counted and logged. With a window of
+//! 0, writes are never held: each goes to the hardware before it is acknowledged, and one which
+//! fails is not acknowledged, but a write and a read always go to the hardware separately.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Work passed from the slave callbacks to the thread which owns the hardware.
+enum Request<K> {
+    /// Bytes written, and where to say whether the hardware took them, if the write is not held
+    Write(K, Vec<u8>, Option<Sender<bool>>),
+    Read(K, usize, Sender<Vec<u8>>),
+}
+
+/// A transfer for the hardware, for the device `K`.
+pub enum Transfer<K> {
+    /// A write with no read after it
+    Write(K, Vec<u8>, Ack),
+    /// A read of some bytes, after the write to the same device just before it, which is empty if
+    /// there was none. The bytes read are to be sent back to the master on the `Sender`.
+    Read(K, Vec<u8>, usize, Sender<Vec<u8>>),
+}
+
+/// Tells a master waiting on an unheld write whether the hardware took it.
+pub struct Ack(Option<Sender<bool>>);
+
+impl Ack {
+    pub fn send(self, ok: bool) {
+        if let Some(ack) = self.0 {
+            let _ = ack.send(ok);
+        }
+    }
+}
+
+/// Makes the callbacks of a bridge's NOS slaves.
+pub struct Slaves<K> {
+    sender: Sender<Request<K>>,
+    hold: bool,
+}
+
+impl<K: Copy> Slaves<K> {
+    /// The callbacks of the slave for device `key`.
+    pub fn slave(&self, key: K) -> Slave<K> {
+        Slave {
+            sender: Mutex::new(self.sender.clone()),
+            key,
+            hold: self.hold,
+        }
+    }
+}
+
+/// A NOS slave's callbacks, which pass each write and read on and wait for the hardware.
+pub struct Slave<K> {
+    sender: Mutex<Sender<Request<K>>>,
+    key: K,
+    hold: bool,
+}
+
+impl<K> Slave<K>
+where
+    K: Copy,
+{
+    /// The master wrote `data`. Returns how many bytes were acknowledged.
+    pub fn write(&self, data: &[u8]) -> usize {
+        if self.hold {
+            let request = Request::Write(self.key, data.to_vec(), None);
+            let _ = self.sender.lock().unwrap().send(request);
+            return data.len();
+        }
+        let (ack, acked) = mpsc::channel();
+        let request = Request::Write(self.key, data.to_vec(), Some(ack));
+        let _ = self.sender.lock().unwrap().send(request);
+        match acked.recv() {
+            Ok(true) => data.len(),
+            _ => 0,
+        }
+    }
+
+    /// The master reads into `data`. Returns how many bytes were read.
+    pub fn read(&self, data: &mut [u8]) -> usize {
+        let (reply, response) = mpsc::channel();
+        let _ = self
+            .sender
+            .lock()
+            .unwrap()
+            .send(Request::Read(self.key, data.len(), reply));
+        match response.recv() {
+            Ok(read) => {
+                let len = read.len().min(data.len());
+                data[..len].copy_from_slice(&read[..len]);
+                len
+            }
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Collects requests from the slave callbacks into transfers.
+pub struct Pairing<K> {
+    requests: Receiver<Request<K>>,
+    window: Duration,
+    /// Write waiting to see if a read follows it
+    pending: Option<(K, Vec<u8>)>,
+    /// Read from another device, waiting for the pending write to be handed on first
+    queued: Option<Transfer<K>>,
+}
+
+/// Make the slave callbacks and the pairing for a bridge, which holds writes back for `window`.
+pub fn pairing<K>(window: Duration) -> (Slaves<K>, Pairing<K>) {
+    let (sender, requests) = mpsc::channel();
+    let slaves = Slaves {
+        sender,
+        hold: window > Duration::from_secs(0),
+    };
+    let pairing = Pairing {
+        requests,
+        window,
+        pending: None,
+        queued: None,
+    };
+    (slaves, pairing)
+}
+
+impl<K> Pairing<K>
+where
+    K: Copy + PartialEq,
+{
+    /// Wait for the next transfer. Returns `None` once every slave has gone.
+    pub fn next(&mut self) -> Option<Transfer<K>> {
+        if let Some(transfer) = self.queued.take() {
+            return Some(transfer);
+        }
+        loop {
+            let request = match self.pending {
+                Some(_) => self.requests.recv_timeout(self.window),
+                None => self
+                    .requests
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match request {
+                Ok(Request::Write(key, data, None)) => {
+                    // A write after a write: the first stands alone
+                    if let Some((held, tx)) = self.pending.replace((key, data)) {
+                        return Some(Transfer::Write(held, tx, Ack(None)));
+                    }
+                }
+                // Writes are only unheld when none are held, so none can be pending
+                Ok(Request::Write(key, data, ack)) => {
+                    return Some(Transfer::Write(key, data, Ack(ack)));
+                }
+                Ok(Request::Read(key, len, reply)) => {
+                    return Some(match self.pending.take() {
+                        Some((held, tx)) if held == key => Transfer::Read(key, tx, len, reply),
+                        Some((held, tx)) => {
+                            self.queued = Some(Transfer::Read(key, Vec::new(), len, reply));
+                            Transfer::Write(held, tx, Ack(None))
+                        }
+                        None => Transfer::Read(key, Vec::new(), len, reply),
+                    });
+                }
+                // No read followed, so the write stands alone
+                Err(_) => {
+                    return self
+                        .pending
+                        .take()
+                        .map(|(held, tx)| Transfer::Write(held, tx, Ack(None)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    /// Describe a transfer, answering a read with `len` bytes of its device number.
+    fn describe(transfer: Transfer<u8>) -> String {
+        match transfer {
+            Transfer::Write(key, tx, ack) => {
+                ack.send(true);
+                format!("write {} {:?}", key, tx)
+            }
+            Transfer::Read(key, tx, len, reply) => {
+                let _ = reply.send(vec![key; len]);
+                format!("read {} {:?} {}", key, tx, len)
+            }
+        }
+    }
+
+    /// Run the slave side on a thread, and describe every transfer until it is done.
+    fn run<F>(window: Duration, master: F) -> Vec<String>
+    where
+        F: FnOnce(Slaves<u8>) + Send + 'static,
+    {
+        let (slaves, mut pairing) = pairing(window);
+        let master = thread::spawn(move || master(slaves));
+        let mut transfers = Vec::new();
+        while let Some(transfer) = pairing.next() {
+            transfers.push(describe(transfer));
+        }
+        master.join().unwrap();
+        transfers
+    }
+
+    #[test]
+    fn write_then_read_is_one_transfer() {
+        let transfers = run(WINDOW, |slaves| {
+            let slave = slaves.slave(1);
+            assert_eq!(slave.write(&[0x10]), 1);
+            let mut rx = [0; 2];
+            assert_eq!(slave.read(&mut rx), 2);
+            assert_eq!(rx, [1, 1]);
+        });
+        assert_eq!(transfers, ["read 1 [16] 2"]);
+    }
+
+    #[test]
+    fn write_alone_is_handed_on_after_the_window() {
+        let transfers = run(WINDOW, |slaves| {
+            slaves.slave(1).write(&[1, 2]);
+            thread::sleep(WINDOW * 3);
+            slaves.slave(1).write(&[3]);
+        });
+        assert_eq!(transfers, ["write 1 [1, 2]", "write 1 [3]"]);
+    }
+
+    #[test]
+    fn write_to_another_device_stands_alone() {
+        let transfers = run(WINDOW, |slaves| {
+            slaves.slave(1).write(&[1]);
+            slaves.slave(2).read(&mut [0; 1]);
+        });
+        assert_eq!(transfers, ["write 1 [1]", "read 2 [] 1"]);
+    }
+
+    #[test]
+    fn unheld_writes_wait_for_the_hardware() {
+        let (slaves, mut pairing) = pairing::<u8>(Duration::from_secs(0));
+        let master = thread::spawn(move || slaves.slave(1).write(&[1, 2, 3]));
+        match pairing.next() {
+            Some(Transfer::Write(1, tx, ack)) => {
+                assert_eq!(tx, [1, 2, 3]);
+                ack.send(false);
+            }
+            _ => panic!("expected a write"),
+        }
+        assert_eq!(master.join().unwrap(), 0);
+        assert!(pairing.next().is_none());
+    }
+}
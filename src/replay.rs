@@ -0,0 +1,471 @@
+//! Replays a session recorded by a UART or I2C bridge (`record = "<path>"`) into NOS, in place
+//! of the hardware, so flight software can be run against real traffic without the hardware.
+//!
+//! A UART replay connects to the bridge's NOS UART as the bridge would, and writes each frame the
+//! hardware sent at the time it was sent, scaled by `speed`. In step mode each frame instead waits
+//! for Enter. An I2C replay puts a slave at each of the bridge's NOS addresses, which answers the
+//! master's reads with the device's recorded reads, in order. The master sets the pace on I2C, so
+//! timing and step mode do not apply to it.
+//!
+//! With `compare`, what the flight software sends is checked against what it sent in the
+//! recording: on a UART, the bytes it sent between one hardware frame and the next, and on I2C,
+//! each write to a device. Every difference is reported. An I2C bridge records the transfers it
+//! made to the device, so for a device in register mode these are the translated transfers.
+
+use crate::capture::{self, Direction, Link, Record};
+use crate::endpoint::POLL_INTERVAL;
+use nosengine_rust::client::i2c::{I2CAddress, I2CSlave};
+use nosengine_rust::client::uart::UART;
+use nosengine_rust::ffi::i2c::I2CDirection;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a UART replay waits for the flight software after the last frame, or after each frame
+/// in step mode
+const SETTLE: Duration = Duration::from_millis(500);
+/// Slowest replay speed, so that scaled offsets cannot overflow a `Duration`
+const MIN_SPEED: f64 = 0.001;
+
+/// How a session is replayed, from the arguments of the `replay` command.
+pub struct Options {
+    /// How many times faster than recorded
+    pub speed: f64,
+    pub step: bool,
+    pub looping: bool,
+    pub compare: bool,
+}
+
+impl Options {
+    /// Parse `speed=N`, `step`, `loop` and `compare`, in any order.
+    pub fn parse(args: &[&str]) -> Result<Options, String> {
+        let mut options = Options {
+            speed: 1.0,
+            step: false,
+            looping: false,
+            compare: false,
+        };
+        for arg in args {
+            match *arg {
+                "step" => options.step = true,
+                "loop" => options.looping = true,
+                "compare" => options.compare = true,
+                arg if arg.starts_with("speed=") => {
+                    options.speed = match arg["speed=".len()..].parse::<f64>() {
+                        Ok(speed) if speed.is_finite() && speed >= MIN_SPEED => speed,
+                        _ => {
+                            return Err(format!(
+                                "'{}' must be a speed of at least {}",
+                                arg, MIN_SPEED
+                            ))
+                        }
+                    }
+                }
+                arg => return Err(format!("unknown option '{}'", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// A frame the hardware sent, and what the flight software sent after it until the next one.
+struct Step {
+    /// Since the start of the session
+    offset: Duration,
+    /// Empty for the first step if the flight software sent first
+    inject: Vec<u8>,
+    expected: Vec<u8>,
+}
+
+/// Replay a UART session into the NOS UART on `nos_bus`, until it ends or `stop` is set.
+pub fn replay_uart(
+    nos_bus: &str,
+    path: &str,
+    options: &Options,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let records: Vec<Record> = capture::read_capture(path)?
+        .into_iter()
+        .filter(|record| record.link == Some(Link::Uart) && record.direction.is_some())
+        .collect();
+    let steps = uart_steps(&records);
+    if steps.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' has no UART traffic", path),
+        ));
+    }
+    let uart = UART::new("fsw", crate::NOS_CONNECTION, nos_bus, 1).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("connecting to NOS: {}", err),
+        )
+    })?;
+    println!(
+        "<replay: {} => {} frames to '{}'",
+        path,
+        steps.iter().filter(|step| !step.inject.is_empty()).count(),
+        nos_bus
+    );
+
+    let mut pass = 1;
+    loop {
+        let start = Instant::now();
+        let mut received = vec![Vec::new(); steps.len()];
+        for (i, step) in steps.iter().enumerate() {
+            if i > 0 {
+                if options.step {
+                    if !prompt(i, steps.len(), &step.inject) {
+                        return Ok(());
+                    }
+                } else {
+                    // What arrives until this frame belongs to the one before
+                    let at = start + step.offset.div_f64(options.speed);
+                    if !collect(&uart, at, stop, &mut received[i - 1]) {
+                        return Ok(());
+                    }
+                }
+            }
+            if !step.inject.is_empty() {
+                uart.write(&step.inject);
+            }
+            if options.step {
+                if !collect(&uart, Instant::now() + SETTLE, stop, &mut received[i]) {
+                    return Ok(());
+                }
+                if !received[i].is_empty() {
+                    println!("<replay: fsw => {}", capture::hex(&received[i]));
+                }
+            }
+        }
+        let last = received.len() - 1;
+        if !collect(&uart, Instant::now() + SETTLE, stop, &mut received[last]) {
+            return Ok(());
+        }
+
+        let mut divergences = 0;
+        if options.compare {
+            for (i, (step, got)) in steps.iter().zip(&received).enumerate() {
+                if step.expected != *got {
+                    divergences += 1;
+                    println!(
+                        "<replay: pass {}, frame {} at {:.3} s => expected [{}], got [{}]",
+                        pass,
+                        i,
+                        step.offset.as_secs_f64(),
+                        capture::hex(&step.expected),
+                        capture::hex(got)
+                    );
+                }
+            }
+        }
+        println!(
+            "<replay: pass {} done => {}",
+            pass,
+            if options.compare {
+                format!("{} divergence(s)", divergences)
+            } else {
+                "not compared".to_string()
+            }
+        );
+        if !options.looping || stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        pass += 1;
+    }
+}
+
+/// Split a UART session into the frames the hardware sent, each with what followed it.
+fn uart_steps(records: &[Record]) -> Vec<Step> {
+    let first = match records.first() {
+        Some(record) => record.time,
+        None => return Vec::new(),
+    };
+    let mut steps: Vec<Step> = Vec::new();
+    for record in records {
+        match record.direction {
+            Some(Direction::HardwareToNos) => steps.push(Step {
+                offset: Duration::from_micros(record.time.saturating_sub(first)),
+                inject: record.data.clone(),
+                expected: Vec::new(),
+            }),
+            _ => {
+                if steps.is_empty() {
+                    steps.push(Step {
+                        offset: Duration::from_secs(0),
+                        inject: Vec::new(),
+                        expected: Vec::new(),
+                    });
+                }
+                let step = steps.len() - 1;
+                steps[step].expected.extend_from_slice(&record.data);
+            }
+        }
+    }
+    steps
+}
+
+/// Read what the flight software sends until `until`. Returns false if `stop` was set.
+fn collect(uart: &UART, until: Instant, stop: &AtomicBool, received: &mut Vec<u8>) -> bool {
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        let data = uart.read(512);
+        if data.is_empty() {
+            let now = Instant::now();
+            if now >= until {
+                return true;
+            }
+            thread::sleep(POLL_INTERVAL.min(until - now));
+        }
+        received.extend_from_slice(&data);
+    }
+}
+
+/// Wait for Enter before the next frame in step mode. Returns false to stop.
+fn prompt(step: usize, steps: usize, inject: &[u8]) -> bool {
+    println!(
+        "<replay: frame {}/{} => [{}], Enter to send, 'q' to stop",
+        step,
+        steps - 1,
+        capture::hex(inject)
+    );
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        Ok(_) => input.trim() != "q",
+        Err(_) => false,
+    }
+}
+
+/// The recorded transfers with one device, in order.
+#[derive(Clone, Default)]
+struct Transfers {
+    reads: VecDeque<Vec<u8>>,
+    writes: VecDeque<Vec<u8>>,
+}
+
+/// One device's replay, shared with its slave's handler.
+struct DeviceReplay {
+    recorded: Transfers,
+    remaining: Transfers,
+    pass: usize,
+    divergences: usize,
+}
+
+/// Replay an I2C session through slaves at the NOS addresses of `devices`, given as (NOS address,
+/// hardware address), until every recorded read has been answered or `stop` is set.
+pub fn replay_i2c(
+    nos_bus: &str,
+    devices: &[(I2CAddress, I2CAddress)],
+    path: &str,
+    options: &Options,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let mut transfers: HashMap<I2CAddress, Transfers> = HashMap::new();
+    // Failed attempts are left out, since the master was never given their data
+    for record in capture::read_capture(path)? {
+        if let (Some((address, read)), false) = (record.i2c, record.failed) {
+            let device = transfers.entry(address).or_default();
+            if read {
+                device.reads.push_back(record.data);
+            } else {
+                device.writes.push_back(record.data);
+            }
+        }
+    }
+    if transfers.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' has no I2C traffic", path),
+        ));
+    }
+
+    let mut replays = Vec::new();
+    let mut slaves = Vec::new();
+    for (nos_addr, hw_addr) in devices {
+        let recorded = match transfers.remove(hw_addr) {
+            Some(recorded) => recorded,
+            None => continue,
+        };
+        println!(
+            "<replay: {} => {} reads for {} (hardware {})",
+            path,
+            recorded.reads.len(),
+            nos_addr,
+            hw_addr
+        );
+        let replay = Arc::new(Mutex::new(DeviceReplay {
+            remaining: recorded.clone(),
+            recorded,
+            pass: 1,
+            divergences: 0,
+        }));
+        replays.push((*nos_addr, replay.clone()));
+        let nos_addr = *nos_addr;
+        let looping = options.looping;
+        let compare = options.compare;
+        let slave = I2CSlave::with_handler(
+            nos_addr,
+            crate::NOS_CONNECTION,
+            nos_bus,
+            move |dir, data| {
+                let mut replay = replay.lock().unwrap();
+                if replay.remaining.reads.is_empty() && looping {
+                    println!("<replay: {} pass {} done", nos_addr, replay.pass);
+                    replay.remaining = replay.recorded.clone();
+                    replay.pass += 1;
+                }
+                match dir {
+                    I2CDirection::Write => {
+                        let expected = replay.remaining.writes.pop_front();
+                        if compare && expected.as_ref().map(|write| &write[..]) != Some(&data[..]) {
+                            replay.divergences += 1;
+                            println!(
+                                "<replay: {} pass {} write => expected [{}], got [{}]",
+                                nos_addr,
+                                replay.pass,
+                                expected
+                                    .map(|write| capture::hex(&write))
+                                    .unwrap_or_default(),
+                                capture::hex(data)
+                            );
+                        }
+                        data.len()
+                    }
+                    I2CDirection::Read => match replay.remaining.reads.pop_front() {
+                        Some(read) => {
+                            let n = read.len().min(data.len());
+                            data[..n].copy_from_slice(&read[..n]);
+                            n
+                        }
+                        // Nothing more was recorded, so answer as if the device did not
+                        // acknowledge
+                        None => 0,
+                    },
+                }
+            },
+        );
+        match slave {
+            Ok(slave) => slaves.push(slave),
+            Err(err) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("connecting address {} to NOS: {}", nos_addr, err),
+                ))
+            }
+        }
+    }
+    for address in transfers.keys() {
+        println!(
+            "<replay: {} => no device at hardware {}, skipped",
+            path, address
+        );
+    }
+    if slaves.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no recorded device is in the bridge",
+        ));
+    }
+
+    // The slaves answer until their reads run out, or for as long as it takes when looping
+    while !stop.load(Ordering::Relaxed) {
+        let done = replays
+            .iter()
+            .all(|(_, replay)| replay.lock().unwrap().remaining.reads.is_empty());
+        if done && !options.looping {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    drop(slaves);
+    for (address, replay) in &replays {
+        let replay = replay.lock().unwrap();
+        println!(
+            "<replay: {} done => {} divergence(s) in {} pass(es)",
+            address, replay.divergences, replay.pass
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: u64, direction: Direction, data: &[u8]) -> Record {
+        Record {
+            time,
+            bridge: String::from("uart.0"),
+            link: Some(Link::Uart),
+            direction: Some(direction),
+            address: None,
+            operation: None,
+            i2c: None,
+            failed: false,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn steps_are_hardware_frames_with_what_followed() {
+        let records = [
+            record(1_000, Direction::NosToHardware, b"hello"),
+            record(1_500, Direction::HardwareToNos, b"a"),
+            record(1_600, Direction::NosToHardware, b"b"),
+            record(1_700, Direction::NosToHardware, b"c"),
+            record(3_000, Direction::HardwareToNos, b"d"),
+        ];
+        let steps = uart_steps(&records);
+        assert_eq!(steps.len(), 3);
+        // The flight software sent first, so nothing is injected before it
+        assert_eq!(steps[0].offset, Duration::from_secs(0));
+        assert!(steps[0].inject.is_empty());
+        assert_eq!(steps[0].expected, b"hello");
+        assert_eq!(steps[1].offset, Duration::from_micros(500));
+        assert_eq!(steps[1].inject, b"a");
+        assert_eq!(steps[1].expected, b"bc");
+        assert_eq!(steps[2].offset, Duration::from_micros(2_000));
+        assert_eq!(steps[2].inject, b"d");
+        assert!(steps[2].expected.is_empty());
+
+        assert!(uart_steps(&[]).is_empty());
+    }
+
+    #[test]
+    fn options_are_parsed_in_any_order() {
+        let options = Options::parse(&["compare", "speed=2.5", "loop"]).unwrap();
+        assert_eq!(options.speed, 2.5);
+        assert!(options.compare && options.looping && !options.step);
+
+        let options = Options::parse(&[]).unwrap();
+        assert_eq!(options.speed, 1.0);
+        assert!(!options.compare && !options.looping && !options.step);
+        assert!(Options::parse(&["step"]).unwrap().step);
+        assert!(Options::parse(&["fast"]).is_err());
+    }
+
+    #[test]
+    fn speeds_which_cannot_scale_the_session_are_rejected() {
+        for speed in &[
+            "speed=0",
+            "speed=-1",
+            "speed=nan",
+            "speed=inf",
+            "speed=1e-300",
+            "speed=",
+        ] {
+            assert!(Options::parse(&[speed]).is_err(), "{} was accepted", speed);
+        }
+        // The slowest speed still scales a day-long session
+        let options = Options::parse(&["speed=0.001"]).unwrap();
+        assert_eq!(
+            Duration::from_secs(86_400).div_f64(options.speed),
+            Duration::from_secs(86_400_000)
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! Telnet with the COM port control option (RFC 2217), for UART bridges reached over TCP.
+//!
+//! Data bytes are passed through, with 0xFF doubled as Telnet requires. A bridge listening for
+//! clients plays the access server: it answers requests to change baud rate and line settings,
+//! which it records and reports but has nothing to apply to, as the NOS UART has no line
+//! settings. A bridge connecting to a terminal server plays the client, and asks the server for
+//! the bridge's baud rate and 8N1 when it connects.
+
+use std::collections::HashSet;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+const SIGNATURE: u8 = 0;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const SET_LINESTATE_MASK: u8 = 10;
+const SET_MODEMSTATE_MASK: u8 = 11;
+const PURGE_DATA: u8 = 12;
+/// Added to a command in the server's answer to it
+const SERVER_OFFSET: u8 = 100;
+
+/// Line settings, as RFC 2217 numbers them.
+struct LineSettings {
+    baud_rate: u32,
+    data_size: u8,
+    /// 1 none, 2 odd, 3 even, 4 mark, 5 space
+    parity: u8,
+    /// 1 one, 2 two, 3 one and a half
+    stop_size: u8,
+    /// 1 none, 2 XON/XOFF, 3 hardware
+    flow_control: u8,
+}
+
+enum State {
+    Data,
+    Iac,
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// One side of a Telnet connection.
+pub struct Telnet {
+    server: bool,
+    state: State,
+    subnegotiation: Vec<u8>,
+    /// Options this side has agreed or asked to use
+    local: HashSet<u8>,
+    /// Options the other side has agreed or been asked to use
+    remote: HashSet<u8>,
+    settings: LineSettings,
+}
+
+impl Telnet {
+    /// The access server side, for a client which has just connected.
+    pub fn server() -> Telnet {
+        Telnet::new(true, 9600)
+    }
+
+    /// The client side, for a terminal server which has just been connected to.
+    pub fn client(baud_rate: u32) -> Telnet {
+        Telnet::new(false, baud_rate)
+    }
+
+    fn new(server: bool, baud_rate: u32) -> Telnet {
+        Telnet {
+            server,
+            state: State::Data,
+            subnegotiation: Vec::new(),
+            local: HashSet::new(),
+            remote: HashSet::new(),
+            settings: LineSettings {
+                baud_rate,
+                data_size: 8,
+                parity: 1,
+                stop_size: 1,
+                flow_control: 1,
+            },
+        }
+    }
+
+    /// What to send as soon as the connection is made.
+    pub fn start(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for option in &[BINARY, SUPPRESS_GO_AHEAD] {
+            self.local.insert(*option);
+            self.remote.insert(*option);
+            out.extend_from_slice(&[IAC, WILL, *option, IAC, DO, *option]);
+        }
+        if self.server {
+            self.remote.insert(COM_PORT_OPTION);
+            out.extend_from_slice(&[IAC, DO, COM_PORT_OPTION]);
+        } else {
+            self.local.insert(COM_PORT_OPTION);
+            out.extend_from_slice(&[IAC, WILL, COM_PORT_OPTION]);
+            let baud_rate = self.settings.baud_rate.to_be_bytes();
+            subnegotiate(&mut out, SET_BAUDRATE, &baud_rate);
+            subnegotiate(&mut out, SET_DATASIZE, &[self.settings.data_size]);
+            subnegotiate(&mut out, SET_PARITY, &[self.settings.parity]);
+            subnegotiate(&mut out, SET_STOPSIZE, &[self.settings.stop_size]);
+            subnegotiate(&mut out, SET_CONTROL, &[self.settings.flow_control]);
+        }
+        out
+    }
+
+    /// Take the Telnet commands out of bytes which have arrived, leaving the data in their place.
+    /// Returns the number of data bytes, and anything to send back in answer.
+    pub fn decode(&mut self, bytes: &mut [u8]) -> (usize, Vec<u8>) {
+        let mut reply = Vec::new();
+        let mut n = 0;
+        for i in 0..bytes.len() {
+            let byte = bytes[i];
+            self.state = match self.state {
+                State::Data if byte == IAC => State::Iac,
+                State::Data => {
+                    bytes[n] = byte;
+                    n += 1;
+                    State::Data
+                }
+                State::Iac => match byte {
+                    IAC => {
+                        bytes[n] = IAC;
+                        n += 1;
+                        State::Data
+                    }
+                    WILL | WONT | DO | DONT => State::Option(byte),
+                    SB => {
+                        self.subnegotiation.clear();
+                        State::Subnegotiation
+                    }
+                    // NOP, go ahead and the like
+                    _ => State::Data,
+                },
+                State::Option(command) => {
+                    self.negotiate(command, byte, &mut reply);
+                    State::Data
+                }
+                State::Subnegotiation if byte == IAC => State::SubnegotiationIac,
+                State::Subnegotiation => {
+                    self.subnegotiation.push(byte);
+                    State::Subnegotiation
+                }
+                State::SubnegotiationIac => match byte {
+                    SE => {
+                        self.subnegotiated(&mut reply);
+                        State::Data
+                    }
+                    IAC => {
+                        self.subnegotiation.push(IAC);
+                        State::Subnegotiation
+                    }
+                    _ => State::Data,
+                },
+            };
+        }
+        (n, reply)
+    }
+
+    /// Escape data to be sent.
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        escape(&mut out, data);
+        out
+    }
+
+    /// Answer a request to start or stop using an option, unless it only confirms what has
+    /// already been agreed.
+    fn negotiate(&mut self, command: u8, option: u8, reply: &mut Vec<u8>) {
+        let supported =
+            option == BINARY || option == SUPPRESS_GO_AHEAD || option == COM_PORT_OPTION;
+        match command {
+            DO if supported => {
+                if self.local.insert(option) {
+                    reply.extend_from_slice(&[IAC, WILL, option]);
+                }
+            }
+            DO => reply.extend_from_slice(&[IAC, WONT, option]),
+            DONT => {
+                if self.local.remove(&option) {
+                    reply.extend_from_slice(&[IAC, WONT, option]);
+                }
+            }
+            WILL if supported => {
+                if self.remote.insert(option) {
+                    reply.extend_from_slice(&[IAC, DO, option]);
+                }
+            }
+            WILL => reply.extend_from_slice(&[IAC, DONT, option]),
+            _ => {
+                if self.remote.remove(&option) {
+                    reply.extend_from_slice(&[IAC, DONT, option]);
+                }
+            }
+        }
+    }
+
+    fn subnegotiated(&mut self, reply: &mut Vec<u8>) {
+        if self.subnegotiation.len() < 2 || self.subnegotiation[0] != COM_PORT_OPTION {
+            return;
+        }
+        let command = self.subnegotiation[1];
+        let value = self.subnegotiation[2..].to_vec();
+        if !self.server {
+            // The server's answers, which give the settings it has actually applied
+            if command == SET_BAUDRATE + SERVER_OFFSET && value.len() == 4 {
+                let baud_rate = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+                println!("<uart: rfc2217 => server set baud rate {}", baud_rate);
+            }
+            return;
+        }
+
+        let answer = command + SERVER_OFFSET;
+        match command {
+            SIGNATURE => subnegotiate(reply, answer, b"nos3_io"),
+            SET_BAUDRATE if value.len() == 4 => {
+                let baud_rate = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+                if baud_rate != 0 && baud_rate != self.settings.baud_rate {
+                    self.settings.baud_rate = baud_rate;
+                    println!("<uart: rfc2217 => client set baud rate {}", baud_rate);
+                }
+                subnegotiate(reply, answer, &self.settings.baud_rate.to_be_bytes());
+            }
+            SET_DATASIZE | SET_PARITY | SET_STOPSIZE | SET_CONTROL if value.len() == 1 => {
+                let (setting, name) = match command {
+                    SET_DATASIZE => (&mut self.settings.data_size, "data size"),
+                    SET_PARITY => (&mut self.settings.parity, "parity"),
+                    SET_STOPSIZE => (&mut self.settings.stop_size, "stop size"),
+                    _ => (&mut self.settings.flow_control, "flow control"),
+                };
+                // 0 asks for the current value. Control values above 3 are DTR, RTS and break,
+                // which are only echoed.
+                let value = value[0];
+                if value == 0 || (command == SET_CONTROL && value > 3) {
+                    let current = if value == 0 { *setting } else { value };
+                    subnegotiate(reply, answer, &[current]);
+                    return;
+                }
+                if value != *setting {
+                    *setting = value;
+                    println!("<uart: rfc2217 => client set {} {}", name, value);
+                }
+                subnegotiate(reply, answer, &[value]);
+            }
+            SET_LINESTATE_MASK | SET_MODEMSTATE_MASK | PURGE_DATA => {
+                subnegotiate(reply, answer, &value)
+            }
+            // Flow control suspend and resume, which need no answer
+            _ => (),
+        }
+    }
+}
+
+/// Append a COM port control subnegotiation.
+fn subnegotiate(out: &mut Vec<u8>, command: u8, value: &[u8]) {
+    out.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, command]);
+    escape(out, value);
+    out.extend_from_slice(&[IAC, SE]);
+}
+
+fn escape(out: &mut Vec<u8>, data: &[u8]) {
+    for byte in data {
+        if *byte == IAC {
+            out.push(IAC);
+        }
+        out.push(*byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode the bytes arriving in each read in turn, and return the data and the replies.
+    fn decode_reads(telnet: &mut Telnet, reads: &[&[u8]]) -> (Vec<u8>, Vec<u8>) {
+        let (mut data, mut replies) = (Vec::new(), Vec::new());
+        for read in reads {
+            let mut bytes = read.to_vec();
+            let (n, reply) = telnet.decode(&mut bytes);
+            data.extend_from_slice(&bytes[..n]);
+            replies.extend(reply);
+        }
+        (data, replies)
+    }
+
+    fn answer(command: u8, value: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        subnegotiate(&mut out, command + SERVER_OFFSET, value);
+        out
+    }
+
+    #[test]
+    fn iac_is_doubled_in_data() {
+        let telnet = Telnet::server();
+        assert_eq!(telnet.encode(&[1, IAC, 2]), [1, IAC, IAC, 2]);
+
+        let mut telnet = Telnet::server();
+        let (data, replies) = decode_reads(&mut telnet, &[&[1, IAC], &[IAC, 2]]);
+        assert_eq!(data, [1, IAC, 2]);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn subnegotiations_can_be_split_across_reads() {
+        let mut telnet = Telnet::server();
+        let (data, replies) = decode_reads(
+            &mut telnet,
+            &[
+                &[b'a', IAC, SB, COM_PORT_OPTION, SET_BAUDRATE, 0x00],
+                &[0x01, 0xC2, 0x00, IAC],
+                &[SE, b'b'],
+            ],
+        );
+        assert_eq!(data, b"ab");
+        assert_eq!(replies, answer(SET_BAUDRATE, &115_200u32.to_be_bytes()));
+        assert_eq!(telnet.settings.baud_rate, 115_200);
+    }
+
+    #[test]
+    fn the_server_answers_baud_rate_requests() {
+        let mut telnet = Telnet::server();
+        let mut request = Vec::new();
+        // 0 asks for the current baud rate
+        subnegotiate(&mut request, SET_BAUDRATE, &[0, 0, 0, 0]);
+        let (_, replies) = decode_reads(&mut telnet, &[&request]);
+        assert_eq!(replies, answer(SET_BAUDRATE, &9600u32.to_be_bytes()));
+
+        // 0x0000FF00 has an IAC to escape in both directions
+        let mut request = Vec::new();
+        subnegotiate(&mut request, SET_BAUDRATE, &[0, 0, IAC, 0]);
+        let (_, replies) = decode_reads(&mut telnet, &[&request]);
+        assert_eq!(replies, answer(SET_BAUDRATE, &[0, 0, IAC, 0]));
+        assert_eq!(telnet.settings.baud_rate, 0xFF00);
+    }
+
+    #[test]
+    fn the_server_answers_control_requests() {
+        let mut telnet = Telnet::server();
+        let mut control = |value: u8| {
+            let mut request = Vec::new();
+            subnegotiate(&mut request, SET_CONTROL, &[value]);
+            decode_reads(&mut telnet, &[&request]).1
+        };
+        // 0 asks for the current flow control
+        assert_eq!(control(0), answer(SET_CONTROL, &[1]));
+        assert_eq!(control(3), answer(SET_CONTROL, &[3]));
+        assert_eq!(control(0), answer(SET_CONTROL, &[3]));
+        // DTR on is only echoed
+        assert_eq!(control(8), answer(SET_CONTROL, &[8]));
+        assert_eq!(control(0), answer(SET_CONTROL, &[3]));
+    }
+
+    #[test]
+    fn options_are_only_answered_once() {
+        let mut telnet = Telnet::server();
+        telnet.start();
+        let (_, replies) = decode_reads(&mut telnet, &[&[IAC, DO, BINARY, IAC, WILL, 99]]);
+        assert_eq!(replies, [IAC, DONT, 99]);
+        let (_, replies) = decode_reads(&mut telnet, &[&[IAC, DO, COM_PORT_OPTION]]);
+        assert_eq!(replies, [IAC, WILL, COM_PORT_OPTION]);
+    }
+
+    #[test]
+    fn the_client_asks_for_its_settings() {
+        let mut telnet = Telnet::client(115_200);
+        let start = telnet.start();
+        let mut request = Vec::new();
+        subnegotiate(&mut request, SET_BAUDRATE, &115_200u32.to_be_bytes());
+        assert!(start
+            .windows(request.len())
+            .any(|window| window == &request[..]));
+        // The server's answers need none
+        let (_, replies) = decode_reads(&mut telnet, &[&answer(SET_BAUDRATE, &[0, 1, 0xC2, 0])]);
+        assert!(replies.is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! Bridges a Linux spidev device to a NOS SPI bus.
+//!
+//! The device appears on the NOS bus as an `SPISlave` on the configured chip select. NOSEngine
+//! delivers a master's write and the read that follows it as two separate callbacks, but most
+//! devices expect the command and the response within one chip select. So they are paired by
+//! timing (see `pairing.rs`): a write followed within `transaction_window_us` by a read goes to
+//! the device with the read in one spidev message, with the chip select held.
+
+use crate::capture::{Capture, Direction};
+use crate::config;
+use crate::pairing::{self, Transfer};
+use crate::status::BridgeStatus;
+use nosengine_rust::client::spi::SPISlave;
+use nosengine_rust::ffi::spi::SPIDirection;
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use toml::value::Table;
+
+pub struct SPIConfig {
+    pub device_path: String,
+    pub nos_bus: String,
+    pub chip_select: u8,
+    pub mode: SpiModeFlags,
+    pub speed_hz: u32,
+    pub bits_per_word: u8,
+    pub transaction_window: Duration,
+    /// Record to the capture file, if there is one
+    pub capture: bool,
+}
+
+impl SPIConfig {
+    pub fn from_table(name: &str, mut table: Table) -> SPIConfig {
+        let section = format!("spi.{}", name);
+        let device_path = config::required(&mut table, &section, "device_path");
+        let nos_bus = config::required(&mut table, &section, "nos_bus");
+        let chip_select = config::required(&mut table, &section, "chip_select");
+        let mode = match config::optional::<u8>(&mut table, &section, "mode").unwrap_or(0) {
+            0 => SpiModeFlags::SPI_MODE_0,
+            1 => SpiModeFlags::SPI_MODE_1,
+            2 => SpiModeFlags::SPI_MODE_2,
+            3 => SpiModeFlags::SPI_MODE_3,
+            mode => panic!(
+                "Error parsing config.toml: [{}] 'mode' must be 0-3, not {}",
+                section, mode
+            ),
+        };
+        let speed_hz = config::optional(&mut table, &section, "speed_hz").unwrap_or(1_000_000);
+        let bits_per_word = config::optional(&mut table, &section, "bits_per_word").unwrap_or(8);
+        let transaction_window = Duration::from_micros(
+            config::optional(&mut table, &section, "transaction_window_us").unwrap_or(1000),
+        );
+        let capture = config::optional(&mut table, &section, "capture").unwrap_or(true);
+
+        SPIConfig {
+            device_path,
+            nos_bus,
+            chip_select,
+            mode,
+            speed_hz,
+            bits_per_word,
+            transaction_window,
+            capture,
+        }
+    }
+}
+
+pub fn spi_init(config: SPIConfig, status: Arc<BridgeStatus>, capture: Capture) {
+    let mut spi = match Spidev::open(&config.device_path) {
+        Ok(spi) => spi,
+        Err(err) => {
+            println!(
+                "Error opening the SPI device '{}', details: {}",
+                &config.device_path, err
+            );
+            return;
+        }
+    };
+    let options = SpidevOptions::new()
+        .mode(config.mode)
+        .max_speed_hz(config.speed_hz)
+        .bits_per_word(config.bits_per_word)
+        .build();
+    if let Err(err) = spi.configure(&options) {
+        println!(
+            "Error configuring the SPI device '{}', details: {}",
+            &config.device_path, err
+        );
+        return;
+    }
+
+    // There is only the one device
+    let (callbacks, mut pairing) = pairing::pairing(config.transaction_window);
+    let callbacks = callbacks.slave(());
+    let _slave = match SPISlave::with_handler(
+        config.chip_select,
+        crate::NOS_CONNECTION,
+        &config.nos_bus,
+        move |dir, data| match dir {
+            SPIDirection::Write => callbacks.write(data),
+            SPIDirection::Read => callbacks.read(data),
+        },
+    ) {
+        Ok(slave) => {
+            println!("Established SPI connection to NOS! Starting...");
+            slave
+        }
+        Err(err) => {
+            println!(
+                "<spi: error => connecting chip select {} to NOS: {}",
+                config.chip_select, err
+            );
+            return;
+        }
+    };
+
+    // Keep this thread working for the lifetime of the program
+    while let Some(next) = pairing.next() {
+        match next {
+            Transfer::Write((), tx, ack) => {
+                let result = transfer(&spi, &tx, 0, &status, &capture);
+                if let Err(ref err) = result {
+                    println!("<spi: error => {}", err);
+                }
+                ack.send(result.is_ok());
+            }
+            Transfer::Read((), tx, len, reply) => {
+                let received = Instant::now();
+                let rx = match transfer(&spi, &tx, len, &status, &capture) {
+                    Ok(rx) => rx,
+                    Err(err) => {
+                        println!("<spi: error => {}", err);
+                        Vec::new()
+                    }
+                };
+                let _ = reply.send(rx);
+                status.latency(received.elapsed());
+            }
+        }
+    }
+}
+
+/// Writes `tx`, then reads `rx_len` bytes, without releasing the chip select in between.
+fn transfer(
+    spi: &Spidev,
+    tx: &[u8],
+    rx_len: usize,
+    status: &BridgeStatus,
+    capture: &Capture,
+) -> io::Result<Vec<u8>> {
+    let mut rx = vec![0u8; rx_len];
+    {
+        let mut transfers = Vec::with_capacity(2);
+        if !tx.is_empty() {
+            transfers.push(SpidevTransfer::write(tx));
+        }
+        if rx_len > 0 {
+            transfers.push(SpidevTransfer::read(&mut rx));
+        }
+        if !transfers.is_empty() {
+            if let Err(err) = spi.transfer_multiple(&mut transfers) {
+                if !tx.is_empty() {
+                    capture.record_failure(Direction::NosToHardware, tx, &err);
+                }
+                if rx_len > 0 {
+                    capture.record_failure(Direction::HardwareToNos, &[], &err);
+                }
+                status.failure(&err);
+                return Err(err);
+            }
+        }
+    }
+    status.transfer();
+    if !tx.is_empty() {
+        capture.record(Direction::NosToHardware, tx);
+    }
+    if rx_len > 0 {
+        capture.record(Direction::HardwareToNos, &rx);
+    }
+    Ok(rx)
+}
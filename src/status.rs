@@ -0,0 +1,270 @@
+//! Counters kept by running bridges, shown by the `status` and `stats` commands.
+
+use crate::capture::Direction;
+use nix::errno::Errno;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct BridgeStatus {
+    stopped: AtomicBool,
+    transfers: AtomicUsize,
+    retries: AtomicUsize,
+    failures: AtomicUsize,
+    last_error: Mutex<Option<String>>,
+    /// Counters particular to one kind of bridge, in the order they were first counted
+    counters: Mutex<Vec<(&'static str, usize)>>,
+    /// Bytes and frames passed, hardware to NOS first
+    bytes: [AtomicUsize; 2],
+    frames: [AtomicUsize; 2],
+    /// Failures by category, in the order they first happened
+    errors: Mutex<Vec<(String, usize)>>,
+    reconnects: AtomicUsize,
+    last_activity: Mutex<Option<Instant>>,
+    latency: Mutex<Latency>,
+}
+
+/// How long the bridge took to pass data on, from when it arrived.
+#[derive(Default)]
+struct Latency {
+    samples: u32,
+    total: Duration,
+    min: Option<Duration>,
+    max: Duration,
+}
+
+impl BridgeStatus {
+    pub fn new() -> BridgeStatus {
+        BridgeStatus::default()
+    }
+
+    /// A transfer with the hardware succeeded.
+    pub fn transfer(&self) {
+        self.transfers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A transfer with the hardware failed, and is being tried again.
+    pub fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request from the simulation failed, after any retries.
+    pub fn failure(&self, err: &io::Error) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(err.to_string());
+        let category = category(err);
+        let mut errors = self.errors.lock().unwrap();
+        match errors.iter_mut().find(|(name, _)| *name == category) {
+            Some((_, count)) => *count += 1,
+            None => errors.push((category, 1)),
+        }
+    }
+
+    /// Add `n` to a counter particular to this kind of bridge, e.g. "ccsds packets".
+    pub fn count(&self, counter: &'static str, n: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        match counters.iter_mut().find(|(name, _)| *name == counter) {
+            Some((_, count)) => *count += n,
+            None => counters.push((counter, n)),
+        }
+    }
+
+    /// A frame of `len` bytes passed through the bridge.
+    pub fn traffic(&self, direction: Direction, len: usize) {
+        let index = match direction {
+            Direction::HardwareToNos => 0,
+            Direction::NosToHardware => 1,
+        };
+        self.bytes[index].fetch_add(len, Ordering::Relaxed);
+        self.frames[index].fetch_add(1, Ordering::Relaxed);
+        *self.last_activity.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// A connection to an endpoint was made again, after an earlier one.
+    pub fn reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Data which arrived `latency` ago has been passed on.
+    pub fn latency(&self, latency: Duration) {
+        let mut stats = self.latency.lock().unwrap();
+        stats.samples += 1;
+        stats.total += latency;
+        stats.min = Some(stats.min.map_or(latency, |min| min.min(latency)));
+        stats.max = stats.max.max(latency);
+    }
+
+    /// The bridge's thread has finished.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Start every count again from nothing.
+    pub fn reset(&self) {
+        let counters = [
+            &self.transfers,
+            &self.retries,
+            &self.failures,
+            &self.reconnects,
+        ];
+        for counter in counters
+            .iter()
+            .copied()
+            .chain(&self.bytes)
+            .chain(&self.frames)
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+        *self.last_error.lock().unwrap() = None;
+        self.counters.lock().unwrap().clear();
+        self.errors.lock().unwrap().clear();
+        *self.last_activity.lock().unwrap() = None;
+        *self.latency.lock().unwrap() = Latency::default();
+    }
+
+    /// Whether the bridge is still running, and when it last passed anything.
+    fn state(&self) -> String {
+        let state = if self.stopped.load(Ordering::Relaxed) {
+            "stopped"
+        } else {
+            "running"
+        };
+        match *self.last_activity.lock().unwrap() {
+            Some(at) => format!(
+                "{}, last activity {:.1} s ago",
+                state,
+                at.elapsed().as_secs_f64()
+            ),
+            None => format!("{}, no activity", state),
+        }
+    }
+
+    /// Every count, one line to each kind, for the `stats` command.
+    pub fn stats(&self) -> Vec<String> {
+        let mut lines = vec![self.state()];
+        for (index, direction) in [Direction::HardwareToNos, Direction::NosToHardware]
+            .iter()
+            .enumerate()
+        {
+            lines.push(format!(
+                "{}: {} bytes in {} frames",
+                direction,
+                self.bytes[index].load(Ordering::Relaxed),
+                self.frames[index].load(Ordering::Relaxed)
+            ));
+        }
+        let errors: Vec<String> = self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(category, count)| format!("{} {}", count, category))
+            .collect();
+        lines.push(format!(
+            "errors: {}, {} retries, {} reconnects",
+            if errors.is_empty() {
+                "none".to_string()
+            } else {
+                errors.join(", ")
+            },
+            self.retries.load(Ordering::Relaxed),
+            self.reconnects.load(Ordering::Relaxed)
+        ));
+        let latency = self.latency.lock().unwrap();
+        lines.push(match latency.min {
+            Some(min) => format!(
+                "latency: min {:.3} ms, avg {:.3} ms, max {:.3} ms, over {} frames",
+                min.as_secs_f64() * 1e3,
+                (latency.total / latency.samples).as_secs_f64() * 1e3,
+                latency.max.as_secs_f64() * 1e3,
+                latency.samples
+            ),
+            None => "latency: nothing passed on yet".to_string(),
+        });
+        let counters = self.counters.lock().unwrap();
+        if !counters.is_empty() {
+            let counters: Vec<String> = counters
+                .iter()
+                .map(|(name, count)| format!("{} {}", count, name))
+                .collect();
+            lines.push(counters.join(", "));
+        }
+        lines
+    }
+}
+
+/// What kind of error it was: the Linux error number's name if it has one, such as `ENXIO` for
+/// a NACK, or else the kind of I/O error, such as `TimedOut`. An error wrapping another, as with
+/// the device's address, is categorised by the error it wraps.
+fn category(err: &io::Error) -> String {
+    let wrapped = err
+        .get_ref()
+        .and_then(|inner| inner.source())
+        .and_then(|source| source.downcast_ref::<io::Error>());
+    match err
+        .raw_os_error()
+        .or_else(|| wrapped.and_then(io::Error::raw_os_error))
+    {
+        Some(errno) => format!("{:?}", Errno::from_i32(errno)),
+        None => format!("{:?}", wrapped.unwrap_or(err).kind()),
+    }
+}
+
+/// A one-line summary of `stats`, for the `status` command.
+impl fmt::Display for BridgeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} transfers, {} {} and {} {} frames, {} failures, {} retries, {} reconnects",
+            self.state(),
+            self.transfers.load(Ordering::Relaxed),
+            self.frames[0].load(Ordering::Relaxed),
+            Direction::HardwareToNos,
+            self.frames[1].load(Ordering::Relaxed),
+            Direction::NosToHardware,
+            self.failures.load(Ordering::Relaxed),
+            self.retries.load(Ordering::Relaxed),
+            self.reconnects.load(Ordering::Relaxed)
+        )?;
+        for (name, count) in self.counters.lock().unwrap().iter() {
+            write!(f, ", {} {}", count, name)?;
+        }
+        if let Some(ref err) = *self.last_error.lock().unwrap() {
+            write!(f, " (last: {})", err)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_sums_up_stats_on_one_line() {
+        let status = BridgeStatus::new();
+        status.traffic(Direction::HardwareToNos, 4);
+        status.traffic(Direction::NosToHardware, 2);
+        status.traffic(Direction::NosToHardware, 2);
+        status.transfer();
+        status.retry();
+        status.count("pty frames dropped", 3);
+        status.failure(&io::Error::new(io::ErrorKind::TimedOut, "no answer"));
+        let summary = status.to_string();
+        assert!(!summary.contains('\n'));
+        assert!(summary.starts_with("running, last activity"));
+        assert!(summary.contains(
+            "1 transfers, 1 HW->NOS and 2 NOS->HW frames, 1 failures, 1 retries, 0 reconnects"
+        ));
+        assert!(summary.ends_with(", 3 pty frames dropped (last: no answer)"));
+
+        status.stop();
+        status.reset();
+        assert!(status
+            .to_string()
+            .starts_with("stopped, no activity, 0 transfers"));
+    }
+}
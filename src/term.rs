@@ -0,0 +1,231 @@
+//! Turns the console into a raw terminal on a NOS UART port, like minicom, for the `attach`
+//! command and `nos3_io term`.
+//!
+//! Every key is sent to the port as it is typed, and everything from the port is printed as it
+//! arrives, until the escape key, Ctrl-], detaches. With `echo`, typed keys are also printed, for
+//! consoles which do not echo. With `crlf`, Enter is sent as CR LF and a lone LF from the port is
+//! printed as CR LF. With `log=<path>`, everything sent and received is appended to a file.
+
+use crate::endpoint::POLL_INTERVAL;
+use nix::poll::{self, PollFd, PollFlags};
+use nix::sys::termios::{self, SetArg, Termios};
+use nix::unistd;
+use nosengine_rust::client::uart::UART;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+/// Ctrl-], as in telnet
+const ESCAPE: u8 = 0x1D;
+
+/// Port a bridge takes on its NOS UART, and so the default for a terminal
+pub const DEFAULT_PORT: u8 = 1;
+
+pub struct Options {
+    pub echo: bool,
+    pub crlf: bool,
+    pub log: Option<String>,
+}
+
+impl Options {
+    /// Parse `echo`, `crlf` and `log=<path>`, in any order.
+    pub fn parse(args: &[&str]) -> Result<Options, String> {
+        let mut options = Options {
+            echo: false,
+            crlf: false,
+            log: None,
+        };
+        for arg in args {
+            match *arg {
+                "echo" => options.echo = true,
+                "crlf" => options.crlf = true,
+                "log=" => return Err("'log=' needs a path".to_string()),
+                arg if arg.starts_with("log=") => options.log = Some(arg[4..].to_string()),
+                arg => return Err(format!("unknown option '{}'", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Puts the console back as it was, however the terminal ends.
+struct RawMode {
+    saved: Termios,
+}
+
+impl RawMode {
+    fn enter() -> io::Result<RawMode> {
+        let saved = termios::tcgetattr(0)?;
+        let mut raw = saved.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(0, SetArg::TCSANOW, &raw)?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(0, SetArg::TCSANOW, &self.saved);
+    }
+}
+
+/// Connect the console to `port` on the NOS UART `nos_bus`, until the escape key is pressed.
+pub fn run(nos_bus: &str, port: u8, options: &Options) -> io::Result<()> {
+    let uart = UART::new("nos3_io_term", crate::NOS_CONNECTION, nos_bus, port).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "NOS connection failure. Try restarting NOS3",
+        )
+    })?;
+    let mut log = match options.log {
+        Some(ref path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    println!(
+        "<term: connected to '{}' port {} => Ctrl-] to detach",
+        nos_bus, port
+    );
+
+    let raw = RawMode::enter()?;
+    let stdout = io::stdout();
+    let mut keys = [0u8; 64];
+    // Whether the last byte from NOS was a CR, as a CR LF can be split across reads
+    let mut after_cr = false;
+    let result = loop {
+        // keys to NOS
+        let mut fds = [PollFd::new(0, PollFlags::POLLIN)];
+        if poll::poll(&mut fds, POLL_INTERVAL.as_millis() as i32)? > 0 {
+            let n = unistd::read(0, &mut keys)?;
+            let typed = &keys[..n];
+            let (typed, detach) = match typed.iter().position(|key| *key == ESCAPE) {
+                Some(at) => (&typed[..at], true),
+                None => (typed, n == 0),
+            };
+            let mut sent = Vec::with_capacity(typed.len());
+            for key in typed {
+                sent.push(*key);
+                if options.crlf && *key == b'\r' {
+                    sent.push(b'\n');
+                }
+            }
+            if !sent.is_empty() {
+                uart.write(&sent);
+                if options.echo {
+                    let mut out = stdout.lock();
+                    out.write_all(&sent)?;
+                    out.flush()?;
+                }
+                write_log(&mut log, &sent);
+            }
+            if detach {
+                break Ok(());
+            }
+        }
+
+        // bytes from NOS to the console
+        let received = uart.read(512);
+        if !received.is_empty() {
+            write_log(&mut log, &received);
+            let shown = if options.crlf {
+                to_crlf(&received, &mut after_cr)
+            } else {
+                received
+            };
+            let mut out = stdout.lock();
+            out.write_all(&shown)?;
+            out.flush()?;
+        }
+    };
+    drop(raw);
+    println!("\r\n<term: detached from '{}' port {}", nos_bus, port);
+    result
+}
+
+/// Print each lone LF as CR LF. `after_cr` says whether the byte before `data` was a CR, and is
+/// updated for the next call.
+fn to_crlf(data: &[u8], after_cr: &mut bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for byte in data {
+        if *byte == b'\n' && !*after_cr {
+            out.push(b'\r');
+        }
+        out.push(*byte);
+        *after_cr = *byte == b'\r';
+    }
+    out
+}
+
+fn write_log(log: &mut Option<File>, data: &[u8]) {
+    if let Some(ref mut file) = *log {
+        if let Err(err) = file.write_all(data) {
+            println!("\r\n<term: error => logging: {}\r", err);
+        }
+    }
+}
+
+/// `nos3_io term --bus usart_1 [--port 1] [echo] [crlf] [log=path]`. Returns the exit code.
+pub fn main(args: &[String]) -> i32 {
+    let mut bus = None;
+    let mut port = DEFAULT_PORT;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bus" => bus = args.next().cloned(),
+            "--port" => match args.next().map(|port| port.parse()) {
+                Some(Ok(parsed)) => port = parsed,
+                _ => {
+                    eprintln!("term: --port must be a number from 0 to 255");
+                    return 2;
+                }
+            },
+            arg => rest.push(arg),
+        }
+    }
+    let bus = match bus {
+        Some(bus) => bus,
+        None => {
+            eprintln!("usage: nos3_io term --bus [nos bus] [--port N] [echo] [crlf] [log=path]");
+            return 2;
+        }
+    };
+    let result = Options::parse(&rest)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+        .and_then(|options| run(&bus, port, &options));
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("term: {}", err);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lone_lfs_are_shown_as_crlf() {
+        let mut after_cr = false;
+        assert_eq!(to_crlf(b"a\nb\r\nc", &mut after_cr), b"a\r\nb\r\nc");
+        assert_eq!(to_crlf(b"\n\n", &mut after_cr), b"\r\n\r\n");
+        assert_eq!(to_crlf(b"", &mut after_cr), b"");
+        // A CR LF split across reads stays as it is
+        assert_eq!(to_crlf(b"ok\r", &mut after_cr), b"ok\r");
+        assert!(after_cr);
+        assert_eq!(to_crlf(b"\nx", &mut after_cr), b"\nx");
+        assert!(!after_cr);
+    }
+
+    #[test]
+    fn options_are_parsed_in_any_order() {
+        let options = Options::parse(&["log=/tmp/fsw.log", "crlf", "echo"]).unwrap();
+        assert!(options.echo && options.crlf);
+        assert_eq!(options.log, Some(String::from("/tmp/fsw.log")));
+
+        let options = Options::parse(&[]).unwrap();
+        assert!(!options.echo && !options.crlf && options.log.is_none());
+        assert!(Options::parse(&["log="]).is_err());
+        assert!(Options::parse(&["binary"]).is_err());
+    }
+}
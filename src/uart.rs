@@ -0,0 +1,390 @@
+//! Bridges a serial port, or another endpoint (see `endpoint.rs`), to a NOS UART.
+//!
+//! Bytes are passed in frames, as set by the bridge's `framing` (see `framing.rs`), so each frame
+//! reaches the other side in one write. Without `framing`, bytes from NOS are passed on as they
+//! come, and only bytes from the endpoints wait for the idle gap.
+//!
+//! NOSEngine allows only two connections to a UART port, so a bridge can instead be a hub: it
+//! takes one of the two connections, and shares it between several endpoints. Every endpoint
+//! which may read is sent every frame from NOS. Frames from endpoints which may write are sent to
+//! NOS whole, endpoint by endpoint in the order of the config, so that frames from different
+//! endpoints never interleave and always arrive in the same order. The bridge waits on all of its
+//! endpoints at once, so however many there are, NOS is checked at least every `POLL_INTERVAL`.
+//!
+//! Bytes from the `send` command (see `inject.rs`) are sent to NOS between endpoints' frames.
+
+use crate::capture::{Capture, Direction};
+use crate::ccsds::PrimaryHeader;
+use crate::config;
+use crate::endpoint::{Endpoint, EndpointConfig, POLL_INTERVAL};
+use crate::framing::{Framer, Framing, FramingConfig};
+use crate::inject::{Injection, RESPONSE_WINDOW};
+use crate::status::BridgeStatus;
+use nix::poll::{self, PollFd, PollFlags};
+use nosengine_rust::client::uart::UART;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::Instant;
+use toml::value::Table;
+
+/// What an endpoint may do with the NOS UART.
+#[derive(Clone, Copy)]
+pub struct Access {
+    /// Sent the bytes from NOS
+    pub read: bool,
+    /// Sends bytes to NOS
+    pub write: bool,
+}
+
+impl Access {
+    fn from_table(section: &str, table: &mut Table) -> Access {
+        let access = config::optional::<String>(table, section, "access")
+            .unwrap_or_else(|| "read_write".to_string());
+        match access.as_str() {
+            "read" => Access {
+                read: true,
+                write: false,
+            },
+            "write" => Access {
+                read: false,
+                write: true,
+            },
+            "read_write" => Access {
+                read: true,
+                write: true,
+            },
+            access => panic!(
+                "Error parsing config.toml: [{}] unknown 'access' {}",
+                section, access
+            ),
+        }
+    }
+}
+
+pub struct UARTConfig {
+    /// One endpoint, or several for a hub
+    pub endpoints: Vec<(EndpointConfig, Access)>,
+    pub nos_bus: String,
+    /// Framing of bytes from the endpoints
+    pub framing: FramingConfig,
+    /// Framing of bytes from NOS
+    pub outgoing_framing: FramingConfig,
+    /// Record to the capture file, if there is one
+    pub capture: bool,
+    /// Session file to record to, for the `replay` command
+    pub record: Option<String>,
+}
+
+impl UARTConfig {
+    pub fn from_table(name: &str, mut table: Table) -> UARTConfig {
+        let section = format!("uart.{}", name);
+        // e.g. hub = [{ endpoint = "pty", pty_link = "/tmp/fsw" }, { endpoint = "tcp_listen",
+        // listen = "0.0.0.0:4002", access = "read" }]
+        let endpoints = match config::optional::<Vec<Table>>(&mut table, &section, "hub") {
+            Some(hub) => hub
+                .into_iter()
+                .map(|mut entry| {
+                    let endpoint = EndpointConfig::from_table(&section, &mut entry);
+                    let access = Access::from_table(&section, &mut entry);
+                    if access.write && !endpoint.readable() {
+                        panic!(
+                            "Error parsing config.toml: [{}] {} can only have 'access' \"read\"",
+                            section, endpoint
+                        );
+                    }
+                    (endpoint, access)
+                })
+                .collect(),
+            None => {
+                let endpoint = EndpointConfig::from_table(&section, &mut table);
+                let access = Access {
+                    read: true,
+                    write: endpoint.readable(),
+                };
+                vec![(endpoint, access)]
+            }
+        };
+        if endpoints.is_empty() {
+            panic!("Error parsing config.toml: [{}] 'hub' is empty", section);
+        }
+        let nos_bus = config::required(&mut table, &section, "nos_bus");
+        let (framing, outgoing_framing) = FramingConfig::from_table(&section, &mut table);
+        let capture = config::optional(&mut table, &section, "capture").unwrap_or(true);
+        let record = config::optional(&mut table, &section, "record");
+
+        UARTConfig {
+            endpoints,
+            nos_bus,
+            framing,
+            outgoing_framing,
+            capture,
+            record,
+        }
+    }
+
+    /// Describe the endpoints, for printing.
+    pub fn describe_endpoints(&self) -> String {
+        let endpoints: Vec<String> = self
+            .endpoints
+            .iter()
+            .map(|(endpoint, _)| endpoint.to_string())
+            .collect();
+        endpoints.join(", ")
+    }
+}
+
+/// One of a bridge's endpoints, while the bridge runs.
+struct Port {
+    endpoint: Box<dyn Endpoint>,
+    readable: bool,
+    access: Access,
+    /// Collects the bytes from the endpoint into frames
+    incoming: Framer,
+}
+
+pub fn uart_init(
+    config: UARTConfig,
+    status: Arc<BridgeStatus>,
+    capture: Capture,
+    injections: Receiver<Injection>,
+) {
+    let uart = match UART::new("fsw", crate::NOS_CONNECTION, &config.nos_bus, 1) {
+        Ok(uart) => {
+            println!("Established UART connection to NOS! Starting...");
+            uart
+        }
+        Err(_) => {
+            println!("NOS connection failure. Try restarting NOS3");
+            return;
+        }
+    };
+
+    let mut ports = Vec::new();
+    for (endpoint, access) in &config.endpoints {
+        match endpoint.open(&status) {
+            Ok(opened) => ports.push(Port {
+                endpoint: opened,
+                readable: endpoint.readable(),
+                access: *access,
+                incoming: Framer::new(config.framing.clone(), status.clone()),
+            }),
+            Err(err) => {
+                println!("Error opening the {}, details: {}", endpoint, err);
+                return;
+            }
+        }
+    }
+    let mut in_buf: Vec<u8> = vec![0; 512]; // transient incoming data read, differs each loop iteration
+    let (packets, log_packets) = match config.framing.framing {
+        Framing::Ccsds(ref ccsds) => (true, ccsds.log),
+        _ => (false, false),
+    };
+    let mut outgoing = Framer::new(config.outgoing_framing, status.clone());
+    // Where to pass NOS's answer to the last injection, until when
+    let mut listener: Option<(Sender<Vec<u8>>, Instant)> = None;
+
+    // Keep this thread working for the lifetime of the program
+    loop {
+        // incoming UART data to NOS UART, endpoint by endpoint. Waiting for up to POLL_INTERVAL
+        // keeps the loop from spinning.
+        let ready = wait_readable(&ports);
+        for (port, ready) in ports.iter_mut().zip(ready) {
+            if !port.readable {
+                continue;
+            }
+            let read = if ready {
+                port.endpoint.read(in_buf.as_mut_slice())
+            } else {
+                Ok(0)
+            };
+            let mut frames = match read {
+                Ok(n) => port.incoming.push(&in_buf[..n]),
+                // Nothing arrived while the read waited
+                Err(ref err) if err.kind() == ErrorKind::TimedOut => Vec::new(),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => Vec::new(),
+                // Counted rather than printed, since a port which has gone fails every read
+                Err(err) => {
+                    status.failure(&err);
+                    Vec::new()
+                }
+            };
+            frames.extend(port.incoming.poll());
+            // Latency is from when the frames were completed, once the read had returned their
+            // last bytes, rather than from when the read started waiting for them
+            let completed = Instant::now();
+            // Endpoints which may not write are still read, so that they are never blocked
+            if !port.access.write {
+                continue;
+            }
+            for frame in frames {
+                if log_packets {
+                    log_packet("serial => NOS", &frame);
+                }
+                record(&capture, packets, Direction::HardwareToNos, &frame);
+                uart.write(&frame);
+                status.transfer();
+                status.latency(completed.elapsed());
+            }
+        }
+
+        // bytes from the send command to NOS UART
+        while let Ok(injection) = injections.try_recv() {
+            capture.record(Direction::HardwareToNos, &injection.data);
+            uart.write(&injection.data);
+            status.transfer();
+            listener = Some((injection.responses, Instant::now() + RESPONSE_WINDOW));
+        }
+        if listener
+            .as_ref()
+            .map_or(false, |(_, until)| Instant::now() >= *until)
+        {
+            listener = None;
+        }
+
+        // outgoing NOS data to every endpoint which may read it
+        let mut frames = outgoing.push(&uart.read(512));
+        frames.extend(outgoing.poll());
+        // Frames completed together are all timed from then, so each one's latency includes
+        // passing on the ones before it
+        let completed = Instant::now();
+        for frame in frames {
+            if log_packets {
+                log_packet("NOS => serial", &frame);
+            }
+            record(&capture, packets, Direction::NosToHardware, &frame);
+            if let Some((ref responses, _)) = listener {
+                let _ = responses.send(frame.clone());
+            }
+            // One transfer to each frame, however many endpoints it went to, as for the frames
+            // counted in `stats`
+            let mut delivered = false;
+            for port in ports.iter_mut().filter(|port| port.access.read) {
+                match port.endpoint.write_all(&frame) {
+                    Ok(()) => delivered = true,
+                    Err(err) => {
+                        println!("<uart: error => {}", err);
+                        status.failure(&err);
+                    }
+                }
+            }
+            if delivered {
+                status.transfer();
+            }
+            status.latency(completed.elapsed());
+        }
+    }
+}
+
+/// Wait for up to `POLL_INTERVAL` for any endpoint to have bytes to read. Returns whether to read
+/// each port: if one of its endpoint's descriptors is readable, or if it has none to wait on.
+fn wait_readable(ports: &[Port]) -> Vec<bool> {
+    let fds: Vec<Vec<RawFd>> = ports
+        .iter()
+        .map(|port| {
+            if port.readable {
+                port.endpoint.poll_fds()
+            } else {
+                Vec::new()
+            }
+        })
+        .collect();
+    let mut poll_fds: Vec<PollFd> = fds
+        .iter()
+        .flatten()
+        .map(|fd| PollFd::new(*fd, PollFlags::POLLIN))
+        .collect();
+    if poll::poll(&mut poll_fds, POLL_INTERVAL.as_millis() as i32).is_err() {
+        // Interrupted, or unable to wait: try every port
+        return vec![true; ports.len()];
+    }
+    // Anything returned, errors and hang-ups included, is for the read to find. Every port's
+    // descriptors are counted, so that the next port starts at its own.
+    let mut returned = poll_fds
+        .iter()
+        .map(|fd| fd.revents().map_or(false, |events| !events.is_empty()));
+    fds.iter()
+        .map(|fds| {
+            fds.is_empty() || fds.iter().filter(|_| returned.next() == Some(true)).count() > 0
+        })
+        .collect()
+}
+
+/// Record a frame passing through the bridge, described by its primary header if it is a space
+/// packet.
+fn record(capture: &Capture, packet: bool, direction: Direction, frame: &[u8]) {
+    if packet {
+        capture.record_packet(direction, frame);
+    } else {
+        capture.record(direction, frame);
+    }
+}
+
+/// Print the primary header of a space packet passing through the bridge.
+fn log_packet(direction: &str, packet: &[u8]) {
+    if let Some(header) = PrimaryHeader::parse(packet) {
+        println!("<uart: {} => {}", direction, header);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::Framing;
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::process;
+    use std::time::Duration;
+
+    /// A port on a new pty, linked from a temporary path, which is returned with it.
+    fn pty_port(name: &str) -> (Port, String) {
+        let link = env::temp_dir()
+            .join(format!("nos3_io_{}_{}", process::id(), name))
+            .to_string_lossy()
+            .into_owned();
+        let status = Arc::new(BridgeStatus::new());
+        let config = EndpointConfig::Pty {
+            link: Some(link.clone()),
+        };
+        let framing = FramingConfig {
+            framing: Framing::Raw,
+            max_frame: None,
+            flush_timeout: None,
+        };
+        let port = Port {
+            endpoint: config.open(&status).unwrap(),
+            readable: true,
+            access: Access {
+                read: true,
+                write: true,
+            },
+            incoming: Framer::new(framing, status),
+        };
+        (port, link)
+    }
+
+    #[test]
+    fn the_hub_reads_only_endpoints_with_bytes() {
+        let (first, _) = pty_port("hub_first");
+        let (second, link) = pty_port("hub_second");
+        let ports = [first, second];
+
+        assert_eq!(wait_readable(&ports), [false, false]);
+
+        let mut tty = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&link)
+            .unwrap();
+        tty.write_all(b"x").unwrap();
+        let started = Instant::now();
+        let mut ready = wait_readable(&ports);
+        while ready == [false, false] && started.elapsed() < Duration::from_secs(1) {
+            ready = wait_readable(&ports);
+        }
+        assert_eq!(ready, [false, true]);
+    }
+}
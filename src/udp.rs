@@ -0,0 +1,294 @@
+//! Bridges UDP datagrams to `DataNode` messages on a NOS bus, for ground tools such as cFS
+//! CI_LAB and TO_LAB.
+//!
+//! Each datagram arriving on `listen` is sent as a message to the `destination` node, and each
+//! message arriving at the bridge's own node is sent as a datagram to `send_to`. In request mode
+//! both directions wait for an answer: a datagram is sent as a request message and the reply goes
+//! back to the datagram's sender, and a message from NOS is sent on as a datagram and the first
+//! datagram back is the reply. Either way the wait is bounded by `reply_timeout`, and a request
+//! which fails or times out is answered with `error_reply` followed by the reason, so that the
+//! requester can tell it from an empty answer.
+//!
+//! NOSEngine waits for the reply to a request without a timeout of its own, so datagrams are sent
+//! on as requests from a node on a thread of their own. If a request times out, that node is left
+//! waiting and the next datagram is sent from a new one.
+
+use crate::capture::{Capture, Direction};
+use crate::config;
+use crate::status::BridgeStatus;
+use nosengine_rust::client::{Bus, DataNode, NosError};
+use std::io;
+use std::net::UdpSocket;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use toml::value::Table;
+
+/// Largest datagram which can be received
+const MAX_DATAGRAM: usize = 65_535;
+
+pub struct UDPConfig {
+    pub nos_bus: String,
+    pub nos_node: String,
+    /// Node which datagrams are sent on to
+    pub destination: String,
+    /// Local address datagrams arrive on
+    pub listen: String,
+    /// Address messages from NOS are sent on to
+    pub send_to: String,
+    pub request_reply: bool,
+    /// How long a request waits for its answer, in request mode
+    pub reply_timeout: Duration,
+    /// Sent back, followed by the reason, when a request fails or times out
+    pub error_reply: String,
+    /// Record to the capture file, if there is one
+    pub capture: bool,
+}
+
+impl UDPConfig {
+    pub fn from_table(name: &str, mut table: Table) -> UDPConfig {
+        let section = format!("udp.{}", name);
+        let nos_bus = config::required(&mut table, &section, "nos_bus");
+        let nos_node = config::optional(&mut table, &section, "nos_node")
+            .unwrap_or_else(|| format!("nos3_io_udp_{}", name));
+        let destination = config::required(&mut table, &section, "destination");
+        let listen = config::required(&mut table, &section, "listen");
+        let send_to = config::required(&mut table, &section, "send_to");
+        let mode = config::optional::<String>(&mut table, &section, "mode")
+            .unwrap_or_else(|| "message".to_string());
+        let request_reply = match mode.as_str() {
+            "message" => false,
+            "request" => true,
+            mode => panic!(
+                "Error parsing config.toml: [{}] unknown 'mode' {}",
+                section, mode
+            ),
+        };
+        let reply_timeout = config::optional(&mut table, &section, "reply_timeout_ms")
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| Duration::from_secs(1));
+        let error_reply = config::optional(&mut table, &section, "error_reply")
+            .unwrap_or_else(|| "nos3_io error".to_string());
+        let capture = config::optional(&mut table, &section, "capture").unwrap_or(true);
+
+        UDPConfig {
+            nos_bus,
+            nos_node,
+            destination,
+            listen,
+            send_to,
+            request_reply,
+            reply_timeout,
+            error_reply,
+            capture,
+        }
+    }
+}
+
+/// Sends requests to NOS from a node on a thread of its own, so that waiting for the reply can
+/// time out.
+struct Requester {
+    requests: Sender<Vec<u8>>,
+    replies: Receiver<Result<Vec<u8>, NosError>>,
+}
+
+impl Requester {
+    fn new(bus: &Arc<Bus>, name: &str, destination: &str) -> Result<Requester, NosError> {
+        let node = DataNode::new(bus, name)?;
+        let destination = destination.to_string();
+        let (requests, received) = mpsc::channel::<Vec<u8>>();
+        let (sender, replies) = mpsc::channel();
+        thread::spawn(move || {
+            for data in received {
+                let reply = node
+                    .send_request_message(&destination, &data)
+                    .map(|reply| reply.get_contents().to_vec());
+                // The bridge has given up on this requester
+                if sender.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Requester { requests, replies })
+    }
+
+    fn request(&self, data: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        self.requests
+            .send(data.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "requester stopped"))?;
+        match self.replies.recv_timeout(timeout) {
+            Ok(reply) => reply.map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string())),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no reply from NOS within reply_timeout_ms",
+            )),
+        }
+    }
+}
+
+pub fn udp_init(config: UDPConfig, status: Arc<BridgeStatus>, capture: Capture) {
+    let (bus, node) = match Bus::new(&config.nos_bus, crate::NOS_CONNECTION)
+        .and_then(|bus| DataNode::new(&bus, &config.nos_node).map(|node| (bus, node)))
+    {
+        Ok(connected) => {
+            println!("Established UDP connection to NOS! Starting...");
+            connected
+        }
+        Err(err) => {
+            println!("NOS connection failure: {}. Try restarting NOS3", err);
+            return;
+        }
+    };
+
+    let socket = match UdpSocket::bind(&config.listen) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("<udp: error => binding {}: {}", config.listen, err);
+            return;
+        }
+    };
+
+    // Messages from NOS to UDP
+    let handler = if config.request_reply {
+        // A socket of its own, so that answers are not mixed up with datagrams on `listen`
+        UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+            socket.set_read_timeout(Some(config.reply_timeout))?;
+            Ok(socket)
+        })
+    } else {
+        socket.try_clone()
+    };
+    let handler = match handler {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!(
+                "<udp: error => opening a socket for {}: {}",
+                config.send_to, err
+            );
+            return;
+        }
+    };
+    let request_reply = config.request_reply;
+    let error_reply = config.error_reply.clone();
+    let send_to = config.send_to.clone();
+    let handler_status = status.clone();
+    let handler_capture = capture.clone();
+    node.set_message_handler(move |data: &[u8]| {
+        let received = Instant::now();
+        let reply = forward_message(&handler, &send_to, data, request_reply);
+        match reply {
+            Ok(reply) => {
+                handler_status.transfer();
+                handler_status.latency(received.elapsed());
+                handler_capture.record(Direction::NosToHardware, data);
+                if let Some(ref reply) = reply {
+                    handler_capture.record(Direction::HardwareToNos, reply);
+                }
+                reply
+            }
+            Err(err) => {
+                println!("<udp: error => {}", err);
+                handler_status.failure(&err);
+                // The requester waits for a reply, so it is told what went wrong
+                if request_reply {
+                    Some(format!("{}: {}", error_reply, err).into_bytes())
+                } else {
+                    None
+                }
+            }
+        }
+    });
+
+    // Datagrams from UDP to NOS
+    let mut requester = None;
+    let mut requesters = 0;
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let (n, source) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) => {
+                println!("<udp: error => {}", err);
+                status.failure(&err);
+                continue;
+            }
+        };
+        let received = Instant::now();
+        capture.record(Direction::HardwareToNos, &buf[..n]);
+        let result = if request_reply {
+            let reply = match requester.take() {
+                Some(requester) => Ok(requester),
+                None => {
+                    let name = format!("{}_request_{}", config.nos_node, requesters);
+                    requesters += 1;
+                    Requester::new(&bus, &name, &config.destination)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                }
+            }
+            .and_then(|current| {
+                let reply = current.request(&buf[..n], config.reply_timeout);
+                // A requester which failed may be stuck, so the next datagram gets a new one
+                if reply.is_ok() {
+                    requester = Some(current);
+                }
+                reply
+            });
+            match reply {
+                Ok(reply) => {
+                    capture.record(Direction::NosToHardware, &reply);
+                    socket.send_to(&reply, source).map(|_| ())
+                }
+                Err(err) => {
+                    let _ = socket.send_to(
+                        format!("{}: {}", config.error_reply, err).as_bytes(),
+                        source,
+                    );
+                    Err(err)
+                }
+            }
+        } else {
+            node.send_message(&config.destination, &buf[..n])
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+        };
+        match result {
+            Ok(()) => {
+                status.transfer();
+                status.latency(received.elapsed());
+            }
+            Err(err) => {
+                println!("<udp: error => {}", err);
+                status.failure(&err);
+            }
+        }
+    }
+}
+
+/// Send a message from NOS on as a datagram, and in request mode return the answering datagram
+/// as the reply.
+fn forward_message(
+    socket: &UdpSocket,
+    send_to: &str,
+    data: &[u8],
+    request_reply: bool,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    if request_reply {
+        // Throw away answers which came too late for an earlier request
+        socket.set_nonblocking(true)?;
+        while socket.recv(&mut buf).is_ok() {}
+        socket.set_nonblocking(false)?;
+    }
+    socket.send_to(data, send_to)?;
+    if !request_reply {
+        return Ok(None);
+    }
+    let n = socket.recv(&mut buf).map_err(|err| match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no answer from {} within reply_timeout_ms", send_to),
+        ),
+        _ => err,
+    })?;
+    buf.truncate(n);
+    Ok(Some(buf))
+}